@@ -0,0 +1,75 @@
+// Drives a VM that lives in a worker thread from the main thread, the way a debugger UI would:
+// commands go over one channel and the worker answers each of them over another.
+
+use std::sync::mpsc;
+use std::thread;
+
+use uvm::vm::{VmStatus, VM};
+
+enum Command {
+    Step,
+    Registers,
+    Quit,
+}
+
+enum Response {
+    Stepped(Result<VmStatus, String>),
+    Registers(Vec<i64>),
+}
+
+fn main() {
+    let code = uvm::load_source(
+        "
+        SET 0 r0
+        SET 3 r1
+        loop:
+        ADDL 1 r0
+        CMP r1 r0
+        JLT loop
+        HALT",
+    )
+    .unwrap();
+
+    let (command_tx, command_rx) = mpsc::channel();
+    let (response_tx, response_rx) = mpsc::channel();
+
+    // the VM is moved into the worker, which owns it from then on
+    let mut vm = VM::new(code);
+    let worker = thread::spawn(move || {
+        for command in command_rx {
+            let response = match command {
+                Command::Step => Response::Stepped(vm.tick().map_err(|err| err.to_string())),
+                Command::Registers => Response::Registers(vm.get_registers().to_vec()),
+                Command::Quit => break,
+            };
+            if response_tx.send(response).is_err() {
+                break;
+            }
+        }
+    });
+
+    loop {
+        command_tx.send(Command::Step).unwrap();
+        let status = match response_rx.recv().unwrap() {
+            Response::Stepped(status) => status,
+            Response::Registers(_) => unreachable!(),
+        };
+
+        command_tx.send(Command::Registers).unwrap();
+        if let Response::Registers(regs) = response_rx.recv().unwrap() {
+            println!("r0 = {}, r1 = {}", regs[0], regs[1]);
+        }
+
+        match status {
+            Ok(VmStatus::Halted(_)) => break,
+            Ok(_) => {}
+            Err(err) => {
+                println!("{}", uvm::err!("{}", err));
+                break;
+            }
+        }
+    }
+
+    command_tx.send(Command::Quit).unwrap();
+    worker.join().unwrap();
+}
@@ -2,61 +2,122 @@ use std::collections::HashMap;
 
 use num_enum::TryFromPrimitive;
 
-#[derive(Copy, Clone, Debug, PartialEq, TryFromPrimitive)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, TryFromPrimitive)]
 #[repr(u8)]
 pub enum OpCode {
-    HALT,    // Stops execution
-    SET,     // x rb: Sets `rb` to `x`
-    SETF,    // x rb: Sets `rb` to `x` as a floating point value
-    MOV,     // ra rb: Sets `rb` to `ra`
-    PUSH,    // rb: Pushes the value of `rb` to the stack
-    PUSHL,   // x: Pushes `x` to the stack
-    POP,     // rb: Pops the top of the stack to `rb`
-    PUSHRF,  // x: Saves the value of the first `n` registers to the stack
-    POPRF,   // x: Loads the value of the first `n` registers from the stack
-    ADD,     // ra rb: Adds `ra` and `rb` and stores the result in `rb`
-    ADDL,    // x rb: Adds `x` and `rb` and stores the result in `rb`
-    SUB,     // ra rb: Subtracts `ra` from `rb` and stores the result in `rb`
-    SUBL,    // x rb: Subtracts `x` from `rb` and stores the result in `rb`
-    SUB2L,   // x rb: Subtracts `rb` from `x` and stores the result in `rb`
-    MUL,     // ra rb: Multiplies `ra` and `rb` and stores the result in `rb`
-    MULL,    // x rb: Multiplies `x` and `rb` and stores the result in `rb`
-    DIV,     // ra rb: Divides `rb` by `ra` and stores the result in `rb`
-    DIVL,    // x rb: Divides `rb` by `x` and stores the result in `rb`
-    DIV2L,   // x rb: Divides `x` by `rb` and stores the result in `rb`
-    MOD,     // ra rb: Stores the remainder of `rb` divided by `ra` in `rb`
-    INC,     // rb: Increments `rb` by 1
-    DEC,     // rb: Decrements `rb` by 1
-    ADDF,    // ra rb: Floating point adds `ra` and `rb` and stores the result in `rb`
-    ADDFL,   // x rb: Floating point adds `x` and `rb` and stores the result in `rb`
-    SUBF,    // ra rb: Floating point subtracts `ra` from `rb` and stores the result in `rb`
-    SUBFL,   // x rb: Floating point subtracts `x` from `rb` and stores the result in `rb`
-    SUBF2L,  // x rb: Floating point subtracts `rb` from `x` and stores the result in `rb`
-    MULF,    // ra rb: Floating point multiplies `ra` and `rb` and stores the result in `rb`
-    MULFL,   // x rb: Floating point multiplies `x` and `rb` and stores the result in `rb`
-    DIVF,    // ra rb: Floating point divides `rb` by `ra` and stores the result in `rb`
-    DIVFL,   // x rb: Floating point divides `rb` by `x` and stores the result in `rb`
-    DIVF2L,  // x rb: Floating point divides `x` by `rb` and stores the result in `rb`
-    POW,     // ra rb: Raises `rb` to the power of `ra` and stores the result in `rb`
-    POW2,    // ra rb: Raises `ra` to the power of `rb` and stores the result in `rb`
-    POWL,    // x rb: Raises `rb` to the power of `x` and stores the result in `rb`
-    POW2L,   // x rb: Raises `x` to the power of `rb` and stores the result in `rb`
-    CEIL,    // rb: Rounds `rb` up to the nearest integer
-    FLOR,    // rb: Rounds `rb` down to the nearest integer
-    CMP,     // ra rb: Compares `rb` and `ra` and stores the result in `cmp` (e.g. GT if `rb` > `ra`)
-    CMPL,    // x rb: Compares `rb` and `x` and stores the result in `cmp` (e.g. GT if `rb` > `x`)
-    JMP,     // addr: Jumps to `addr`
-    JEQ,     // addr: Jumps to `addr` if `cmp` has EQ
-    JLT,     // addr: Jumps to `addr` if `cmp` has LT
-    JLE,     // addr: Jumps to `addr` if `cmp` has LE
-    JGT,     // addr: Jumps to `addr` if `cmp` has GT
-    JGE,     // addr: Jumps to `addr` if `cmp` has GE
-    JNE,     // addr: Jumps to `addr` if `cmp` has NE
-    CALL,    // addr: Calls the function at `addr` saving the current address in the call stack
-    RET,     // Returns from a function (pops the call stack and jumps to the saved address)
-    DBGREG,  // rb: Prints the value of `rb` to stdout for debugging
-    DBGREGF, // rb: Prints the value of `rb` as a floating point value to stdout for debugging
-    DBGREGS, // Prints the values of all registers to stdout for debugging
+    HALT,      // Stops execution
+    SET,       // x rb: Sets `rb` to `x`
+    SETF,      // x rb: Sets `rb` to `x` as a floating point value
+    MOV,       // ra rb: Sets `rb` to `ra`
+    PUSH,      // rb: Pushes the value of `rb` to the stack
+    PUSHL,     // x: Pushes `x` to the stack
+    POP,       // rb: Pops the top of the stack to `rb`
+    PUSHRF,    // x: Saves the value of the first `n` registers to the stack
+    POPRF,     // x: Loads the value of the first `n` registers from the stack
+    ADD,       // ra rb: Adds `ra` and `rb` and stores the result in `rb`
+    ADDL,      // x rb: Adds `x` and `rb` and stores the result in `rb`
+    SUB,       // ra rb: Subtracts `ra` from `rb` and stores the result in `rb`
+    SUBL,      // x rb: Subtracts `x` from `rb` and stores the result in `rb`
+    SUB2L,     // x rb: Subtracts `rb` from `x` and stores the result in `rb`
+    MUL,       // ra rb: Multiplies `ra` and `rb` and stores the result in `rb`
+    MULL,      // x rb: Multiplies `x` and `rb` and stores the result in `rb`
+    DIV,       // ra rb: Divides `rb` by `ra` and stores the result in `rb`
+    DIVL,      // x rb: Divides `rb` by `x` and stores the result in `rb`
+    DIV2L,     // x rb: Divides `x` by `rb` and stores the result in `rb`
+    MOD,       // ra rb: Stores the remainder of `rb` divided by `ra` in `rb`
+    INC,       // rb: Increments `rb` by 1
+    DEC,       // rb: Decrements `rb` by 1
+    ADDF,      // ra rb: Floating point adds `ra` and `rb` and stores the result in `rb`
+    ADDFL,     // x rb: Floating point adds `x` and `rb` and stores the result in `rb`
+    SUBF,      // ra rb: Floating point subtracts `ra` from `rb` and stores the result in `rb`
+    SUBFL,     // x rb: Floating point subtracts `x` from `rb` and stores the result in `rb`
+    SUBF2L,    // x rb: Floating point subtracts `rb` from `x` and stores the result in `rb`
+    MULF,      // ra rb: Floating point multiplies `ra` and `rb` and stores the result in `rb`
+    MULFL,     // x rb: Floating point multiplies `x` and `rb` and stores the result in `rb`
+    DIVF,      // ra rb: Floating point divides `rb` by `ra` and stores the result in `rb`
+    DIVFL,     // x rb: Floating point divides `rb` by `x` and stores the result in `rb`
+    DIVF2L,    // x rb: Floating point divides `x` by `rb` and stores the result in `rb`
+    POW,       // ra rb: Raises `rb` to the power of `ra` and stores the result in `rb`
+    POW2,      // ra rb: Raises `ra` to the power of `rb` and stores the result in `rb`
+    POWL,      // x rb: Raises `rb` to the power of `x` and stores the result in `rb`
+    POW2L,     // x rb: Raises `x` to the power of `rb` and stores the result in `rb`
+    CEIL,      // rb: Rounds `rb` up to the nearest integer
+    FLOR,      // rb: Rounds `rb` down to the nearest integer
+    CMP,       // ra rb: Compares `rb` and `ra` and stores the result in `cmp` (e.g. GT if `rb` > `ra`)
+    CMPL,      // x rb: Compares `rb` and `x` and stores the result in `cmp` (e.g. GT if `rb` > `x`)
+    JMP,       // addr: Jumps to `addr`
+    JEQ,       // addr: Jumps to `addr` if `cmp` has EQ
+    JLT,       // addr: Jumps to `addr` if `cmp` has LT
+    JLE,       // addr: Jumps to `addr` if `cmp` has LE
+    JGT,       // addr: Jumps to `addr` if `cmp` has GT
+    JGE,       // addr: Jumps to `addr` if `cmp` has GE
+    JNE,       // addr: Jumps to `addr` if `cmp` has NE
+    CALL,      // addr: Calls the function at `addr` saving the current address in the call stack
+    RET,       // Returns from a function (pops the call stack and jumps to the saved address)
+    DBGREG,    // rb: Prints the value of `rb` to stdout for debugging
+    DBGREGF,   // rb: Prints the value of `rb` as a floating point value to stdout for debugging
+    DBGREGS,   // Prints the values of all registers to stdout for debugging
+    MOVI,      // ra rb: Sets `rb` to the register indexed by the value of `ra`
+    MOVIS,     // ra rb: Sets the register indexed by the value of `ra` to `rb`
+    JEMPTY,    // addr: Jumps to `addr` if the stack is empty
+    JNEMPTY,   // addr: Jumps to `addr` if the stack is not empty
+    CLAMP8,    // rb: Clamps `rb` to the [0, 255] range
+    CLAMP8S,   // rb: Clamps `rb` to the [-128, 127] range
+    TAILCALL,  // addr: Jumps to `addr` reusing the current call frame, so `RET` returns to its caller
+    LOAD,      // ra rb: Sets `rb` to the stack cell indexed by the value of `ra`
+    STORE,     // ra rb: Sets the stack cell indexed by the value of `ra` to `rb`
+    CHECKSUM,  // rb: Sets `rb` to the XOR of all values currently on the stack
+    YIELD,     // Suspends execution, returning control from `run()` until it is called again
+    ENSURE,    // lo hi rb: Fails with an error if `rb` is outside the [`lo`, `hi`] range
+    DBGTABLE,  // Prints a table with every register as an integer and as a floating point value for debugging
+    OPCOUNT,   // x rb: Sets `rb` to how many times the opcode numbered `x` has been executed
+    CLROUT,    // Discards the output captured so far (does nothing unless capturing output)
+    SETBITS,   // x rb: Sets `rb` to the raw bits `x`, for building floats (e.g. NaN payloads) that have no decimal form
+    ISNAN,     // ra rb: Sets `rb` to 1 if `ra` is a floating point NaN and to 0 otherwise
+    FIB,       // rb: Sets `rb` to the Fibonacci number indexed by the value of `rb`
+    SKIPN,     // x: Skips the next `x` instructions if `cmp` has EQ
+    PRINTB,    // x rb: Prints the value of `rb` in base `x` (from 2 to 36)
+    POPN,      // x: Pops `x` values from the stack into `r0`, `r1`, ... in the order they are popped
+    ITOF,      // rb: Converts the integer value in `rb` to a float
+    FTOI,      // rb: Converts the float in `rb` to an integer, truncating towards zero
+    GETLIMIT,  // x rb: Stores limit `x` in `rb` (0: step limit or -1, 1: stack size, 2: call stack size, 3: registers)
+    RAND,      // rb: Stores a pseudo-random integer in `rb`
+    RANDF,     // rb: Stores a pseudo-random float in [0, 1) in `rb`
+    XCHG,      // ra rb: Swaps the contents of `ra` and `rb`
+    DOT,       // ra rb n: Stores in `ra` the dot product of the `n` registers from `ra` and the `n` from `rb`
+    SYSCALL,   // x: Runs the host function registered as `x`
+    RESULT,    // rb: Halts with the value in `rb` as the result of the program
+    REVERSE,   // ra rb: Reverses the `rb` stack cells starting at the one indexed by `ra`
+    BSEARCH,   // ra rb rc: Binary searches `rb` sorted cells from `ra` for `rc` (index or -1 in `ra`, EQ if found)
+    HAMMING,   // ra rb: Sets `rb` to the number of bits that differ between `ra` and `rb`
+    BREAK,     // Pauses the debugger here as if there was a breakpoint, and does nothing otherwise
+    NOP,       // Does nothing
+    PUSHA,     // Pushes all registers to the stack, from the first to the last
+    POPA,      // Pops all registers from the stack, undoing a PUSHA
+    LOADSP,    // x rb: Sets `rb` to the stack cell at `sp + x` (e.g. -1 for the last one pushed)
+    STORESP,   // x rb: Sets the stack cell at `sp + x` to `rb`
+    ENTER,     // x: Pushes `bp` and starts a stack frame there with `x` zeroed locals
+    LEAVE,     // Drops the current stack frame and pops the `bp` ENTER pushed
+    LOADBP,    // x rb: Sets `rb` to the stack cell at `bp + x` (locals from 0, arguments pushed before a CALL from -2)
+    STOREBP,   // x rb: Sets the stack cell at `bp + x` to `rb`
+    CMPF,      // ra rb: Compares floats `rb` and `ra` like `CMP`, or stores UN in `cmp` if either is NaN
+    JUN,       // addr: Jumps to `addr` if `cmp` is UN, i.e. the last CMPF involved a NaN
+    CLRA,      // Sets every register to 0
+    CLR,       // rb: Sets `rb` to 0
+    POPCNT,    // rb: Sets `rb` to how many of its bits are set
+    CLZ,       // rb: Sets `rb` to how many leading bits are 0 (64 if `rb` is 0)
+    CTZ,       // rb: Sets `rb` to how many trailing bits are 0 (64 if `rb` is 0)
+    SPGET,     // rb: Sets `rb` to `sp`, i.e. how many stack cells are in use (see GETLIMIT 1 for the total)
+    CSPGET,    // rb: Sets `rb` to `csp`, i.e. how many calls are currently nested
+    DROP,      // x: Discards the top `x` values of the stack, e.g. the arguments pushed before a CALL
+    DBGSTACK,  // Prints the values on the stack, from the bottom up, to stdout for debugging
+    DBGSTACKN, // x: Prints the top `x` values on the stack (or all of them, if fewer) to stdout for debugging
+    CMOVEQ,    // ra rb: Sets `rb` to `ra` if `cmp` has EQ
+    CMOVNE,    // ra rb: Sets `rb` to `ra` if `cmp` has NE
+    CMOVLT,    // ra rb: Sets `rb` to `ra` if `cmp` has LT
+    CMOVLE,    // ra rb: Sets `rb` to `ra` if `cmp` has LE
+    CMOVGT,    // ra rb: Sets `rb` to `ra` if `cmp` has GT
+    CMOVGE,    // ra rb: Sets `rb` to `ra` if `cmp` has GE
 }
 
 impl OpCode {
@@ -75,6 +136,9 @@ impl OpCode {
 }
 
 impl std::fmt::Display for OpCode {
+    // to_string() is only called when padding is requested, and it is always called
+    // without any alignment, so it never recurses more than once
+    #[allow(clippy::recursive_format_impl)]
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match f.align() {
             None => match self {
@@ -130,6 +194,67 @@ impl std::fmt::Display for OpCode {
                 OpCode::DBGREG => write!(f, "DBGREG"),
                 OpCode::DBGREGF => write!(f, "DBGREGF"),
                 OpCode::DBGREGS => write!(f, "DBGREGS"),
+                OpCode::MOVI => write!(f, "MOVI"),
+                OpCode::MOVIS => write!(f, "MOVIS"),
+                OpCode::JEMPTY => write!(f, "JEMPTY"),
+                OpCode::JNEMPTY => write!(f, "JNEMPTY"),
+                OpCode::CLAMP8 => write!(f, "CLAMP8"),
+                OpCode::CLAMP8S => write!(f, "CLAMP8S"),
+                OpCode::TAILCALL => write!(f, "TAILCALL"),
+                OpCode::LOAD => write!(f, "LOAD"),
+                OpCode::STORE => write!(f, "STORE"),
+                OpCode::CHECKSUM => write!(f, "CHECKSUM"),
+                OpCode::YIELD => write!(f, "YIELD"),
+                OpCode::ENSURE => write!(f, "ENSURE"),
+                OpCode::DBGTABLE => write!(f, "DBGTABLE"),
+                OpCode::OPCOUNT => write!(f, "OPCOUNT"),
+                OpCode::CLROUT => write!(f, "CLROUT"),
+                OpCode::SETBITS => write!(f, "SETBITS"),
+                OpCode::ISNAN => write!(f, "ISNAN"),
+                OpCode::FIB => write!(f, "FIB"),
+                OpCode::SKIPN => write!(f, "SKIPN"),
+                OpCode::PRINTB => write!(f, "PRINTB"),
+                OpCode::POPN => write!(f, "POPN"),
+                OpCode::ITOF => write!(f, "ITOF"),
+                OpCode::FTOI => write!(f, "FTOI"),
+                OpCode::GETLIMIT => write!(f, "GETLIMIT"),
+                OpCode::RAND => write!(f, "RAND"),
+                OpCode::RANDF => write!(f, "RANDF"),
+                OpCode::XCHG => write!(f, "XCHG"),
+                OpCode::DOT => write!(f, "DOT"),
+                OpCode::SYSCALL => write!(f, "SYSCALL"),
+                OpCode::RESULT => write!(f, "RESULT"),
+                OpCode::REVERSE => write!(f, "REVERSE"),
+                OpCode::BSEARCH => write!(f, "BSEARCH"),
+                OpCode::HAMMING => write!(f, "HAMMING"),
+                OpCode::BREAK => write!(f, "BREAK"),
+                OpCode::NOP => write!(f, "NOP"),
+                OpCode::PUSHA => write!(f, "PUSHA"),
+                OpCode::POPA => write!(f, "POPA"),
+                OpCode::LOADSP => write!(f, "LOADSP"),
+                OpCode::STORESP => write!(f, "STORESP"),
+                OpCode::ENTER => write!(f, "ENTER"),
+                OpCode::LEAVE => write!(f, "LEAVE"),
+                OpCode::LOADBP => write!(f, "LOADBP"),
+                OpCode::STOREBP => write!(f, "STOREBP"),
+                OpCode::CMPF => write!(f, "CMPF"),
+                OpCode::JUN => write!(f, "JUN"),
+                OpCode::CLRA => write!(f, "CLRA"),
+                OpCode::CLR => write!(f, "CLR"),
+                OpCode::POPCNT => write!(f, "POPCNT"),
+                OpCode::CLZ => write!(f, "CLZ"),
+                OpCode::CTZ => write!(f, "CTZ"),
+                OpCode::SPGET => write!(f, "SPGET"),
+                OpCode::CSPGET => write!(f, "CSPGET"),
+                OpCode::DROP => write!(f, "DROP"),
+                OpCode::DBGSTACK => write!(f, "DBGSTACK"),
+                OpCode::DBGSTACKN => write!(f, "DBGSTACKN"),
+                OpCode::CMOVEQ => write!(f, "CMOVEQ"),
+                OpCode::CMOVNE => write!(f, "CMOVNE"),
+                OpCode::CMOVLT => write!(f, "CMOVLT"),
+                OpCode::CMOVLE => write!(f, "CMOVLE"),
+                OpCode::CMOVGT => write!(f, "CMOVGT"),
+                OpCode::CMOVGE => write!(f, "CMOVGE"),
             },
             Some(_) => f.pad(&self.to_string()),
         }
@@ -193,7 +318,68 @@ impl std::str::FromStr for OpCode {
             "DBGREG" => Ok(OpCode::DBGREG),
             "DBGREGF" => Ok(OpCode::DBGREGF),
             "DBGREGS" => Ok(OpCode::DBGREGS),
-            _ => Err(err!("Failed to parse opcode: {}", s)),
+            "MOVI" => Ok(OpCode::MOVI),
+            "MOVIS" => Ok(OpCode::MOVIS),
+            "JEMPTY" => Ok(OpCode::JEMPTY),
+            "JNEMPTY" => Ok(OpCode::JNEMPTY),
+            "CLAMP8" => Ok(OpCode::CLAMP8),
+            "CLAMP8S" => Ok(OpCode::CLAMP8S),
+            "TAILCALL" => Ok(OpCode::TAILCALL),
+            "LOAD" => Ok(OpCode::LOAD),
+            "STORE" => Ok(OpCode::STORE),
+            "CHECKSUM" => Ok(OpCode::CHECKSUM),
+            "YIELD" => Ok(OpCode::YIELD),
+            "ENSURE" => Ok(OpCode::ENSURE),
+            "DBGTABLE" => Ok(OpCode::DBGTABLE),
+            "OPCOUNT" => Ok(OpCode::OPCOUNT),
+            "CLROUT" => Ok(OpCode::CLROUT),
+            "SETBITS" => Ok(OpCode::SETBITS),
+            "ISNAN" => Ok(OpCode::ISNAN),
+            "FIB" => Ok(OpCode::FIB),
+            "SKIPN" => Ok(OpCode::SKIPN),
+            "PRINTB" => Ok(OpCode::PRINTB),
+            "POPN" => Ok(OpCode::POPN),
+            "ITOF" => Ok(OpCode::ITOF),
+            "FTOI" => Ok(OpCode::FTOI),
+            "GETLIMIT" => Ok(OpCode::GETLIMIT),
+            "RAND" => Ok(OpCode::RAND),
+            "RANDF" => Ok(OpCode::RANDF),
+            "XCHG" => Ok(OpCode::XCHG),
+            "DOT" => Ok(OpCode::DOT),
+            "SYSCALL" => Ok(OpCode::SYSCALL),
+            "RESULT" => Ok(OpCode::RESULT),
+            "REVERSE" => Ok(OpCode::REVERSE),
+            "BSEARCH" => Ok(OpCode::BSEARCH),
+            "HAMMING" => Ok(OpCode::HAMMING),
+            "BREAK" => Ok(OpCode::BREAK),
+            "NOP" => Ok(OpCode::NOP),
+            "PUSHA" => Ok(OpCode::PUSHA),
+            "POPA" => Ok(OpCode::POPA),
+            "LOADSP" => Ok(OpCode::LOADSP),
+            "STORESP" => Ok(OpCode::STORESP),
+            "ENTER" => Ok(OpCode::ENTER),
+            "LEAVE" => Ok(OpCode::LEAVE),
+            "LOADBP" => Ok(OpCode::LOADBP),
+            "STOREBP" => Ok(OpCode::STOREBP),
+            "CMPF" => Ok(OpCode::CMPF),
+            "JUN" => Ok(OpCode::JUN),
+            "CLRA" => Ok(OpCode::CLRA),
+            "CLR" => Ok(OpCode::CLR),
+            "POPCNT" => Ok(OpCode::POPCNT),
+            "CLZ" => Ok(OpCode::CLZ),
+            "CTZ" => Ok(OpCode::CTZ),
+            "SPGET" => Ok(OpCode::SPGET),
+            "CSPGET" => Ok(OpCode::CSPGET),
+            "DROP" => Ok(OpCode::DROP),
+            "DBGSTACK" => Ok(OpCode::DBGSTACK),
+            "DBGSTACKN" => Ok(OpCode::DBGSTACKN),
+            "CMOVEQ" => Ok(OpCode::CMOVEQ),
+            "CMOVNE" => Ok(OpCode::CMOVNE),
+            "CMOVLT" => Ok(OpCode::CMOVLT),
+            "CMOVLE" => Ok(OpCode::CMOVLE),
+            "CMOVGT" => Ok(OpCode::CMOVGT),
+            "CMOVGE" => Ok(OpCode::CMOVGE),
+            _ => Err(format!("Failed to parse opcode: {}", s)),
         }
     }
 }
@@ -207,64 +393,140 @@ pub enum OpArgT {
     Addr,
     Int,
     RealReg,
+    IntIntReg,
+    RegRegInt,
+    RegRegReg,
 }
 
-pub const OP_ARG_TYPES: [OpArgT; 52] = [
-    OpArgT::Nil,     // HALT
-    OpArgT::IntReg,  // SET
-    OpArgT::RealReg, // SETF
-    OpArgT::RegReg,  // MOV
-    OpArgT::Reg,     // PUSH
-    OpArgT::Int,     // PUSHL
-    OpArgT::Reg,     // POP
-    OpArgT::Int,     // PUSHRF
-    OpArgT::Int,     // POPRF
-    OpArgT::RegReg,  // ADD
-    OpArgT::IntReg,  // ADDL
-    OpArgT::RegReg,  // SUB
-    OpArgT::IntReg,  // SUBL
-    OpArgT::IntReg,  // SUB2L
-    OpArgT::RegReg,  // MUL
-    OpArgT::IntReg,  // MULL
-    OpArgT::RegReg,  // DIV
-    OpArgT::IntReg,  // DIVL
-    OpArgT::IntReg,  // DIV2L
-    OpArgT::RegReg,  // MOD
-    OpArgT::Reg,     // INC
-    OpArgT::Reg,     // DEC
-    OpArgT::RegReg,  // ADDF
-    OpArgT::RealReg, // ADDFL
-    OpArgT::RegReg,  // SUBF
-    OpArgT::RealReg, // SUBFL
-    OpArgT::RealReg, // SUBF2L
-    OpArgT::RegReg,  // MULF
-    OpArgT::RealReg, // MULFL
-    OpArgT::RegReg,  // DIVF
-    OpArgT::RealReg, // DIVFL
-    OpArgT::RealReg, // DIVF2L
-    OpArgT::RegReg,  // POW
-    OpArgT::RegReg,  // POW2
-    OpArgT::IntReg,  // POWL
-    OpArgT::IntReg,  // POW2L
-    OpArgT::Reg,     // CEIL
-    OpArgT::Reg,     // FLOR
-    OpArgT::RegReg,  // CMP
-    OpArgT::IntReg,  // CMPL
-    OpArgT::Addr,    // JMP
-    OpArgT::Addr,    // JEQ
-    OpArgT::Addr,    // JLT
-    OpArgT::Addr,    // JLE
-    OpArgT::Addr,    // JGT
-    OpArgT::Addr,    // JGE
-    OpArgT::Addr,    // JNE
-    OpArgT::Addr,    // CALL
-    OpArgT::Nil,     // RET
-    OpArgT::Reg,     // DBGREG
-    OpArgT::Reg,     // DBGREGF
-    OpArgT::Nil,     // DBGREGS
+impl OpArgT {
+    /// How many atoms follow an opcode taking these arguments.
+    pub fn num_args(&self) -> usize {
+        match self {
+            OpArgT::Nil => 0,
+            OpArgT::Reg | OpArgT::Addr | OpArgT::Int => 1,
+            OpArgT::IntReg | OpArgT::RegReg | OpArgT::RealReg => 2,
+            OpArgT::IntIntReg | OpArgT::RegRegInt | OpArgT::RegRegReg => 3,
+        }
+    }
+}
+
+pub const OP_ARG_TYPES: [OpArgT; 113] = [
+    OpArgT::Nil,       // HALT
+    OpArgT::IntReg,    // SET
+    OpArgT::RealReg,   // SETF
+    OpArgT::RegReg,    // MOV
+    OpArgT::Reg,       // PUSH
+    OpArgT::Int,       // PUSHL
+    OpArgT::Reg,       // POP
+    OpArgT::Int,       // PUSHRF
+    OpArgT::Int,       // POPRF
+    OpArgT::RegReg,    // ADD
+    OpArgT::IntReg,    // ADDL
+    OpArgT::RegReg,    // SUB
+    OpArgT::IntReg,    // SUBL
+    OpArgT::IntReg,    // SUB2L
+    OpArgT::RegReg,    // MUL
+    OpArgT::IntReg,    // MULL
+    OpArgT::RegReg,    // DIV
+    OpArgT::IntReg,    // DIVL
+    OpArgT::IntReg,    // DIV2L
+    OpArgT::RegReg,    // MOD
+    OpArgT::Reg,       // INC
+    OpArgT::Reg,       // DEC
+    OpArgT::RegReg,    // ADDF
+    OpArgT::RealReg,   // ADDFL
+    OpArgT::RegReg,    // SUBF
+    OpArgT::RealReg,   // SUBFL
+    OpArgT::RealReg,   // SUBF2L
+    OpArgT::RegReg,    // MULF
+    OpArgT::RealReg,   // MULFL
+    OpArgT::RegReg,    // DIVF
+    OpArgT::RealReg,   // DIVFL
+    OpArgT::RealReg,   // DIVF2L
+    OpArgT::RegReg,    // POW
+    OpArgT::RegReg,    // POW2
+    OpArgT::IntReg,    // POWL
+    OpArgT::IntReg,    // POW2L
+    OpArgT::Reg,       // CEIL
+    OpArgT::Reg,       // FLOR
+    OpArgT::RegReg,    // CMP
+    OpArgT::IntReg,    // CMPL
+    OpArgT::Addr,      // JMP
+    OpArgT::Addr,      // JEQ
+    OpArgT::Addr,      // JLT
+    OpArgT::Addr,      // JLE
+    OpArgT::Addr,      // JGT
+    OpArgT::Addr,      // JGE
+    OpArgT::Addr,      // JNE
+    OpArgT::Addr,      // CALL
+    OpArgT::Nil,       // RET
+    OpArgT::Reg,       // DBGREG
+    OpArgT::Reg,       // DBGREGF
+    OpArgT::Nil,       // DBGREGS
+    OpArgT::RegReg,    // MOVI
+    OpArgT::RegReg,    // MOVIS
+    OpArgT::Addr,      // JEMPTY
+    OpArgT::Addr,      // JNEMPTY
+    OpArgT::Reg,       // CLAMP8
+    OpArgT::Reg,       // CLAMP8S
+    OpArgT::Addr,      // TAILCALL
+    OpArgT::RegReg,    // LOAD
+    OpArgT::RegReg,    // STORE
+    OpArgT::Reg,       // CHECKSUM
+    OpArgT::Nil,       // YIELD
+    OpArgT::IntIntReg, // ENSURE
+    OpArgT::Nil,       // DBGTABLE
+    OpArgT::IntReg,    // OPCOUNT
+    OpArgT::Nil,       // CLROUT
+    OpArgT::IntReg,    // SETBITS
+    OpArgT::RegReg,    // ISNAN
+    OpArgT::Reg,       // FIB
+    OpArgT::Int,       // SKIPN
+    OpArgT::IntReg,    // PRINTB
+    OpArgT::Int,       // POPN
+    OpArgT::Reg,       // ITOF
+    OpArgT::Reg,       // FTOI
+    OpArgT::IntReg,    // GETLIMIT
+    OpArgT::Reg,       // RAND
+    OpArgT::Reg,       // RANDF
+    OpArgT::RegReg,    // XCHG
+    OpArgT::RegRegInt, // DOT
+    OpArgT::Int,       // SYSCALL
+    OpArgT::Reg,       // RESULT
+    OpArgT::RegReg,    // REVERSE
+    OpArgT::RegRegReg, // BSEARCH
+    OpArgT::RegReg,    // HAMMING
+    OpArgT::Nil,       // BREAK
+    OpArgT::Nil,       // NOP
+    OpArgT::Nil,       // PUSHA
+    OpArgT::Nil,       // POPA
+    OpArgT::IntReg,    // LOADSP
+    OpArgT::IntReg,    // STORESP
+    OpArgT::Int,       // ENTER
+    OpArgT::Nil,       // LEAVE
+    OpArgT::IntReg,    // LOADBP
+    OpArgT::IntReg,    // STOREBP
+    OpArgT::RegReg,    // CMPF
+    OpArgT::Addr,      // JUN
+    OpArgT::Nil,       // CLRA
+    OpArgT::Reg,       // CLR
+    OpArgT::Reg,       // POPCNT
+    OpArgT::Reg,       // CLZ
+    OpArgT::Reg,       // CTZ
+    OpArgT::Reg,       // SPGET
+    OpArgT::Reg,       // CSPGET
+    OpArgT::Int,       // DROP
+    OpArgT::Nil,       // DBGSTACK
+    OpArgT::Int,       // DBGSTACKN
+    OpArgT::RegReg,    // CMOVEQ
+    OpArgT::RegReg,    // CMOVNE
+    OpArgT::RegReg,    // CMOVLT
+    OpArgT::RegReg,    // CMOVLE
+    OpArgT::RegReg,    // CMOVGT
+    OpArgT::RegReg,    // CMOVGE
 ];
 
-#[derive(Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Code {
     Op(OpCode),
     Reg(u8),
@@ -273,7 +535,93 @@ pub enum Code {
     Real(f64),
 }
 
+/// A parsed program: its code plus the data section, which is placed at the bottom of the stack,
+/// and the values the first registers start with.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Program {
+    pub code: Vec<Code>,
+    pub data: Vec<i64>,
+    pub registers: Vec<i64>, // initial values of r0, r1, ... as set by `.regs` (the rest start at 0)
+    pub symbols: DebugSymbols,
+}
+
+/// Where an entry of the code was written in the source, with the line and column starting from 1.
+/// For included files it refers to the file it came from, and for macros to where they were used.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SourceSpan {
+    pub filename: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Maps addresses back to the source, so the debugger can show label names and line numbers,
+/// and registers back to the aliases given to them with `.reg`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DebugSymbols {
+    // labels spelled as in the source (i.e. `.child` for sublabels, which belong to the
+    // closest label before them) and the address they point to, in the order they were defined
+    pub labels: Vec<(String, usize)>,
+    pub lines: Vec<(usize, usize)>, // address of each instruction and the source line it came from
+    pub registers: Vec<(String, u8)>, // register aliases in the order they were defined
+}
+
+impl DebugSymbols {
+    pub fn labels_at(&self, addr: usize) -> impl Iterator<Item = &str> {
+        self.labels
+            .iter()
+            .filter(move |(_, a)| *a == addr)
+            .map(|(label, _)| label.as_str())
+    }
+
+    /// Finds a label by name, where sublabels are named `parent.child`.
+    pub fn label_addr(&self, label: &str) -> Option<usize> {
+        self.qualified_labels()
+            .into_iter()
+            .find(|(l, _)| l == label)
+            .map(|(_, addr)| addr)
+    }
+
+    /// The first alias given to `reg`, if any.
+    pub fn register_name(&self, reg: u8) -> Option<&str> {
+        self.registers
+            .iter()
+            .find(|(_, r)| *r == reg)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// The labels with sublabels prefixed by their parent label (e.g. `parent.child`).
+    pub fn qualified_labels(&self) -> Vec<(String, usize)> {
+        let mut parent = "";
+        let mut qualified = Vec::new();
+        for (label, addr) in &self.labels {
+            if label.starts_with('.') {
+                qualified.push((format!("{}{}", parent, label), *addr));
+            } else {
+                parent = label;
+                qualified.push((label.clone(), *addr));
+            }
+        }
+        qualified
+    }
+
+    pub fn line_at(&self, addr: usize) -> Option<usize> {
+        self.lines.iter().find(|(a, _)| *a == addr).map(|(_, line)| *line)
+    }
+}
+
+impl Code {
+    /// Same as its `Display`, but without the ANSI escapes that make opcodes bold.
+    pub fn to_plain_string(&self) -> String {
+        match self {
+            Code::Op(op) => op.to_string(),
+            _ => self.to_string(),
+        }
+    }
+}
+
 impl std::fmt::Display for Code {
+    // see the note on OpCode's Display implementation
+    #[allow(clippy::recursive_format_impl)]
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match f.align() {
             None => match self {
@@ -288,58 +636,86 @@ impl std::fmt::Display for Code {
     }
 }
 
-pub fn display_code(code: &Vec<Code>) {
-    // We assume that the code is valid for this function and make
-    // gratuitous use of unwrap().
+/// Checks that every instruction of a hand-built `code` starts with an opcode and is followed by as
+/// many atoms as its opcode takes, so that walking it instruction by instruction can't go past its end.
+pub(crate) fn check_operands(code: &[Code]) -> Result<(), String> {
+    let mut idx = 0;
+    while idx < code.len() {
+        let op = match code[idx] {
+            Code::Op(op) => op,
+            _ => return Err(format!("Expected an opcode, but got {} at {}", code[idx], idx)),
+        };
+        let num_args = OP_ARG_TYPES[op as usize].num_args();
+        if idx + num_args >= code.len() {
+            return Err(format!(
+                "{} at {} takes {} operands but only {} follow it",
+                op,
+                idx,
+                num_args,
+                code.len() - idx - 1
+            ));
+        }
+        idx += 1 + num_args;
+    }
+    Ok(())
+}
 
-    println!("{}", info!("Displaying loaded code below:"));
-    println!("┌ START");
+/// Lists the loaded `code` one instruction per line along with its address. If `colored`, the header
+/// and the opcodes are styled with ANSI escapes, which only make sense when shown in a terminal.
+pub fn display_code(code: &[Code], colored: bool) -> Result<String, String> {
+    // once the operands are known to be there, indexing into the code can't go out of bounds
+    let check = check_operands(code);
+    if check.is_err() {
+        return Err(check.unwrap_err());
+    }
+
+    let header = "Displaying loaded code below:";
+    let mut listing = if colored {
+        format!("{}\n┌ START\n", info!("{}", header))
+    } else {
+        format!("[INFO] {}\n┌ START\n", header)
+    };
 
     let mut idx = 0;
     while idx < code.len() {
         let op = match code[idx] {
             Code::Op(op) => op,
-            _ => {
-                panic!("Expected an opcode, but got {}", code[idx])
-            }
+            _ => unreachable!(),
         };
-        let arg_t = OP_ARG_TYPES[op as usize];
-        match arg_t {
-            OpArgT::Nil => {
-                println!("│ {:04} {}", idx, code[idx]);
-                idx += 1;
-            }
-            OpArgT::Reg => {
-                println!("│ {:04} {} {}", idx, code[idx], code[idx + 1]);
-                idx += 2;
-            }
-            OpArgT::IntReg => {
-                println!("│ {:04} {} {} {}", idx, code[idx], code[idx + 1], code[idx + 2]);
-                idx += 3;
-            }
-            OpArgT::RegReg => {
-                println!("│ {:04} {} {} {}", idx, code[idx], code[idx + 1], code[idx + 2]);
-                idx += 3;
-            }
-            OpArgT::Addr => {
-                println!("│ {:04} {} {}", idx, code[idx], code[idx + 1]);
-                idx += 2;
-            }
-            OpArgT::Int => {
-                println!("│ {:04} {} {}", idx, code[idx], code[idx + 1]);
-                idx += 2;
-            }
-            OpArgT::RealReg => {
-                println!("│ {:04} {} {} {}", idx, code[idx], code[idx + 1], code[idx + 2]);
-                idx += 3;
-            }
-        }
+        let num_args = OP_ARG_TYPES[op as usize].num_args();
+        let instr: Vec<String> = code[idx..=idx + num_args]
+            .iter()
+            .map(|code| {
+                if colored {
+                    code.to_string()
+                } else {
+                    code.to_plain_string()
+                }
+            })
+            .collect();
+        listing.push_str(&format!("│ {:04} {}\n", idx, instr.join(" ")));
+        idx += 1 + num_args;
     }
 
-    println!("└ END\n"); // note the trailing newline
+    listing.push_str("└ END\n\n"); // note the trailing newline
+    Ok(listing)
+}
+
+/// Prints the listing made by `display_code` to stdout, colored unless colors are turned off.
+pub fn print_code(code: &[Code]) -> Result<(), String> {
+    let listing = display_code(code, crate::log_macros::use_color());
+    if listing.is_err() {
+        return Err(listing.unwrap_err());
+    }
+    print!("{}", listing.unwrap());
+    Ok(())
 }
 
-pub fn displayable_code(code: &Vec<Code>) -> (Vec<String>, HashMap<usize, usize>, HashMap<usize, usize>) {
+/// Each instruction rendered on its own, plus the maps from addresses to indices into it and back.
+pub type DisplayableCode = (Vec<String>, HashMap<usize, usize>, HashMap<usize, usize>);
+
+/// Renders each instruction of `code` on its own, failing if the code isn't made of whole instructions.
+pub fn displayable_code(code: &[Code]) -> Result<DisplayableCode, String> {
     // We return:
     // - A vector of strings representing each instruction.
     // - A hashmap mapping the address of each instruction to its index in the vector.
@@ -354,6 +730,12 @@ pub fn displayable_code(code: &Vec<Code>) -> (Vec<String>, HashMap<usize, usize>
     // and a hashmap of
     // {0: 0, 1: 3}
 
+    // once the operands are known to be there, indexing into the code can't go out of bounds
+    let check = check_operands(code);
+    if check.is_err() {
+        return Err(check.unwrap_err());
+    }
+
     let mut addr2idx = HashMap::new();
     let mut displayable_code = Vec::new();
 
@@ -361,51 +743,445 @@ pub fn displayable_code(code: &Vec<Code>) -> (Vec<String>, HashMap<usize, usize>
     while idx < code.len() {
         let op = match code[idx] {
             Code::Op(op) => op,
-            _ => {
-                panic!("Expected an opcode, but got {}", code[idx])
+            _ => unreachable!(),
+        };
+        let num_args = OP_ARG_TYPES[op as usize].num_args();
+        let instr: Vec<String> = code[idx..=idx + num_args].iter().map(|code| code.to_string()).collect();
+        displayable_code.push(instr.join(" "));
+        addr2idx.insert(idx, displayable_code.len() - 1);
+        idx += 1 + num_args;
+    }
+
+    let idx2addr = addr2idx.iter().map(|(k, v)| (*v, *k)).collect();
+
+    Ok((displayable_code, addr2idx, idx2addr))
+}
+
+/// Renders a program back into source code that assembles to the very same program. Jumps refer to
+/// the labels in `program.symbols` (keeping sublabels as `.child`), or to made up `L_0042` labels,
+/// and registers are written with their `.reg` aliases when they have one.
+/// Fails if a jump targets something other than the start of an instruction (or the end of the code).
+pub fn render_source(program: &Program) -> Result<String, String> {
+    // the parser names sublabels defined before any label after this, so we do the same
+    let mut parent = "__beggining_of_program__".to_string();
+
+    // which label each sublabel belongs to, to know whether it can be referenced as `.child`
+    let mut owned_labels: Vec<(String, &str, usize)> = Vec::new();
+    for (label, addr) in &program.symbols.labels {
+        if !label.starts_with('.') {
+            parent = label.clone();
+        }
+        owned_labels.push((parent.clone(), label, *addr));
+    }
+    parent = "__beggining_of_program__".to_string();
+
+    // jump targets without any label get one made up, so they can still be referenced
+    let mut made_up_labels: Vec<usize> = program
+        .code
+        .iter()
+        .filter_map(|code| match code {
+            Code::Addr(addr) if program.symbols.labels_at(*addr).next().is_none() => Some(*addr),
+            _ => None,
+        })
+        .collect();
+    made_up_labels.sort();
+    made_up_labels.dedup();
+
+    for addr in &made_up_labels {
+        match program.code.get(*addr) {
+            Some(Code::Op(_)) => {}
+            Some(_) => return Err(format!("Jump target {} lands in the middle of an instruction", addr)),
+            None if *addr == program.code.len() => {}
+            None => return Err(format!("Jump target {} is past the end of the code", addr)),
+        }
+    }
+
+    let mut source = String::new();
+
+    if !program.data.is_empty() {
+        let values: Vec<String> = program.data.iter().map(|val| val.to_string()).collect();
+        source.push_str(&format!(".data\ndata: {}\n.text\n", values.join(" ")));
+    }
+    if !program.registers.is_empty() {
+        let values: Vec<String> = program.registers.iter().map(|val| val.to_string()).collect();
+        source.push_str(&format!(".regs {}\n", values.join(" ")));
+    }
+    for (name, reg) in &program.symbols.registers {
+        source.push_str(&format!(".reg {} r{}\n", name, reg));
+    }
+
+    let emit_labels = |source: &mut String, parent: &mut String, addr: usize| {
+        for (_, label, _) in owned_labels.iter().filter(|(_, _, a)| *a == addr) {
+            if !label.starts_with('.') {
+                *parent = label.to_string();
+            }
+            source.push_str(&format!("{}:\n", label));
+        }
+        if made_up_labels.contains(&addr) {
+            *parent = format!("L_{:04}", addr);
+            source.push_str(&format!("L_{:04}:\n", addr));
+        }
+    };
+
+    for (idx, code) in program.code.iter().enumerate() {
+        match code {
+            Code::Op(op) => {
+                if idx > 0 {
+                    source.push('\n');
+                }
+                emit_labels(&mut source, &mut parent, idx);
+                source.push_str(&format!("\t{}", op));
+            }
+            Code::Reg(reg) => match program.symbols.register_name(*reg) {
+                Some(name) => source.push_str(&format!(" {}", name)),
+                None => source.push_str(&format!(" r{}", reg)),
+            },
+            Code::Int(val) => source.push_str(&format!(" {}", val)),
+            Code::Real(val) => source.push_str(&format!(" {}", val)),
+            Code::Addr(addr) => {
+                let local = owned_labels
+                    .iter()
+                    .find(|(owner, label, a)| *a == *addr && label.starts_with('.') && *owner == parent);
+                let global = owned_labels
+                    .iter()
+                    .find(|(_, label, a)| *a == *addr && !label.starts_with('.'));
+                let other = owned_labels.iter().find(|(_, _, a)| *a == *addr);
+
+                let label = match (local, global, other) {
+                    (Some((_, label, _)), _, _) => label.to_string(),
+                    (None, Some((_, label, _)), _) => label.to_string(),
+                    // sublabels of other labels can only be referenced by their full internal name
+                    (None, None, Some((owner, label, _))) => format!("{}>{}", owner, &label[1..]),
+                    (None, None, None) => format!("L_{:04}", addr),
+                };
+                source.push_str(&format!(" {}", label));
+            }
+        }
+    }
+    if !program.code.is_empty() {
+        source.push('\n');
+    }
+
+    // labels can also point right past the last instruction
+    let end_labels: Vec<usize> = owned_labels
+        .iter()
+        .map(|(_, _, addr)| *addr)
+        .chain(made_up_labels.iter().copied())
+        .filter(|addr| *addr >= program.code.len())
+        .collect::<std::collections::BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    for addr in end_labels {
+        emit_labels(&mut source, &mut parent, addr);
+    }
+
+    Ok(source)
+}
+
+/// How many nested register frames `validate` follows before giving up on a path.
+const MAX_VALIDATED_FRAMES: usize = 16;
+
+/// Statically checks the code for mistakes that would otherwise corrupt state silently, returning one
+/// message per problem found. Currently it flags a `POPRF` that can be reached with a frame size that
+/// doesn't match the `PUSHRF` it pops. This is a heuristic: each function (the start of the code and every
+/// `CALL`/`TAILCALL` target) is walked on its own, so frames pushed by a caller are not tracked.
+pub fn validate(code: &[Code]) -> Vec<String> {
+    // each state is an address plus the (frame size, PUSHRF address) frames pushed so far
+    let mut problems = Vec::new();
+    let mut visited = std::collections::HashSet::new();
+    let mut pending: Vec<(usize, Vec<(i64, usize)>)> = Vec::new();
+
+    for (idx, atom) in code.iter().enumerate() {
+        if let Code::Op(OpCode::CALL | OpCode::TAILCALL) = atom {
+            if let Some(Code::Addr(addr)) = code.get(idx + 1) {
+                pending.push((*addr, Vec::new()));
             }
+        }
+    }
+    pending.push((0, Vec::new()));
+
+    while let Some((addr, mut frames)) = pending.pop() {
+        // loops that keep pushing frames would never settle, so give up on them
+        if frames.len() > MAX_VALIDATED_FRAMES || !visited.insert((addr, frames.clone())) {
+            continue;
+        }
+        let op = match code.get(addr) {
+            Some(Code::Op(op)) => *op,
+            _ => continue,
+        };
+        let next = (addr + 1..code.len())
+            .find(|idx| matches!(code[*idx], Code::Op(_)))
+            .unwrap_or(code.len());
+        let target = match code.get(addr + 1) {
+            Some(Code::Addr(target)) => Some(*target),
+            _ => None,
         };
-        let arg_t = OP_ARG_TYPES[op as usize];
-        match arg_t {
-            OpArgT::Nil => {
-                displayable_code.push(format!("{}", code[idx]));
-                addr2idx.insert(idx, displayable_code.len() - 1);
-                idx += 1;
+
+        match op {
+            OpCode::HALT | OpCode::RESULT | OpCode::RET | OpCode::TAILCALL => {}
+            OpCode::JMP => pending.extend(target.map(|target| (target, frames))),
+            OpCode::JEQ
+            | OpCode::JLT
+            | OpCode::JLE
+            | OpCode::JGT
+            | OpCode::JGE
+            | OpCode::JNE
+            | OpCode::JUN
+            | OpCode::JEMPTY
+            | OpCode::JNEMPTY => {
+                pending.extend(target.map(|target| (target, frames.clone())));
+                pending.push((next, frames));
             }
-            OpArgT::Reg => {
-                displayable_code.push(format!("{} {}", code[idx], code[idx + 1]));
-                addr2idx.insert(idx, displayable_code.len() - 1);
-                idx += 2;
+            OpCode::SKIPN => {
+                if let Some(Code::Int(count)) = code.get(addr + 1) {
+                    let skipped = (next..code.len())
+                        .filter(|idx| matches!(code[*idx], Code::Op(_)))
+                        .nth((*count).max(0) as usize);
+                    pending.push((skipped.unwrap_or(code.len()), frames.clone()));
+                }
+                pending.push((next, frames));
             }
-            OpArgT::IntReg => {
-                displayable_code.push(format!("{} {} {}", code[idx], code[idx + 1], code[idx + 2]));
-                addr2idx.insert(idx, displayable_code.len() - 1);
-                idx += 3;
+            OpCode::PUSHRF | OpCode::POPRF => {
+                let size = match code.get(addr + 1) {
+                    Some(Code::Int(size)) => *size,
+                    _ => continue,
+                };
+                if op == OpCode::PUSHRF {
+                    frames.push((size, addr));
+                } else if let Some((pushed, pushed_at)) = frames.pop() {
+                    if pushed != size {
+                        let problem =
+                            format!("POPRF {} at {} doesn't match PUSHRF {} at {}", size, addr, pushed, pushed_at);
+                        if !problems.contains(&problem) {
+                            problems.push(problem);
+                        }
+                    }
+                }
+                pending.push((next, frames));
             }
-            OpArgT::RegReg => {
-                displayable_code.push(format!("{} {} {}", code[idx], code[idx + 1], code[idx + 2]));
-                addr2idx.insert(idx, displayable_code.len() - 1);
-                idx += 3;
+            _ => pending.push((next, frames)),
+        }
+    }
+
+    problems
+}
+
+/// Looks for instructions that can never run because they come right after a `HALT`, `RESULT`, `RET`,
+/// `TAILCALL` or `JMP`, returning one warning per such stretch of code. It's conservative, so anything a
+/// label points to or that some instruction could jump (or skip) to counts as reachable, and so do
+/// `NOP`s, since those are usually padding from `.org`. It also warns about a `JMP` to itself (possibly
+/// through `NOP`s), which would loop forever.
+pub fn lint(program: &Program) -> Vec<String> {
+    let code = &program.code;
+    let mut warnings = Vec::new();
+
+    let mut targets: std::collections::HashSet<usize> = program.symbols.labels.iter().map(|(_, addr)| *addr).collect();
+    let ops: Vec<usize> = (0..code.len())
+        .filter(|idx| matches!(code[*idx], Code::Op(_)))
+        .collect();
+    for (nth, addr) in ops.iter().enumerate() {
+        match code.get(addr + 1) {
+            Some(Code::Addr(target)) => {
+                targets.insert(*target);
             }
-            OpArgT::Addr => {
-                displayable_code.push(format!("{} {}", code[idx], code[idx + 1]));
-                addr2idx.insert(idx, displayable_code.len() - 1);
-                idx += 2;
+            Some(Code::Int(count)) if code[*addr] == Code::Op(OpCode::SKIPN) => {
+                targets.extend(ops.get(nth + 1 + (*count).max(0) as usize));
             }
-            OpArgT::Int => {
-                displayable_code.push(format!("{} {}", code[idx], code[idx + 1]));
-                addr2idx.insert(idx, displayable_code.len() - 1);
-                idx += 2;
+            _ => {}
+        }
+    }
+
+    // the instruction that made the code after it unreachable, and whether that was already warned about
+    let mut stopped_at: Option<(usize, OpCode)> = None;
+    let mut warned = false;
+    for addr in ops {
+        let op = match code[addr] {
+            Code::Op(op) => op,
+            _ => unreachable!(),
+        };
+        if targets.contains(&addr) {
+            stopped_at = None;
+        }
+        let line = match program.symbols.line_at(addr) {
+            Some(line) => format!(" (line {})", line),
+            None => String::new(),
+        };
+
+        // only a JMP can't get out, since a conditional jump's condition could change
+        if let (OpCode::JMP, Some(Code::Addr(target))) = (op, code.get(addr + 1)) {
+            if *target <= addr && code[*target..addr].iter().all(|atom| *atom == Code::Op(OpCode::NOP)) {
+                warnings.push(format!("JMP at {}{} jumps back to itself, so it would loop forever", addr, line));
             }
-            OpArgT::RealReg => {
-                displayable_code.push(format!("{} {} {}", code[idx], code[idx + 1], code[idx + 2]));
-                addr2idx.insert(idx, displayable_code.len() - 1);
-                idx += 3;
+        }
+
+        if let Some((stop_addr, stop_op)) = stopped_at {
+            // one warning is enough for the whole stretch
+            if !warned && op != OpCode::NOP {
+                warnings.push(format!(
+                    "{} at {}{} can never run, since it comes after {} at {}",
+                    op, addr, line, stop_op, stop_addr
+                ));
+                warned = true;
             }
+            continue;
+        }
+
+        if matches!(op, OpCode::HALT | OpCode::RESULT | OpCode::RET | OpCode::TAILCALL | OpCode::JMP) {
+            stopped_at = Some((addr, op));
+            warned = false;
         }
     }
 
-    let idx2addr = addr2idx.iter().map(|(k, v)| (*v, *k)).collect();
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate_source(source: &str) -> Vec<String> {
+        validate(&crate::parser::parse_source(source).unwrap())
+    }
+
+    #[test]
+    fn test_validate_balanced_frames() {
+        let source = "
+            CALL f
+            HALT
+        f:
+            PUSHRF 2
+            CMPL 0 r0
+            JEQ .end
+            PUSHRF 1
+            POPRF 1
+        .end:
+            POPRF 2
+            RET
+        ";
+        assert!(validate_source(source).is_empty());
+        assert!(validate_source("PUSHRF 3\nPOPRF 3\nPOPRF 2\nHALT").is_empty());
+    }
+
+    #[test]
+    fn test_validate_mismatched_frames() {
+        let source = "
+            CALL f
+            HALT
+        f:
+            PUSHRF 3
+            CMPL 0 r0
+            JEQ .end
+            POPRF 3
+            RET
+        .end:
+            POPRF 2
+            RET
+        ";
+        assert_eq!(validate_source(source), vec!["POPRF 2 at 13 doesn't match PUSHRF 3 at 3".to_string()]);
+        // the other conditional jumps are followed just the same
+        let source = source.replace("CMPL 0 r0", "CMPF r0 r0").replace("JEQ", "JUN");
+        assert_eq!(validate_source(&source), vec!["POPRF 2 at 13 doesn't match PUSHRF 3 at 3".to_string()]);
+    }
+
+    #[test]
+    fn test_lint_unreachable_code() {
+        let lint_source = |source: &str| lint(&crate::parser::parse_program_source(source).unwrap());
+
+        let source = "
+            SET 1 r0
+            JMP end
+            INC r0
+            INC r0
+        end:
+            HALT
+            DEC r0
+        ";
+        assert_eq!(
+            lint_source(source),
+            [
+                "INC at 5 (line 4) can never run, since it comes after JMP at 3",
+                "DEC at 10 (line 8) can never run, since it comes after HALT at 9",
+            ]
+        );
+
+        // code that's jumped, skipped or returned to is fine, and so is padding from .org
+        let source = "
+            CALL f
+            CMPL 0 r0
+            JEQ .skip
+            HALT
+        .skip:
+            SKIPN 1
+            HALT
+            RET
+        f:
+            RET
+            .org 32
+        g:
+            HALT
+        ";
+        assert!(lint_source(source).is_empty(), "{:?}", lint_source(source));
+    }
+
+    #[test]
+    fn test_lint_infinite_loops() {
+        let lint_source = |source: &str| lint(&crate::parser::parse_program_source(source).unwrap());
+
+        assert_eq!(
+            lint_source("SET 1 r0\nloop:\nJMP loop"),
+            ["JMP at 3 (line 3) jumps back to itself, so it would loop forever"]
+        );
+        assert_eq!(
+            lint_source("JMP .wait\n.wait:\nNOP\nNOP\nJMP .wait"),
+            ["JMP at 4 (line 5) jumps back to itself, so it would loop forever"]
+        );
 
-    (displayable_code, addr2idx, idx2addr)
+        // loops that do something, or that can be left, aren't flagged
+        assert!(lint_source("loop:\nINC r0\nJMP loop").is_empty());
+        assert!(lint_source("loop:\nJEQ loop\nHALT").is_empty());
+        assert!(lint_source("loop:\nJMP end\nend:\nHALT").is_empty());
+    }
+
+    #[test]
+    fn test_display_code() {
+        let code = crate::parser::parse_source("SET 5 r0\nloop:\nDEC r0\nJNE loop\nHALT").unwrap();
+        let listing = display_code(&code, true).unwrap();
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(
+            lines[1..],
+            [
+                "┌ START",
+                "│ 0000 \x1b[1mSET\x1b[0m 5i r0",
+                "│ 0003 \x1b[1mDEC\x1b[0m r0",
+                "│ 0005 \x1b[1mJNE\x1b[0m addr(3)",
+                "│ 0007 \x1b[1mHALT\x1b[0m",
+                "└ END",
+                "",
+            ]
+        );
+        assert!(listing.ends_with("└ END\n\n"));
+    }
+
+    #[test]
+    fn test_displayable_code_malformed() {
+        let (instrs, addr2idx, _) =
+            displayable_code(&[Code::Op(OpCode::INC), Code::Reg(1), Code::Op(OpCode::HALT)]).unwrap();
+        assert_eq!(instrs, ["\x1b[1mINC\x1b[0m r1", "\x1b[1mHALT\x1b[0m"]);
+        assert_eq!(addr2idx[&2], 1);
+
+        let err = displayable_code(&[Code::Op(OpCode::SET), Code::Int(1)]).unwrap_err();
+        assert_eq!(err, "SET at 0 takes 2 operands but only 1 follow it");
+        let err = displayable_code(&[Code::Reg(0)]).unwrap_err();
+        assert_eq!(err, "Expected an opcode, but got r0 at 0");
+    }
+
+    #[test]
+    fn test_display_code_plain() {
+        let code = crate::parser::parse_source("SET 5 r0\nloop:\nDEC r0\nJNE loop\nHALT").unwrap();
+        let listing = display_code(&code, false).unwrap();
+        assert!(!listing.contains('\x1b'));
+        assert!(listing.starts_with("[INFO] Displaying loaded code below:\n┌ START\n│ 0000 SET 5i r0\n"));
+        assert!(code.iter().all(|code| !code.to_plain_string().contains('\x1b')));
+        assert_eq!(Code::Op(OpCode::JNE).to_plain_string(), "JNE");
+    }
 }
@@ -1,3 +1,7 @@
+// The codebase deliberately checks `is_err()` and then calls `unwrap_err()` instead of
+// pattern matching, which is what this lint complains about.
+#![allow(clippy::unnecessary_unwrap)]
+
 #[macro_use]
 pub mod log_macros;
 
@@ -6,3 +10,38 @@ pub mod parser;
 pub mod serializer;
 pub mod utils;
 pub mod vm;
+
+use asm::Code;
+
+/// Loads a program from an in-memory UVM binary, like `serializer::disassemble` does from a file.
+pub fn load_binary(bytes: &[u8]) -> Result<Vec<Code>, String> {
+    serializer::deserialize(bytes.to_vec())
+}
+
+/// Loads a program from in-memory UVM source code, like `parser::parse_file` does from a file.
+pub fn load_source(text: &str) -> Result<Vec<Code>, String> {
+    parser::parse_source(text)
+}
+
+/// Parses the program at `input_path` and checks that it could be loaded and run, without running it,
+/// returning the parsed program. Problems found by the VM are reported without a line, as if they were
+/// about the whole file.
+pub fn check_file(input_path: String) -> Result<asm::Program, parser::ParseError> {
+    let program = parser::parse_program_structured(input_path.clone());
+    if program.is_err() {
+        return Err(program.unwrap_err());
+    }
+    let in_file = |err: vm::VmError| parser::ParseError {
+        filename: input_path.clone(),
+        line: None,
+        column: None,
+        message: err.to_string(),
+    };
+    let program = program.unwrap();
+    let vm = match vm::VM::from_program(program.clone()) {
+        Ok(vm) => vm,
+        Err(err) => return Err(in_file(err)),
+    };
+    vm.validate().map_err(in_file)?;
+    Ok(program)
+}
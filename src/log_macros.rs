@@ -1,20 +1,86 @@
+// Prefixes for messages shown to a user. The library returns its errors as plain text and only
+// the CLI renders them with `err!`; `dbg!` is what the DBG* opcodes print as program output.
+// The prefixes are colored unless that was turned off (see `use_color`), and since `warn!` is the
+// one printed to stderr, it looks at stderr instead of stdout.
+
+use std::{ffi::OsStr, io::IsTerminal, sync::OnceLock};
+
+/// Whether the prefixes (and the code listing) are colored, i.e. stdout is a terminal and `NO_COLOR`
+/// isn't set to anything (see https://no-color.org). Only checked the first time it's needed.
+pub fn use_color() -> bool {
+    static USE_COLOR: OnceLock<bool> = OnceLock::new();
+    *USE_COLOR.get_or_init(|| color_enabled(std::env::var_os("NO_COLOR").as_deref(), std::io::stdout().is_terminal()))
+}
+
+/// Same as `use_color`, but for what is printed to stderr.
+pub fn use_color_stderr() -> bool {
+    static USE_COLOR: OnceLock<bool> = OnceLock::new();
+    *USE_COLOR.get_or_init(|| color_enabled(std::env::var_os("NO_COLOR").as_deref(), std::io::stderr().is_terminal()))
+}
+
+fn color_enabled(no_color: Option<&OsStr>, is_terminal: bool) -> bool {
+    is_terminal && no_color.is_none_or(|val| val.is_empty())
+}
+
+/// Renders `[label]`, in the ANSI `style` if `colored`.
+pub fn prefix(style: &str, label: &str, colored: bool) -> String {
+    if colored {
+        format!("\x1b[{}m[{}]\x1b[0m", style, label)
+    } else {
+        format!("[{}]", label)
+    }
+}
+
 #[macro_export]
 macro_rules! err {
     ($($arg:tt)*) => ({
-        format!("\x1b[1;31m[ERROR]\x1b[0m {}", format_args!($($arg)*))
+        format!("{} {}", $crate::log_macros::prefix("1;31", "ERROR", $crate::log_macros::use_color()), format_args!($($arg)*))
     })
 }
 
 #[macro_export]
 macro_rules! dbg {
     ($($arg:tt)*) => ({
-        format!("\x1b[1;32m[DEBUG]\x1b[0m {}", format_args!($($arg)*))
+        format!("{} {}", $crate::log_macros::prefix("1;32", "DEBUG", $crate::log_macros::use_color()), format_args!($($arg)*))
+    })
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ({
+        format!("{} {}", $crate::log_macros::prefix("1;33", "WARN", $crate::log_macros::use_color_stderr()), format_args!($($arg)*))
     })
 }
 
 #[macro_export]
 macro_rules! info {
     ($($arg:tt)*) => ({
-        format!("\x1b[1;34m[INFO]\x1b[0m {}", format_args!($($arg)*))
+        format!("{} {}", $crate::log_macros::prefix("1;34", "INFO", $crate::log_macros::use_color()), format_args!($($arg)*))
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_color_enabled() {
+        assert!(color_enabled(None, true));
+        assert!(color_enabled(Some(OsStr::new("")), true));
+        assert!(!color_enabled(Some(OsStr::new("1")), true));
+        assert!(!color_enabled(None, false));
+    }
+
+    #[test]
+    fn test_prefix() {
+        assert_eq!(prefix("1;33", "WARN", true), "\x1b[1;33m[WARN]\x1b[0m");
+        assert_eq!(prefix("1;33", "WARN", false), "[WARN]");
+    }
+
+    #[test]
+    fn test_info() {
+        let message = info!("{} loaded", 3);
+        assert!(message.contains("[INFO]"), "{:?}", message);
+        assert!(message.ends_with(" 3 loaded"), "{:?}", message);
+    }
+}
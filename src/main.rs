@@ -1,4 +1,7 @@
-use uvm::{asm, parser, serializer, vm};
+// See the note in lib.rs.
+#![allow(clippy::unnecessary_unwrap)]
+
+use uvm::{asm, err, info, parser, serializer, vm, warn};
 
 extern crate clap;
 use clap::{Arg, ArgAction, Command};
@@ -32,13 +35,41 @@ fn main() {
                         .action(ArgAction::SetTrue)
                         .help("Capture the output of the program and print it all at once"),
                 )
+                .arg(
+                    Arg::new("max_output_bytes")
+                        .long("max-output-bytes")
+                        .value_parser(clap::value_parser!(usize))
+                        .requires("batched_output")
+                        .help("Stop the program once its captured output grows past this many bytes"),
+                )
                 .arg(
                     Arg::new("verbose")
                         .short('v')
                         .long("verbose")
                         .action(ArgAction::SetTrue),
                 )
-                .arg(Arg::new("debug").short('d').long("debug").action(ArgAction::SetTrue)),
+                .arg(
+                    Arg::new("debug")
+                        .short('d')
+                        .long("debug")
+                        .action(ArgAction::SetTrue)
+                        .help("Run the program inside the interactive debugger"),
+                )
+                .arg(
+                    Arg::new("step")
+                        .short('s')
+                        .long("step")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("debug")
+                        .help("Print each instruction as it runs and wait for Enter before the next one"),
+                )
+                .arg(
+                    Arg::new("profile")
+                        .short('p')
+                        .long("profile")
+                        .action(ArgAction::SetTrue)
+                        .help("Print how many times each opcode was executed once the program stops"),
+                ),
         )
         .subcommand(
             Command::new("asm")
@@ -48,7 +79,83 @@ fn main() {
                         .required(true)
                         .help("Path to the program to be assembled"),
                 )
-                .arg(Arg::new("output_path").required(true).help("Path to the output file")),
+                .arg(Arg::new("output_path").required(true).help("Path to the output file"))
+                .arg(
+                    Arg::new("symbols")
+                        .short('g')
+                        .long("symbols")
+                        .action(ArgAction::SetTrue)
+                        .help("Include label names and source lines so the debugger can show them"),
+                ),
+        )
+        .subcommand(
+            Command::new("asm-dir")
+                .about("Assembles every .uvm file in a directory into a .uvmb file with the same name")
+                .arg(
+                    Arg::new("input_dir")
+                        .required(true)
+                        .help("Directory with the programs to be assembled"),
+                )
+                .arg(
+                    Arg::new("output_dir")
+                        .required(true)
+                        .help("Directory for the binaries, created if it doesn't exist"),
+                )
+                .arg(
+                    Arg::new("symbols")
+                        .short('g')
+                        .long("symbols")
+                        .action(ArgAction::SetTrue)
+                        .help("Include label names and source lines so the debugger can show them"),
+                ),
+        )
+        .subcommand(
+            Command::new("disasm")
+                .about("Prints the source code of a UVM binary")
+                .arg(
+                    Arg::new("input_path")
+                        .required(true)
+                        .help("Path to the binary to be disassembled"),
+                ),
+        )
+        .subcommand(
+            Command::new("check")
+                .about("Checks that a UVM program parses and is well formed, without running or assembling it")
+                .arg(
+                    Arg::new("input_path")
+                        .required(true)
+                        .help("Path to the program to be checked"),
+                )
+                .arg(
+                    Arg::new("quiet")
+                        .short('q')
+                        .long("quiet")
+                        .action(ArgAction::SetTrue)
+                        .help("Don't print anything when the program is fine"),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_parser(["text", "json"])
+                        .default_value("text")
+                        .help("Print the error as text or as a JSON object with its filename, line and message"),
+                )
+                .arg(
+                    Arg::new("lint")
+                        .short('l')
+                        .long("lint")
+                        .action(ArgAction::SetTrue)
+                        .help("Also warn (on stderr) about code that can never run or that loops forever"),
+                ),
+        )
+        .subcommand(
+            Command::new("verify")
+                .about("Statically checks a UVM program for mismatched PUSHRF/POPRF frames")
+                .arg(
+                    Arg::new("input_path")
+                        .required(true)
+                        .help("Path to the program to be checked"),
+                ),
         )
         .get_matches();
 
@@ -60,25 +167,46 @@ fn main() {
             let is_batched_output = run_matches.get_flag("batched_output");
             let is_debug = run_matches.get_flag("debug");
             let is_verbose = run_matches.get_flag("verbose");
+            let is_step = run_matches.get_flag("step");
+            let is_profile = run_matches.get_flag("profile");
+            let max_output_bytes = run_matches.get_one::<usize>("max_output_bytes").copied();
 
             if is_binary {
-                let code = serializer::disassemble(input_path);
-                if code.is_err() {
-                    let err = code.unwrap_err();
-                    println!("{}", err);
+                let program = serializer::disassemble(input_path);
+                if program.is_err() {
+                    let err = program.unwrap_err();
+                    println!("{}", err!("{}", err));
                     std::process::exit(1);
                 }
-                let code = code.unwrap();
-                run(code, is_batched_output, is_debug, is_verbose);
+                let program = program.unwrap();
+                run(
+                    program,
+                    None,
+                    is_batched_output,
+                    max_output_bytes,
+                    is_debug,
+                    is_step,
+                    is_profile,
+                    is_verbose,
+                );
             } else {
-                let code = parser::parse_file(input_path);
-                if code.is_err() {
-                    let err = code.unwrap_err();
-                    println!("{}", err);
+                let program = parser::parse_program_with_spans(input_path);
+                if program.is_err() {
+                    let err = program.unwrap_err();
+                    println!("{}", err!("{}", err));
                     std::process::exit(1);
                 }
-                let code = code.unwrap();
-                run(code, is_batched_output, is_debug, is_verbose);
+                let (program, spans) = program.unwrap();
+                run(
+                    program,
+                    Some(spans),
+                    is_batched_output,
+                    max_output_bytes,
+                    is_debug,
+                    is_step,
+                    is_profile,
+                    is_verbose,
+                );
             }
         }
         Some(("asm", asm_matches)) => {
@@ -86,9 +214,84 @@ fn main() {
             let input_path = asm_matches.get_one::<String>("input_path").unwrap().clone();
             let output_path = asm_matches.get_one::<String>("output_path").unwrap().clone();
 
-            let asm_result = serializer::assemble(input_path, output_path);
+            let with_symbols = asm_matches.get_flag("symbols");
+
+            let asm_result = serializer::assemble(input_path, output_path, with_symbols);
             if asm_result.is_err() {
-                println!("{}", asm_result.unwrap_err());
+                println!("{}", err!("{}", asm_result.unwrap_err()));
+                std::process::exit(1);
+            }
+        }
+        Some(("asm-dir", asm_dir_matches)) => {
+            // required, so it's safe to unwrap
+            let input_dir = asm_dir_matches.get_one::<String>("input_dir").unwrap();
+            let output_dir = asm_dir_matches.get_one::<String>("output_dir").unwrap();
+
+            let with_symbols = asm_dir_matches.get_flag("symbols");
+
+            if !assemble_dir(input_dir, output_dir, with_symbols) {
+                std::process::exit(1);
+            }
+        }
+        Some(("disasm", disasm_matches)) => {
+            // required, so it's safe to unwrap
+            let input_path = disasm_matches.get_one::<String>("input_path").unwrap().clone();
+
+            let program = serializer::disassemble(input_path);
+            if program.is_err() {
+                println!("{}", err!("{}", program.unwrap_err()));
+                std::process::exit(1);
+            }
+            let source = asm::render_source(&program.unwrap());
+            if source.is_err() {
+                println!("{}", err!("{}", source.unwrap_err()));
+                std::process::exit(1);
+            }
+            print!("{}", source.unwrap());
+        }
+        Some(("check", check_matches)) => {
+            // required, so it's safe to unwrap
+            let input_path = check_matches.get_one::<String>("input_path").unwrap().clone();
+            let is_quiet = check_matches.get_flag("quiet");
+            // has a default value, so it's safe to unwrap
+            let is_json = check_matches.get_one::<String>("format").unwrap() == "json";
+            let is_lint = check_matches.get_flag("lint");
+
+            let checked = uvm::check_file(input_path);
+            if checked.is_err() {
+                let err = checked.unwrap_err();
+                if is_json {
+                    println!("{}", err.to_json());
+                } else {
+                    println!("{}", err!("{}", err));
+                }
+                std::process::exit(1);
+            }
+            // warnings don't make the check fail
+            if is_lint {
+                for warning in asm::lint(&checked.unwrap()) {
+                    eprintln!("{}", warn!("{}", warning));
+                }
+            }
+            // a JSON consumer only cares about errors, so there's nothing to print otherwise
+            if !is_quiet && !is_json {
+                println!("OK");
+            }
+        }
+        Some(("verify", verify_matches)) => {
+            // required, so it's safe to unwrap
+            let input_path = verify_matches.get_one::<String>("input_path").unwrap().clone();
+
+            let program = parser::parse_program(input_path);
+            if program.is_err() {
+                println!("{}", err!("{}", program.unwrap_err()));
+                std::process::exit(1);
+            }
+            let problems = asm::validate(&program.unwrap().code);
+            for problem in &problems {
+                println!("{}", err!("{}", problem));
+            }
+            if !problems.is_empty() {
                 std::process::exit(1);
             }
         }
@@ -96,22 +299,134 @@ fn main() {
     }
 }
 
-fn run(code: Vec<asm::Code>, is_batched_output: bool, is_debug: bool, is_verbose: bool) {
+/// Assembles each `*.uvm` file directly inside `input_dir` into `output_dir/<name>.uvmb`, reporting
+/// each file as it goes. A file that fails doesn't stop the rest, and `false` is returned if any did.
+fn assemble_dir(input_dir: &str, output_dir: &str, with_symbols: bool) -> bool {
+    let entries = std::fs::read_dir(input_dir);
+    if entries.is_err() {
+        println!("{}", err!("Failed to read directory {}: {}", input_dir, entries.unwrap_err()));
+        return false;
+    }
+    // sorted so the report comes out in the same order every time
+    let mut sources: Vec<std::path::PathBuf> = entries
+        .unwrap()
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "uvm"))
+        .collect();
+    sources.sort();
+
+    let created = std::fs::create_dir_all(output_dir);
+    if created.is_err() {
+        println!("{}", err!("Failed to create directory {}: {}", output_dir, created.unwrap_err()));
+        return false;
+    }
+
+    let mut all_ok = true;
+    for source in sources {
+        // the extension was just checked, so there's a file stem
+        let output = std::path::Path::new(output_dir)
+            .join(source.file_stem().unwrap())
+            .with_extension("uvmb");
+        let input_path = source.to_string_lossy().to_string();
+        let output_path = output.to_string_lossy().to_string();
+
+        match serializer::assemble(input_path.clone(), output_path.clone(), with_symbols) {
+            Ok(()) => println!("{}", info!("Assembled {} into {}", input_path, output_path)),
+            Err(e) => {
+                println!("{}", err!("Failed to assemble {}: {}", input_path, e));
+                all_ok = false;
+            }
+        }
+    }
+    all_ok
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run(
+    program: asm::Program,
+    spans: Option<Vec<asm::SourceSpan>>,
+    is_batched_output: bool,
+    max_output_bytes: Option<usize>,
+    is_debug: bool,
+    is_step: bool,
+    is_profile: bool,
+    is_verbose: bool,
+) {
     if is_verbose {
-        asm::display_code(&code);
+        if let Err(err) = asm::print_code(&program.code) {
+            println!("{}", err!("{}", err));
+            std::process::exit(1);
+        }
     }
 
-    let mut vm = vm::VM::new(code);
+    let mut vm = match vm::VM::from_program(program) {
+        Ok(vm) => vm,
+        Err(err) => {
+            println!("{}", err!("{}", err));
+            std::process::exit(1);
+        }
+    };
+    if let Err(err) = vm.validate() {
+        println!("{}", err!("{}", err));
+        std::process::exit(1);
+    }
     if is_batched_output {
         vm = vm.capture_output();
     }
+    if let Some(max) = max_output_bytes {
+        vm = vm.with_output_limit(max);
+    }
+    if is_step {
+        vm = vm.step_by_step();
+    }
+    if let Some(spans) = spans {
+        vm = vm.with_spans(spans);
+    }
 
-    let result = if !is_debug { vm.run() } else { vm.debugger() };
+    let result = if !is_debug { run_to_halt(&mut vm) } else { vm.debugger() };
+    if is_profile {
+        print_profile(&vm);
+    }
     if result.is_err() {
-        println!("{}", result.unwrap_err());
+        println!("{}", err!("{}", result.unwrap_err()));
+        if let Some(span) = vm.op_span() {
+            println!(
+                "{}",
+                info!(
+                    "The failing instruction is at line {}, column {} of {}",
+                    span.line, span.column, span.filename
+                )
+            );
+        }
         std::process::exit(1);
     }
     if is_batched_output {
         println!("{}", result.unwrap());
     }
 }
+
+fn print_profile(vm: &vm::VM) {
+    // the opcodes that ran the most come first, and ties are broken by name to keep the order stable
+    let mut counts: Vec<(asm::OpCode, u64)> = vm.profile().into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.to_string().cmp(&b.0.to_string())));
+
+    println!("{}", info!("Executed opcodes:"));
+    for (op, count) in counts {
+        println!("{:>12} {}", count, op);
+    }
+}
+
+fn run_to_halt(vm: &mut vm::VM) -> Result<String, vm::VmError> {
+    // there's nothing else to give control to when the program yields, so it's just resumed
+    let mut output = String::new();
+    loop {
+        match vm.resume() {
+            vm::RunResult::Halted(captured) => {
+                output.push_str(&captured);
+                return Ok(output);
+            }
+            vm::RunResult::Yielded(captured) => output.push_str(&captured),
+            vm::RunResult::Error(err) => return Err(err),
+        }
+    }
+}
@@ -1,85 +1,896 @@
-use std::{collections::HashMap, str::FromStr};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
-use crate::asm::{Code, OpArgT, OpCode, OP_ARG_TYPES};
+use crate::{
+    asm::{Code, DebugSymbols, OpArgT, OpCode, Program, SourceSpan, OP_ARG_TYPES},
+    utils::f2i,
+};
 
+/// An error found while parsing, along with where it was found so that it can be reported in a
+/// structured way (e.g. to an editor) instead of only as the message shown to the user.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    pub filename: String,
+    /// Line the error is on, starting at 1, or `None` if it's about the file as a whole.
+    pub line: Option<usize>,
+    /// Column of the token the error is about, starting at 1, if it's about a specific one.
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl ParseError {
+    fn in_file(filename: &str, message: String) -> Self {
+        Self {
+            filename: filename.to_string(),
+            line: None,
+            column: None,
+            message,
+        }
+    }
+
+    /// The error as a JSON object with the `filename`, `line`, `column` (both possibly `null`) and
+    /// `message` keys.
+    pub fn to_json(&self) -> String {
+        let or_null = |val: Option<usize>| match val {
+            Some(val) => val.to_string(),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"filename\": {}, \"line\": {}, \"column\": {}, \"message\": {}}}",
+            json_string(&self.filename),
+            or_null(self.line),
+            or_null(self.column),
+            json_string(&self.message)
+        )
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(f, "{}.{}:{}: {}", self.filename, line, column, self.message),
+            (Some(line), None) => write!(f, "{}.{}: {}", self.filename, line, self.message),
+            _ => write!(f, "{}: {}", self.filename, self.message),
+        }
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[derive(Clone, Debug)]
 struct Ctxt {
     filename: String,
     line: usize,
+    // the line being parsed, so errors can point to the token they're about (empty until then)
+    text: String,
+    // the column of the macro invocation the line was expanded from, if it was
+    expanded_at: Option<usize>,
 }
 
 impl Ctxt {
     fn new(filename: String) -> Self {
-        Self { filename, line: 0 }
+        Self {
+            filename,
+            line: 0,
+            text: String::new(),
+            expanded_at: None,
+        }
     }
 
     fn inc(&mut self) {
         self.line += 1;
     }
+
+    fn error(&self, message: String) -> ParseError {
+        ParseError {
+            filename: self.filename.clone(),
+            line: Some(self.line),
+            column: None,
+            message,
+        }
+    }
+
+    /// Same as `error`, but also pointing to the column of `token`, which must be part of `text`.
+    fn error_at(&self, token: &str, message: String) -> ParseError {
+        let offset = (token.as_ptr() as usize).checked_sub(self.text.as_ptr() as usize);
+        ParseError {
+            column: offset
+                .filter(|&offset| offset <= self.text.len())
+                .map(|offset| self.text[..offset].chars().count() + 1),
+            ..self.error(message)
+        }
+    }
+}
+
+/// How many `.include`s can be nested inside each other by default.
+pub const DEFAULT_MAX_INCLUDE_DEPTH: usize = 32;
+
+pub fn parse_program(input_path: String) -> Result<Program, String> {
+    parse_program_with_include_depth(input_path, DEFAULT_MAX_INCLUDE_DEPTH)
+}
+
+/// Same as `parse_program`, but erroring out once `.include`s nest deeper than `max_include_depth`.
+pub fn parse_program_with_include_depth(input_path: String, max_include_depth: usize) -> Result<Program, String> {
+    read_and_parse(input_path, max_include_depth)
+        .map(|(program, _)| program)
+        .map_err(|err| err.to_string())
+}
+
+/// Same as `parse_program`, but with the error as a `ParseError` instead of its message.
+pub fn parse_program_structured(input_path: String) -> Result<Program, ParseError> {
+    read_and_parse(input_path, DEFAULT_MAX_INCLUDE_DEPTH).map(|(program, _)| program)
+}
+
+pub fn parse_program_source(source: &str) -> Result<Program, String> {
+    parse_string(source, Ctxt::new("<source>".to_string())).map_err(|err| err.to_string())
 }
 
+/// Same as `parse_program`, but also returning where in the source each entry of the code came from.
+pub fn parse_program_with_spans(input_path: String) -> Result<(Program, Vec<SourceSpan>), String> {
+    read_and_parse(input_path, DEFAULT_MAX_INCLUDE_DEPTH).map_err(|err| err.to_string())
+}
+
+/// Same as `parse_program_source`, but also returning where in the source each entry of the code came from.
+pub fn parse_string_with_spans(source: &str) -> Result<(Program, Vec<SourceSpan>), String> {
+    parse_spanned(source, Ctxt::new("<source>".to_string()), DEFAULT_MAX_INCLUDE_DEPTH).map_err(|err| err.to_string())
+}
+
+/// Parses a program that only has code, erroring out if it declares a `.data` section,
+/// since it would be lost otherwise (use `parse_program` for those).
 pub fn parse_file(input_path: String) -> Result<Vec<Code>, String> {
+    parse_file_structured(input_path).map_err(|err| err.to_string())
+}
+
+/// Same as `parse_file`, but with the error as a `ParseError` instead of its message.
+pub fn parse_file_structured(input_path: String) -> Result<Vec<Code>, ParseError> {
+    let filename = input_path.clone();
+    let program = parse_program_structured(input_path);
+    if program.is_err() {
+        return Err(program.unwrap_err());
+    }
+    code_only(program.unwrap(), &filename)
+}
+
+pub fn parse_source(source: &str) -> Result<Vec<Code>, String> {
+    let program = parse_string(source, Ctxt::new("<source>".to_string()));
+    if program.is_err() {
+        return Err(program.unwrap_err().to_string());
+    }
+    code_only(program.unwrap(), "<source>").map_err(|err| err.to_string())
+}
+
+fn read_and_parse(input_path: String, max_include_depth: usize) -> Result<(Program, Vec<SourceSpan>), ParseError> {
     let program = std::fs::read_to_string(&input_path);
     if program.is_err() {
-        return Err(format!("std::fs::read_to_string({}) => {}", &input_path, program.unwrap_err()));
+        return Err(ParseError::in_file(
+            &input_path,
+            format!("Failed to read the file: {}", program.unwrap_err()),
+        ));
     }
     let program = program.unwrap();
 
-    parse_string(&program, Ctxt::new(input_path))
+    parse_spanned(&program, Ctxt::new(input_path), max_include_depth)
+}
+
+fn code_only(program: Program, filename: &str) -> Result<Vec<Code>, ParseError> {
+    if !program.data.is_empty() {
+        return Err(ParseError::in_file(
+            filename,
+            ".data sections are only supported when parsing a whole program".to_string(),
+        ));
+    }
+    if !program.registers.is_empty() {
+        return Err(ParseError::in_file(
+            filename,
+            ".regs is only supported when parsing a whole program".to_string(),
+        ));
+    }
+    Ok(program.code)
+}
+
+// a line of source code along with where it came from, since `.include` brings in lines from other files
+#[derive(Debug)]
+struct SourceLine {
+    ctxt: Ctxt,
+    text: String,
+}
+
+fn expand_includes(
+    raw_code: &str,
+    mut ctxt: Ctxt,
+    including: &mut Vec<PathBuf>,
+    depth: usize,
+    max_depth: usize,
+    conditionals: &mut Conditionals,
+) -> Result<Vec<SourceLine>, ParseError> {
+    // `.include "path"` is replaced by the lines of the file at `path` (relative to the including
+    // file's directory), so that the included code and labels are shared with the rest of the program.
+    // `including` holds the canonicalized paths of the files currently being expanded to catch cycles,
+    // while `depth` counts how many includes deep we are, since each one recurses on the Rust stack.
+    // Conditionals are resolved along the way, so that an `.include` in a block left out isn't opened.
+    let mut lines = Vec::new();
+
+    let raw_code = strip_block_comments(raw_code, &ctxt);
+    if raw_code.is_err() {
+        return Err(raw_code.unwrap_err());
+    }
+    let raw_code = raw_code.unwrap();
+
+    for line in raw_code.lines() {
+        ctxt.inc(); // increment line number
+
+        let directive = line.split("//").next().unwrap().trim().strip_prefix(".include");
+        let include_path = match directive {
+            Some(rest) if rest.is_empty() || rest.starts_with(char::is_whitespace) => rest.trim(),
+            _ => {
+                let statements = split_statements(vec![SourceLine {
+                    ctxt: ctxt.clone(),
+                    text: line.to_string(),
+                }]);
+                for statement in statements {
+                    let keep = conditionals.keep(&statement);
+                    if keep.is_err() {
+                        return Err(keep.unwrap_err());
+                    }
+                    if keep.unwrap() {
+                        lines.push(statement);
+                    }
+                }
+                continue;
+            }
+        };
+        if !conditionals.is_active() {
+            continue;
+        }
+
+        let include_path = include_path.strip_prefix('"').and_then(|p| p.strip_suffix('"'));
+        if include_path.is_none() || include_path.unwrap().is_empty() {
+            return Err(ctxt.error(".include expected to find a quoted path".to_string()));
+        }
+        let include_path = Path::new(&ctxt.filename)
+            .parent()
+            .unwrap_or(Path::new(""))
+            .join(include_path.unwrap());
+        let include_filename = include_path.to_string_lossy().to_string();
+
+        let canonical_path = include_path.canonicalize();
+        if canonical_path.is_err() {
+            return Err(ctxt.error(format!("Failed to include {}: {}", include_filename, canonical_path.unwrap_err())));
+        }
+        let canonical_path = canonical_path.unwrap();
+        if including.contains(&canonical_path) {
+            return Err(ctxt.error(format!("Including {} would recurse into itself", include_filename)));
+        }
+
+        if depth >= max_depth {
+            return Err(ctxt.error(format!("Include depth limit exceeded including {}", include_filename)));
+        }
+
+        let included_code = std::fs::read_to_string(&include_path);
+        if included_code.is_err() {
+            return Err(ctxt.error(format!("Failed to include {}: {}", include_filename, included_code.unwrap_err())));
+        }
+        let included_code = included_code.unwrap();
+
+        including.push(canonical_path);
+        let included_lines =
+            expand_includes(&included_code, Ctxt::new(include_filename), including, depth + 1, max_depth, conditionals);
+        including.pop();
+        if included_lines.is_err() {
+            return Err(included_lines.unwrap_err());
+        }
+        lines.extend(included_lines.unwrap());
+    }
+
+    Ok(lines)
+}
+
+fn strip_block_comments(raw_code: &str, ctxt: &Ctxt) -> Result<String, ParseError> {
+    // everything from `/*` to the next `*/` is blanked out with spaces, even across lines, so that the
+    // lines and columns of what's left stay the same. `/*` inside a `//` comment or a character literal
+    // doesn't start one, and neither nesting them nor leaving one open is allowed.
+    let error_at = |line: usize, column: usize, message: &str| ParseError {
+        line: Some(line),
+        column: Some(column),
+        ..ctxt.error(message.to_string())
+    };
+
+    let mut stripped = Vec::new();
+    // where the block comment being read was opened, if any
+    let mut opened: Option<(usize, usize)> = None;
+
+    for (line_idx, line) in raw_code.lines().enumerate() {
+        let chars: Vec<char> = line.chars().collect();
+        let mut text = String::with_capacity(line.len());
+        let mut in_char = false;
+        let mut escaped = false;
+        let mut idx = 0;
+        while idx < chars.len() {
+            let pair = (chars[idx], chars.get(idx + 1).copied().unwrap_or(' '));
+            if opened.is_some() {
+                match pair {
+                    ('/', '*') => return Err(error_at(line_idx + 1, idx + 1, "Block comments can't be nested")),
+                    ('*', '/') => {
+                        opened = None;
+                        text.push_str("  ");
+                        idx += 2;
+                    }
+                    _ => {
+                        text.push(' ');
+                        idx += 1;
+                    }
+                }
+                continue;
+            }
+
+            match pair {
+                _ if escaped => escaped = false,
+                ('\\', _) if in_char => escaped = true,
+                ('\'', _) => in_char = !in_char,
+                ('/', '/') if !in_char => {
+                    // the rest of the line is a regular comment
+                    text.extend(&chars[idx..]);
+                    break;
+                }
+                ('/', '*') if !in_char => {
+                    opened = Some((line_idx + 1, idx + 1));
+                    text.push_str("  ");
+                    idx += 2;
+                    continue;
+                }
+                _ => {}
+            }
+            text.push(chars[idx]);
+            idx += 1;
+        }
+        stripped.push(text);
+    }
+
+    if let Some((line, column)) = opened {
+        return Err(error_at(line, column, "Block comment is never closed"));
+    }
+    Ok(stripped.join("\n"))
+}
+
+fn split_statements(lines: Vec<SourceLine>) -> Vec<SourceLine> {
+    // `A; B` is turned into a line with `A` and another with `B`, both with the same context. Each
+    // keeps the other statements blanked out instead of removed, so that columns stay the same.
+    let mut split = Vec::new();
+
+    for line in lines {
+        let code = line.text.split("//").next().unwrap();
+
+        // a `;` inside a character literal (i.e. `';'`) doesn't end the statement
+        let mut ends = Vec::new();
+        let mut in_char = false;
+        let mut escaped = false;
+        for (idx, c) in code.char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if in_char => escaped = true,
+                '\'' => in_char = !in_char,
+                ';' if !in_char => ends.push(idx),
+                _ => {}
+            }
+        }
+        if ends.is_empty() {
+            split.push(line);
+            continue;
+        }
+        ends.push(code.len());
+
+        let mut start = 0;
+        for end in ends {
+            let blank = |text: &str| " ".repeat(text.chars().count());
+            let text = format!("{}{}{}", blank(&code[..start]), &code[start..end], blank(&code[end..]));
+            // empty statements (e.g. after a trailing `;`) are just dropped
+            if !text.trim().is_empty() {
+                split.push(SourceLine {
+                    ctxt: line.ctxt.clone(),
+                    text,
+                });
+            }
+            start = end + 1;
+        }
+    }
+
+    split
+}
+
+// an `.if` block being read: whether its lines are kept, whether its `.else` was seen and where it started
+struct Conditional {
+    active: bool,
+    in_else: bool,
+    ctxt: Ctxt,
+}
+
+// the symbols defined so far and the `.if` blocks being read, shared by every included file
+#[derive(Default)]
+struct Conditionals {
+    symbols: HashSet<String>,
+    open: Vec<Conditional>,
+}
+
+impl Conditionals {
+    fn is_active(&self) -> bool {
+        self.open.iter().all(|conditional| conditional.active)
+    }
+
+    /// Handles `line` if it is a `.def`, `.if`, `.else` or `.endif`, returning whether it is a line to keep.
+    fn keep(&mut self, line: &SourceLine) -> Result<bool, ParseError> {
+        // `.def SYMBOL` defines a symbol, and `.if SYMBOL` ... `.else` ... `.endif` keeps the lines before
+        // the `.else` if SYMBOL was defined by then and the ones after it otherwise. Blocks can be nested,
+        // and `.def`s inside a block that is left out don't count.
+        let ctxt = &line.ctxt;
+        let text = line.text.split("//").next().unwrap().trim();
+        let mut parts = text.split_whitespace();
+        let directive = parts.next();
+        let is_active = self.is_active();
+
+        match directive {
+            Some(".def") | Some(".if") => {
+                let directive = directive.unwrap();
+                let symbol = parts.next();
+                if symbol.is_none() || !is_identifier(symbol.unwrap()) {
+                    return Err(ctxt.error(format!("{} expected to find a symbol name", directive)));
+                }
+                let symbol = symbol.unwrap();
+                if let Some(extra) = parts.next() {
+                    return Err(ctxt.error(format!("{} expected to find end of line but got {}", directive, extra)));
+                }
+
+                if directive == ".if" {
+                    self.open.push(Conditional {
+                        active: self.symbols.contains(symbol),
+                        in_else: false,
+                        ctxt: ctxt.clone(),
+                    });
+                } else if is_active {
+                    self.symbols.insert(symbol.to_string());
+                }
+                Ok(false)
+            }
+            Some(".else") | Some(".endif") => {
+                let directive = directive.unwrap();
+                if let Some(extra) = parts.next() {
+                    return Err(ctxt.error(format!("{} expected to find end of line but got {}", directive, extra)));
+                }
+                let conditional = self.open.last_mut();
+                if conditional.is_none() {
+                    return Err(ctxt.error(format!("{} found outside of an .if", directive)));
+                }
+                let conditional = conditional.unwrap();
+
+                if directive == ".endif" {
+                    self.open.pop();
+                } else if conditional.in_else {
+                    return Err(ctxt.error(format!(
+                        ".if at {}.{} already has an .else",
+                        conditional.ctxt.filename, conditional.ctxt.line
+                    )));
+                } else {
+                    conditional.in_else = true;
+                    conditional.active = !conditional.active;
+                }
+                Ok(false)
+            }
+            _ => Ok(is_active),
+        }
+    }
+}
+
+// how many macro invocations can be nested inside each other before we assume it recurses forever
+const MAX_MACRO_DEPTH: usize = 16;
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<String>,
+}
+
+fn expand_macros(lines: Vec<SourceLine>) -> Result<Vec<SourceLine>, ParseError> {
+    // `.macro NAME param0 param1` ... `.endmacro` defines a macro, and a line `NAME arg0 arg1` is
+    // replaced by its body with every token equal to a param replaced by the corresponding argument.
+    // Definitions are collected in a pre-pass, so a macro can be used before it is defined.
+    let mut macros: HashMap<String, Macro> = HashMap::new();
+    let mut code_lines = Vec::new();
+
+    // commas between operands are blanked out first, so that params and args are always whole tokens
+    let mut blanked = Vec::new();
+    for line in lines {
+        let mut ctxt = line.ctxt;
+        ctxt.text = line.text;
+        let text = blank_operand_commas(&ctxt);
+        if text.is_err() {
+            return Err(text.unwrap_err());
+        }
+        blanked.push(SourceLine {
+            ctxt,
+            text: text.unwrap(),
+        });
+    }
+
+    let mut lines = blanked.into_iter();
+    while let Some(line) = lines.next() {
+        let ctxt = &line.ctxt;
+        let text = line.text.split("//").next().unwrap().trim();
+        let mut parts = text.split_whitespace();
+
+        match parts.next() {
+            Some(".macro") => {
+                let name = parts.next();
+                if name.is_none() || !is_identifier(name.unwrap()) {
+                    return Err(ctxt.error(".macro expected to find a macro name".to_string()));
+                }
+                let name = name.unwrap().to_string();
+                // opcodes can be written in any case, so e.g. `set` would shadow SET too
+                if OpCode::from_str(&name.to_ascii_uppercase()).is_ok() {
+                    return Err(ctxt.error(format!("Macro {} would shadow an opcode", name)));
+                }
+                if macros.contains_key(&name) {
+                    return Err(ctxt.error(format!("Macro {} already defined", name)));
+                }
+
+                let mut params = Vec::new();
+                for param in parts {
+                    if !is_identifier(param) {
+                        return Err(ctxt.error(format!("Invalid parameter {} for macro {}", param, name)));
+                    }
+                    params.push(param.to_string());
+                }
+
+                let mut body = Vec::new();
+                loop {
+                    let body_line = lines.next();
+                    if body_line.is_none() {
+                        return Err(ctxt.error(format!("Macro {} is missing its .endmacro", name)));
+                    }
+                    let body_line = body_line.unwrap();
+                    let body_text = body_line.text.split("//").next().unwrap().trim();
+
+                    if body_text == ".endmacro" {
+                        break;
+                    }
+                    if body_text.starts_with(".macro") {
+                        return Err(body_line
+                            .ctxt
+                            .error("Macros can't be defined inside another macro".to_string()));
+                    }
+                    if !body_text.is_empty() {
+                        body.push(body_text.to_string());
+                    }
+                }
+
+                macros.insert(name, Macro { params, body });
+            }
+            Some(".endmacro") => {
+                return Err(ctxt.error(".endmacro found outside of a macro".to_string()));
+            }
+            _ => code_lines.push(line),
+        }
+    }
+
+    let mut expanded = Vec::new();
+    for line in code_lines {
+        let expand_op = expand_macro_line(line, &macros, 0, &mut expanded);
+        if expand_op.is_err() {
+            return Err(expand_op.unwrap_err());
+        }
+    }
+
+    Ok(expanded)
+}
+
+fn expand_macro_line(
+    line: SourceLine,
+    macros: &HashMap<String, Macro>,
+    depth: usize,
+    expanded: &mut Vec<SourceLine>,
+) -> Result<(), ParseError> {
+    let text = line.text.split("//").next().unwrap().trim();
+    let mut parts = split_tokens(text);
+
+    let name = parts.next().unwrap_or("");
+    let macro_def = match macros.get(name) {
+        Some(macro_def) => macro_def,
+        None => {
+            expanded.push(line);
+            return Ok(());
+        }
+    };
+
+    // the expanded lines keep the invocation's context, so errors point to where the macro was used
+    let offset = name.as_ptr() as usize - line.text.as_ptr() as usize;
+    let ctxt = &Ctxt {
+        expanded_at: line.ctxt.expanded_at.or(Some(line.text[..offset].chars().count() + 1)),
+        ..line.ctxt.clone()
+    };
+    if depth >= MAX_MACRO_DEPTH {
+        return Err(ctxt.error(format!(
+            "Expanding macro {} exceeded the maximum nesting depth of {}",
+            name, MAX_MACRO_DEPTH
+        )));
+    }
+
+    let args: Vec<&str> = parts.collect();
+    if args.len() != macro_def.params.len() {
+        return Err(ctxt.error(format!(
+            "Macro {} expects {} arguments but got {}",
+            name,
+            macro_def.params.len(),
+            args.len()
+        )));
+    }
+
+    for body_line in &macro_def.body {
+        let text = split_tokens(body_line)
+            .map(|token| match macro_def.params.iter().position(|param| param == token) {
+                Some(idx) => args[idx],
+                None => token,
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let body_line = SourceLine {
+            ctxt: ctxt.clone(),
+            text,
+        };
+        let expand_op = expand_macro_line(body_line, macros, depth + 1, expanded);
+        if expand_op.is_err() {
+            return Err(expand_op.unwrap_err());
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_string(raw_code: &str, ctxt: Ctxt) -> Result<Program, ParseError> {
+    let program = parse_spanned(raw_code, ctxt, DEFAULT_MAX_INCLUDE_DEPTH);
+    if program.is_err() {
+        return Err(program.unwrap_err());
+    }
+    Ok(program.unwrap().0)
 }
 
-fn parse_string(raw_code: &String, mut ctxt: Ctxt) -> Result<Vec<Code>, String> {
+fn parse_spanned(
+    raw_code: &str,
+    ctxt: Ctxt,
+    max_include_depth: usize,
+) -> Result<(Program, Vec<SourceSpan>), ParseError> {
     let mut code = Vec::new();
+    // where each entry of `code` was written, i.e. the line and column of its token
+    let mut spans: Vec<SourceSpan> = Vec::new();
+
+    // the file being parsed is the first one being included, if it's an actual file
+    let mut including: Vec<PathBuf> = Path::new(&ctxt.filename).canonicalize().into_iter().collect();
+    let mut conditionals = Conditionals::default();
+    let lines = expand_includes(raw_code, ctxt, &mut including, 0, max_include_depth, &mut conditionals);
+    if lines.is_err() {
+        return Err(lines.unwrap_err());
+    }
+    if let Some(conditional) = conditionals.open.last() {
+        return Err(conditional.ctxt.error(".if is missing its .endif".to_string()));
+    }
+    let lines = expand_macros(lines.unwrap());
+    if lines.is_err() {
+        return Err(lines.unwrap_err());
+    }
+    let lines = lines.unwrap();
+
+    // values declared in the `.data` section, which will be placed at the bottom of the stack
+    let mut data: Vec<i64> = Vec::new();
+    let mut in_data_section = false;
 
     // hashmap where we store the labels and their corresponding "address"
     let mut labels: HashMap<String, usize> = std::collections::HashMap::new();
-    // hashmap where we map where references to labels happened to the label being addressed
-    let mut label_refs: HashMap<usize, String> = std::collections::HashMap::new();
+    // hashmap where we map where references to labels happened to the label being addressed (and
+    // the line it was written in, in case it turns out to be undefined)
+    let mut label_refs: HashMap<usize, (String, Ctxt)> = std::collections::HashMap::new();
     // after parsing the program, we substitute label_refs by the actual "address"
 
+    // labels as they were written (i.e. `.child` for sublabels) in the order they were defined
+    let mut defined_labels: Vec<(String, usize)> = Vec::new();
+
+    // address of each instruction and the line it was written in
+    let mut source_lines: Vec<(usize, usize)> = Vec::new();
+
+    // hashmap where we store the constants defined through `.const NAME VALUE`
+    let mut consts: HashMap<String, Code> = HashMap::new();
+
+    // register aliases defined through `.reg NAME rN`, also kept in order for the debug symbols
+    let mut reg_aliases: HashMap<String, u8> = HashMap::new();
+    let mut defined_reg_aliases: Vec<(String, u8)> = Vec::new();
+
+    // initial register values set through `.regs v0 v1 ...`, which can only be given once
+    let mut registers: Option<Vec<i64>> = None;
+
     let mut current_parent_label: String = "__beggining_of_program__".to_string();
 
-    for line in raw_code.lines() {
-        ctxt.inc(); // increment line number
+    for SourceLine { mut ctxt, text } in lines {
+        // the line is kept in the context so that errors can point to the token they're about
+        ctxt.text = text;
+        let text = &ctxt.text;
 
         // Trim whitespace and ignore any comments (i.e. everything starting after //)
-        let line = line.splitn(2, "//").next().unwrap().trim();
+        let line = text.split("//").next().unwrap().trim();
         if line.is_empty() {
             continue;
         }
 
-        let mut parts = line.split_whitespace();
+        let mut parts = split_tokens(line);
         let raw_op = parts.next().unwrap();
 
+        // `.data` and `.text` switch between declaring data and writing code
+        if raw_op == ".data" || raw_op == ".text" {
+            if let Some(next) = parts.next() {
+                return Err(ctxt.error_at(next, format!("{} expected to find end of line but got {}", raw_op, next)));
+            }
+            in_data_section = raw_op == ".data";
+            continue;
+        }
+
+        if raw_op == ".const" {
+            let constant = parse_const(&mut parts, &ctxt);
+            if constant.is_err() {
+                return Err(constant.unwrap_err());
+            }
+            let (name, value) = constant.unwrap();
+
+            if consts.contains_key(&name) {
+                return Err(ctxt.error(format!("Constant {} already defined", name)));
+            }
+            consts.insert(name, value);
+            continue;
+        }
+
+        if raw_op == ".org" {
+            // `.org N` pads the code with NOPs so that what comes next starts at address N
+            let token = parts.next();
+            if token.is_none() {
+                return Err(ctxt.error(".org expected to find an address but found nothing".to_string()));
+            }
+            let token = token.unwrap();
+            let addr = match consts.get(token) {
+                Some(Code::Int(int)) => Ok(*int),
+                _ => parse_int_literal(token),
+            };
+            if addr.is_err() || *addr.as_ref().unwrap() < 0 {
+                return Err(ctxt.error_at(token, format!(".org expected to find an address but got {}", token)));
+            }
+            let addr = addr.unwrap() as usize;
+            if let Some(next) = parts.next() {
+                return Err(ctxt.error_at(next, format!(".org expected to find end of line but got {}", next)));
+            }
+            if addr < code.len() {
+                return Err(ctxt.error_at(
+                    token,
+                    format!(".org {} would move back past code already placed up to address {}", addr, code.len() - 1),
+                ));
+            }
+
+            let offset = raw_op.as_ptr() as usize - text.as_ptr() as usize;
+            let span = SourceSpan {
+                filename: ctxt.filename.clone(),
+                line: ctxt.line,
+                column: ctxt.expanded_at.unwrap_or(text[..offset].chars().count() + 1),
+            };
+            while code.len() < addr {
+                source_lines.push((code.len(), ctxt.line));
+                code.push(Code::Op(OpCode::NOP));
+                spans.push(span.clone());
+            }
+            continue;
+        }
+
+        if raw_op == ".regs" {
+            if registers.is_some() {
+                return Err(ctxt.error("Initial registers already set by .regs".to_string()));
+            }
+            let mut values = Vec::new();
+            for token in parts {
+                let val = parse_reg_value(token, &ctxt, &consts);
+                if val.is_err() {
+                    return Err(val.unwrap_err());
+                }
+                values.push(val.unwrap());
+            }
+            registers = Some(values);
+            continue;
+        }
+
+        if raw_op == ".reg" {
+            let alias = parse_reg_alias(&mut parts, &ctxt);
+            if alias.is_err() {
+                return Err(alias.unwrap_err());
+            }
+            let (name, reg) = alias.unwrap();
+
+            if reg_aliases.contains_key(&name) {
+                return Err(ctxt.error(format!("Register alias {} already defined", name)));
+            }
+            reg_aliases.insert(name.clone(), reg);
+            defined_reg_aliases.push((name, reg));
+            continue;
+        }
+
+        if in_data_section {
+            // data labels become integer constants holding the stack index where their values start
+            if let Some(name) = raw_op.strip_suffix(':') {
+                if !is_identifier(name) {
+                    return Err(ctxt.error(format!("Invalid data label {}", name)));
+                }
+                if consts.contains_key(name) {
+                    return Err(ctxt.error(format!("Data label {} already defined", name)));
+                }
+                consts.insert(name.to_string(), Code::Int(data.len() as i64));
+            } else {
+                // a line without a label keeps adding values to the previous one
+                parts = split_tokens(line);
+            }
+
+            for val in parts {
+                let val = parse_data_value(val, &ctxt, &consts);
+                if val.is_err() {
+                    return Err(val.unwrap_err());
+                }
+                data.push(val.unwrap());
+            }
+            continue;
+        }
+
         // raw_op can either be an actual op or a label, so let's check if it's a label first
         // if it is a label, we'll skip to the next line
-        if raw_op.ends_with(":") {
-            if raw_op.starts_with(".") {
+        if let Some(raw_label) = raw_op.strip_suffix(':') {
+            if let Some(raw_sublabel) = raw_label.strip_prefix('.') {
                 // sublabel, so the actual label is the concatenation of the current parent label
-                let label = format!("{}>{}", current_parent_label, &raw_op[1..raw_op.len() - 1]);
+                let label = format!("{}>{}", current_parent_label, raw_sublabel);
                 if labels.contains_key(&label) {
                     // TODO: add unit test for this behavior
-                    return Err(err!("{}.{}: Sublabel {} already defined", ctxt.filename, ctxt.line, label));
+                    return Err(ctxt.error(format!("Sublabel {} already defined", label)));
                 }
                 labels.insert(label, code.len());
+                defined_labels.push((raw_label.to_string(), code.len()));
             } else {
                 // regular label
-                let label = raw_op[..raw_op.len() - 1].to_string();
+                let label = raw_label.to_string();
                 if labels.contains_key(&label) {
                     // TODO: add unit test for this behavior
-                    return Err(err!("{}.{}: Label {} already defined", ctxt.filename, ctxt.line, label));
+                    return Err(ctxt.error(format!("Label {} already defined", label)));
                 }
                 labels.insert(label.clone(), code.len());
+                defined_labels.push((label.clone(), code.len()));
                 current_parent_label = label;
             }
             continue;
         }
 
-        let op = OpCode::from_str(raw_op);
+        // opcodes can be written in any case, while labels, registers and everything else can't
+        let op = OpCode::from_str(&raw_op.to_ascii_uppercase());
         if op.is_err() {
-            return Err(err!("{}.{}: Expected to find an OpCode but found {}", ctxt.filename, ctxt.line, raw_op));
+            return Err(ctxt.error_at(raw_op, format!("Expected to find an OpCode but found {}", raw_op)));
         }
         let op = op.unwrap();
 
+        // remember which line each instruction came from, for the debug symbols
+        source_lines.push((code.len(), ctxt.line));
+
         let op_type = OP_ARG_TYPES[op as usize];
 
         match op_type {
@@ -92,7 +903,7 @@ fn parse_string(raw_code: &String, mut ctxt: Ctxt) -> Result<Vec<Code>, String>
                 code.push(Code::Op(op));
             }
             OpArgT::Reg => {
-                let reg = consume_reg(&mut parts, op, &ctxt);
+                let reg = consume_reg(&mut parts, op, &ctxt, &reg_aliases);
                 if reg.is_err() {
                     return Err(reg.unwrap_err());
                 }
@@ -107,13 +918,13 @@ fn parse_string(raw_code: &String, mut ctxt: Ctxt) -> Result<Vec<Code>, String>
                 code.push(Code::Reg(reg));
             }
             OpArgT::IntReg => {
-                let int = consume_int(&mut parts, op, &ctxt);
+                let int = consume_int(&mut parts, op, &ctxt, &consts);
                 if int.is_err() {
                     return Err(int.unwrap_err());
                 }
                 let int = int.unwrap();
 
-                let reg = consume_reg(&mut parts, op, &ctxt);
+                let reg = consume_reg(&mut parts, op, &ctxt, &reg_aliases);
                 if reg.is_err() {
                     return Err(reg.unwrap_err());
                 }
@@ -129,13 +940,13 @@ fn parse_string(raw_code: &String, mut ctxt: Ctxt) -> Result<Vec<Code>, String>
                 code.push(Code::Reg(reg));
             }
             OpArgT::RegReg => {
-                let reg1 = consume_reg(&mut parts, op, &ctxt);
+                let reg1 = consume_reg(&mut parts, op, &ctxt, &reg_aliases);
                 if reg1.is_err() {
                     return Err(reg1.unwrap_err());
                 }
                 let reg1 = reg1.unwrap();
 
-                let reg2 = consume_reg(&mut parts, op, &ctxt);
+                let reg2 = consume_reg(&mut parts, op, &ctxt, &reg_aliases);
                 if reg2.is_err() {
                     return Err(reg2.unwrap_err());
                 }
@@ -157,32 +968,27 @@ fn parse_string(raw_code: &String, mut ctxt: Ctxt) -> Result<Vec<Code>, String>
                 let label = parts.next();
                 if label.is_none() {
                     // TODO: add unit test for this behavior
-                    return Err(err!(
-                        "{}.{}: {} expected to find a label but found nothing",
-                        ctxt.filename,
-                        ctxt.line,
-                        op
-                    ));
+                    return Err(ctxt.error(format!("{} expected to find a label but found nothing", op)));
                 }
                 let label = label.unwrap();
 
                 // handle sublabel behavior
-                let label = if label.starts_with(".") {
-                    format!("{}>{}", current_parent_label, &label[1..])
+                let label = if let Some(sublabel) = label.strip_prefix('.') {
+                    format!("{}>{}", current_parent_label, sublabel)
                 } else {
                     label.to_string()
                 };
 
                 // note that currently code.len() will point to the operation that
                 // takes in the addr, so to point to the addr itself we'll need a +1
-                label_refs.insert(code.len() + 1, label);
+                label_refs.insert(code.len() + 1, (label, ctxt.clone()));
 
                 code.push(Code::Op(op));
                 code.push(Code::Addr(0)); // placeholder
             }
             OpArgT::Int => {
                 let int = {
-                    let int = consume_int(&mut parts, op, &ctxt);
+                    let int = consume_int(&mut parts, op, &ctxt, &consts);
                     if int.is_err() {
                         return Err(int.unwrap_err());
                     }
@@ -199,7 +1005,7 @@ fn parse_string(raw_code: &String, mut ctxt: Ctxt) -> Result<Vec<Code>, String>
             }
             OpArgT::RealReg => {
                 let val = {
-                    let val = consume_real(&mut parts, op, &ctxt);
+                    let val = consume_real(&mut parts, op, &ctxt, &consts);
                     if val.is_err() {
                         return Err(val.unwrap_err());
                     }
@@ -207,7 +1013,7 @@ fn parse_string(raw_code: &String, mut ctxt: Ctxt) -> Result<Vec<Code>, String>
                 };
 
                 let reg = {
-                    let reg = consume_reg(&mut parts, op, &ctxt);
+                    let reg = consume_reg(&mut parts, op, &ctxt, &reg_aliases);
                     if reg.is_err() {
                         return Err(reg.unwrap_err());
                     }
@@ -223,113 +1029,474 @@ fn parse_string(raw_code: &String, mut ctxt: Ctxt) -> Result<Vec<Code>, String>
                 code.push(Code::Real(val));
                 code.push(Code::Reg(reg));
             }
-        }
-    }
+            OpArgT::IntIntReg => {
+                let int1 = consume_int(&mut parts, op, &ctxt, &consts);
+                if int1.is_err() {
+                    return Err(int1.unwrap_err());
+                }
+                let int1 = int1.unwrap();
 
-    // Now, for each entry in label_refs, we'll substitute the label by its address
-    for (addr, label) in label_refs {
-        let label_addr = labels.get(&label);
-        if label_addr.is_none() {
-            // TODO: add unit test for this behavior
-            return Err(err!("Reference to label {} at addr {} found but it's not defined", label, addr));
-        }
-        let label_addr = label_addr.unwrap();
-        code[addr] = Code::Addr(*label_addr);
-    }
+                let int2 = consume_int(&mut parts, op, &ctxt, &consts);
+                if int2.is_err() {
+                    return Err(int2.unwrap_err());
+                }
+                let int2 = int2.unwrap();
 
-    Ok(code)
-}
+                let reg = consume_reg(&mut parts, op, &ctxt, &reg_aliases);
+                if reg.is_err() {
+                    return Err(reg.unwrap_err());
+                }
+                let reg = reg.unwrap();
 
-fn consume_int(parts: &mut std::str::SplitWhitespace, op: OpCode, ctxt: &Ctxt) -> Result<i64, String> {
-    let val = parts.next();
-    if val.is_none() {
-        return Err(err!(
-            "{}.{}: {} expected to find an integer but found nothing",
-            ctxt.filename,
-            ctxt.line,
-            op
-        ));
+                let line_is_over_chck = validate_line_is_over(&mut parts, op, &ctxt);
+                if line_is_over_chck.is_err() {
+                    return Err(line_is_over_chck.unwrap_err());
+                }
+
+                code.push(Code::Op(op));
+                code.push(Code::Int(int1));
+                code.push(Code::Int(int2));
+                code.push(Code::Reg(reg));
+            }
+            OpArgT::RegRegInt => {
+                let reg1 = consume_reg(&mut parts, op, &ctxt, &reg_aliases);
+                if reg1.is_err() {
+                    return Err(reg1.unwrap_err());
+                }
+                let reg1 = reg1.unwrap();
+
+                let reg2 = consume_reg(&mut parts, op, &ctxt, &reg_aliases);
+                if reg2.is_err() {
+                    return Err(reg2.unwrap_err());
+                }
+                let reg2 = reg2.unwrap();
+
+                let int = consume_int(&mut parts, op, &ctxt, &consts);
+                if int.is_err() {
+                    return Err(int.unwrap_err());
+                }
+                let int = int.unwrap();
+
+                let line_is_over_chck = validate_line_is_over(&mut parts, op, &ctxt);
+                if line_is_over_chck.is_err() {
+                    return Err(line_is_over_chck.unwrap_err());
+                }
+
+                code.push(Code::Op(op));
+                code.push(Code::Reg(reg1));
+                code.push(Code::Reg(reg2));
+                code.push(Code::Int(int));
+            }
+            OpArgT::RegRegReg => {
+                let mut regs = [0; 3];
+                for reg in regs.iter_mut() {
+                    let parsed = consume_reg(&mut parts, op, &ctxt, &reg_aliases);
+                    if parsed.is_err() {
+                        return Err(parsed.unwrap_err());
+                    }
+                    *reg = parsed.unwrap();
+                }
+
+                let line_is_over_chck = validate_line_is_over(&mut parts, op, &ctxt);
+                if line_is_over_chck.is_err() {
+                    return Err(line_is_over_chck.unwrap_err());
+                }
+
+                code.push(Code::Op(op));
+                code.extend(regs.map(Code::Reg));
+            }
+        }
+
+        // the opcode and each of its arguments came from a token of their own, in the same order, except
+        // for expanded macros, whose tokens all point to where the macro was used
+        for token in split_tokens(line).take(code.len() - spans.len()) {
+            let offset = token.as_ptr() as usize - text.as_ptr() as usize;
+            spans.push(SourceSpan {
+                filename: ctxt.filename.clone(),
+                line: ctxt.line,
+                column: ctxt.expanded_at.unwrap_or(text[..offset].chars().count() + 1),
+            });
+        }
+    }
+
+    // Now, for each entry in label_refs, we'll substitute the label by its address
+    for (addr, (label, ctxt)) in label_refs {
+        let label_addr = labels.get(&label);
+        if label_addr.is_none() {
+            // TODO: add unit test for this behavior
+            return Err(ctxt.error(format!("Reference to label {} at addr {} found but it's not defined", label, addr)));
+        }
+        let label_addr = label_addr.unwrap();
+        code[addr] = Code::Addr(*label_addr);
+    }
+
+    let symbols = DebugSymbols {
+        labels: defined_labels,
+        lines: source_lines,
+        registers: defined_reg_aliases,
+    };
+
+    let program = Program {
+        code,
+        data,
+        registers: registers.unwrap_or_default(),
+        symbols,
+    };
+    Ok((program, spans))
+}
+
+fn consume_int(parts: &mut Tokens, op: OpCode, ctxt: &Ctxt, consts: &HashMap<String, Code>) -> Result<i64, ParseError> {
+    let val = parts.next();
+    if val.is_none() {
+        return Err(ctxt.error(format!("{} expected to find an integer but found nothing", op)));
     }
     let val = val.unwrap();
-    let val = i64::from_str(val);
-    if val.is_err() {
-        return Err(err!(
-            "{}.{}: {} expected to find an integer but got {}",
-            ctxt.filename,
-            ctxt.line,
-            op,
-            val.unwrap_err()
+
+    match consts.get(val) {
+        Some(Code::Int(int)) => return Ok(*int),
+        Some(_) => {
+            return Err(ctxt.error_at(val, format!("{} expected to find an integer but constant {} is a real", op, val)))
+        }
+        None => {}
+    }
+
+    let parsed = parse_int_literal(val);
+    if parsed.is_err() && is_identifier(val) {
+        return Err(
+            ctxt.error_at(val, format!("{} expected to find an integer but got undefined constant {}", op, val))
+        );
+    }
+    // reals are a common slip when there's a float version of the instruction, so point to it
+    if let (true, Some(float_op)) = (parsed.is_err() && f64::from_str(val).is_ok(), float_counterpart(op)) {
+        return Err(ctxt.error_at(
+            val,
+            format!(
+                "{} expected to find an integer but got {} ({} takes an integer; for a float use {})",
+                op, val, op, float_op
+            ),
         ));
     }
-    Ok(val.unwrap())
+    if parsed.is_err() {
+        return Err(ctxt.error_at(val, format!("{} expected to find an integer but got {}", op, parsed.unwrap_err())));
+    }
+    Ok(parsed.unwrap())
 }
 
-fn consume_reg(parts: &mut std::str::SplitWhitespace, op: OpCode, ctxt: &Ctxt) -> Result<u8, String> {
+/// The instruction doing the same as `op` but taking a real instead of an integer, if there's one.
+fn float_counterpart(op: OpCode) -> Option<OpCode> {
+    match op {
+        OpCode::SET => Some(OpCode::SETF),
+        OpCode::ADDL => Some(OpCode::ADDFL),
+        OpCode::SUBL => Some(OpCode::SUBFL),
+        OpCode::SUB2L => Some(OpCode::SUBF2L),
+        OpCode::MULL => Some(OpCode::MULFL),
+        OpCode::DIVL => Some(OpCode::DIVFL),
+        OpCode::DIV2L => Some(OpCode::DIVF2L),
+        _ => None,
+    }
+}
+
+fn consume_reg(parts: &mut Tokens, op: OpCode, ctxt: &Ctxt, aliases: &HashMap<String, u8>) -> Result<u8, ParseError> {
     let reg = parts.next();
     if reg.is_none() {
-        return Err(err!(
-            "{}.{}: {} expected to find a register but found nothing",
-            ctxt.filename,
-            ctxt.line,
-            op,
-        ));
+        return Err(ctxt.error(format!("{} expected to find a register but found nothing", op)));
     }
     let reg = reg.unwrap();
 
+    if let Some(reg) = aliases.get(reg) {
+        return Ok(*reg);
+    }
+
     // make sure it has the r prefix
     if !reg.starts_with('r') {
-        return Err(err!(
-            "{}.{}: {} expected to find a register but got {}",
-            ctxt.filename,
-            ctxt.line,
-            op,
-            reg
-        ));
+        return Err(ctxt.error_at(reg, format!("{} expected to find a register but got {}", op, reg)));
     }
-    let reg = &reg[1..];
 
-    let reg = u8::from_str(reg);
-    if reg.is_err() {
-        return Err(err!(
-            "{}.{}: {} expected to find a register but got {}",
-            ctxt.filename,
-            ctxt.line,
-            op,
-            reg.unwrap_err()
-        ));
+    let idx = u8::from_str(&reg[1..]);
+    if idx.is_err() {
+        return Err(ctxt.error_at(reg, format!("{} expected to find a register but got {}", op, idx.unwrap_err())));
     }
-    Ok(reg.unwrap())
+    Ok(idx.unwrap())
 }
 
-fn consume_real(parts: &mut std::str::SplitWhitespace, op: OpCode, ctxt: &Ctxt) -> Result<f64, String> {
+fn consume_real(
+    parts: &mut Tokens,
+    op: OpCode,
+    ctxt: &Ctxt,
+    consts: &HashMap<String, Code>,
+) -> Result<f64, ParseError> {
     let val = parts.next();
     if val.is_none() {
-        return Err(err!("{}.{}: {} expected to find a real but found nothing", ctxt.filename, ctxt.line, op));
+        return Err(ctxt.error(format!("{} expected to find a real but found nothing", op)));
     }
     let val = val.unwrap();
-    let val = f64::from_str(val);
-    if val.is_err() {
-        return Err(err!(
-            "{}.{}: {} expected to find a real but got {}",
-            ctxt.filename,
-            ctxt.line,
-            op,
-            val.unwrap_err()
+
+    // integer constants are accepted where a real is expected, just like integer literals
+    match consts.get(val) {
+        Some(Code::Real(real)) => return Ok(*real),
+        Some(Code::Int(int)) => return Ok(*int as f64),
+        _ => {}
+    }
+
+    let parsed = f64::from_str(val);
+    if parsed.is_err() && is_identifier(val) {
+        return Err(ctxt.error_at(val, format!("{} expected to find a real but got undefined constant {}", op, val)));
+    }
+    if parsed.is_err() {
+        return Err(ctxt.error_at(val, format!("{} expected to find a real but got {}", op, parsed.unwrap_err())));
+    }
+    Ok(parsed.unwrap())
+}
+
+fn parse_const(parts: &mut Tokens, ctxt: &Ctxt) -> Result<(String, Code), ParseError> {
+    // .const NAME VALUE, where VALUE is either an integer or a real literal
+    let name = parts.next();
+    if name.is_none() || !is_identifier(name.unwrap()) {
+        return Err(ctxt.error(".const expected to find a constant name".to_string()));
+    }
+    let name = name.unwrap();
+
+    let value = parts.next();
+    if value.is_none() {
+        return Err(ctxt.error(format!(".const expected to find a value for {}", name)));
+    }
+    let value = value.unwrap();
+
+    let value = if let Ok(int) = parse_int_literal(value) {
+        Code::Int(int)
+    } else if let Ok(real) = f64::from_str(value) {
+        Code::Real(real)
+    } else {
+        return Err(ctxt.error_at(
+            value,
+            format!(".const expected to find an integer or a real for {} but got {}", name, value),
         ));
+    };
+
+    if let Some(next) = parts.next() {
+        return Err(ctxt.error_at(next, format!(".const expected to find end of line but got {}", next)));
+    }
+
+    Ok((name.to_string(), value))
+}
+
+fn parse_reg_alias(parts: &mut Tokens, ctxt: &Ctxt) -> Result<(String, u8), ParseError> {
+    // .reg NAME rN, after which NAME can be written wherever rN could
+    let name = parts.next();
+    if name.is_none() || !is_identifier(name.unwrap()) {
+        return Err(ctxt.error(".reg expected to find an alias name".to_string()));
+    }
+    let name = name.unwrap();
+    if parse_reg_token(name).is_some() {
+        return Err(ctxt.error_at(name, format!(".reg alias {} would shadow a register", name)));
+    }
+
+    let reg = parts.next();
+    let parsed = reg.and_then(parse_reg_token);
+    if parsed.is_none() {
+        return Err(ctxt.error(format!(
+            ".reg expected to find a register for {} but got {}",
+            name,
+            reg.unwrap_or("nothing")
+        )));
+    }
+
+    if let Some(next) = parts.next() {
+        return Err(ctxt.error_at(next, format!(".reg expected to find end of line but got {}", next)));
+    }
+
+    Ok((name.to_string(), parsed.unwrap()))
+}
+
+fn parse_reg_token(token: &str) -> Option<u8> {
+    token.strip_prefix('r').and_then(|idx| u8::from_str(idx).ok())
+}
+
+fn parse_data_value(token: &str, ctxt: &Ctxt, consts: &HashMap<String, Code>) -> Result<i64, ParseError> {
+    // data values are integer literals or integer constants (including previous data labels)
+    if let Some(Code::Int(int)) = consts.get(token) {
+        return Ok(*int);
+    }
+
+    let val = parse_int_literal(token);
+    if val.is_err() {
+        return Err(ctxt.error_at(token, format!(".data expected to find an integer but got {}", token)));
     }
     Ok(val.unwrap())
 }
 
-fn validate_line_is_over(parts: &mut std::str::SplitWhitespace, op: OpCode, ctxt: &Ctxt) -> Result<(), String> {
-    let next = parts.next();
-    if next.is_some() {
-        Err(err!(
-            "{}.{}: {} expected to find end of line but got {}",
-            ctxt.filename,
-            ctxt.line,
-            op,
-            next.unwrap()
-        ))
+fn parse_reg_value(token: &str, ctxt: &Ctxt, consts: &HashMap<String, Code>) -> Result<i64, ParseError> {
+    // initial register values are integers or reals (stored as their bits, as SETF would), either
+    // as literals or as constants
+    match consts.get(token) {
+        Some(Code::Int(int)) => return Ok(*int),
+        Some(Code::Real(real)) => return Ok(f2i(*real)),
+        _ => {}
+    }
+
+    if let Ok(int) = parse_int_literal(token) {
+        return Ok(int);
+    }
+    if let Ok(real) = token.parse::<f64>() {
+        return Ok(f2i(real));
+    }
+    Err(ctxt.error_at(token, format!(".regs expected to find an integer or a real but got {}", token)))
+}
+
+pub(crate) fn parse_int_literal(token: &str) -> Result<i64, String> {
+    // besides decimal, integers can be written in hexadecimal (0x), binary (0b) and octal (0o),
+    // all of them optionally negative (e.g. -0x10), or as a character literal (e.g. 'A')
+    if token.starts_with('\'') {
+        return parse_char_literal(token);
+    }
+
+    let (sign, unsigned) = match token.strip_prefix('-') {
+        Some(unsigned) => ("-", unsigned),
+        None => ("", token),
+    };
+
+    let (radix, digits) = if let Some(digits) = unsigned.strip_prefix("0x").or(unsigned.strip_prefix("0X")) {
+        (16, digits)
+    } else if let Some(digits) = unsigned.strip_prefix("0b").or(unsigned.strip_prefix("0B")) {
+        (2, digits)
+    } else if let Some(digits) = unsigned.strip_prefix("0o").or(unsigned.strip_prefix("0O")) {
+        (8, digits)
+    } else {
+        return i64::from_str(token).map_err(|e| e.to_string());
+    };
+
+    // from_str_radix accepts a sign on its own, which must not come after the prefix (e.g. 0x-10)
+    if digits.starts_with(['-', '+']) {
+        return Err("invalid digit found in string".to_string());
+    }
+
+    // the sign is kept together with the digits so that i64::MIN can still be written
+    i64::from_str_radix(&format!("{}{}", sign, digits), radix).map_err(|e| e.to_string())
+}
+
+fn parse_char_literal(token: &str) -> Result<i64, String> {
+    // a single character between single quotes, with escapes for \n, \t, \0, \\ and \'
+    let invalid = || Err(format!("invalid character literal {}", token));
+
+    let inner = token.strip_prefix('\'').and_then(|t| t.strip_suffix('\''));
+    if inner.is_none() {
+        return invalid();
+    }
+    let mut chars = inner.unwrap().chars();
+
+    let c = match chars.next() {
+        Some('\\') => match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('0') => '\0',
+            Some('\\') => '\\',
+            Some('\'') => '\'',
+            _ => return invalid(),
+        },
+        Some(c) => c,
+        None => return invalid(),
+    };
+
+    if chars.next().is_some() {
+        return invalid();
+    }
+    Ok(c as i64)
+}
+
+// the tokens of a line, in order
+type Tokens<'a> = std::vec::IntoIter<&'a str>;
+
+fn split_tokens(line: &str) -> Tokens<'_> {
+    // tokens are separated by whitespace, except inside a character literal (i.e. `' '`)
+    let mut tokens = Vec::new();
+    let mut start = None;
+    let mut in_char = false;
+    let mut escaped = false;
+
+    for (idx, c) in line.char_indices() {
+        if c.is_whitespace() && !in_char {
+            if let Some(start) = start.take() {
+                tokens.push(&line[start..idx]);
+            }
+            continue;
+        }
+        start.get_or_insert(idx);
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_char => escaped = true,
+            '\'' => in_char = !in_char,
+            _ => {}
+        }
+    }
+    if let Some(start) = start {
+        tokens.push(&line[start..]);
+    }
+
+    tokens.into_iter()
+}
+
+fn is_identifier(token: &str) -> bool {
+    // identifiers (i.e. constant names) start with a letter or an underscore, which
+    // means they can't be confused with numeric literals
+    token.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && token.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn blank_operand_commas(ctxt: &Ctxt) -> Result<String, ParseError> {
+    // operands can also be separated by commas (i.e. `ADD r0, r1`), which are turned into spaces so
+    // that the columns stay the same. Each comma has to come right after an operand and be followed by
+    // another one, and commas in a character literal (i.e. `','`) or a comment are left alone.
+    let code = ctxt.text.split("//").next().unwrap();
+    let error_at = |column: usize, message: &str| ParseError {
+        column: Some(column),
+        ..ctxt.error(message.to_string())
+    };
+
+    let mut blanked = String::with_capacity(ctxt.text.len());
+    let mut num_tokens = 0;
+    let mut prev: Option<char> = None;
+    // column of the last comma, while no operand came after it yet
+    let mut pending_comma: Option<usize> = None;
+    let mut in_char = false;
+    let mut escaped = false;
+
+    for (idx, c) in code.chars().enumerate() {
+        if !c.is_whitespace() && prev.is_none_or(char::is_whitespace) {
+            num_tokens += 1;
+        }
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_char => escaped = true,
+            '\'' => in_char = !in_char,
+            // a comma stuck to the opcode is left for it to be reported as an unknown one
+            ',' if !in_char && num_tokens > 1 => {
+                if prev.is_none_or(|prev| prev.is_whitespace() || prev == ',') {
+                    return Err(error_at(idx + 1, "Expected to find an operand before ,"));
+                }
+                pending_comma = Some(idx + 1);
+                blanked.push(' ');
+                prev = Some(c);
+                continue;
+            }
+            _ => {}
+        }
+        if !c.is_whitespace() {
+            pending_comma = None;
+        }
+        blanked.push(c);
+        prev = Some(c);
+    }
+
+    if let Some(column) = pending_comma {
+        return Err(error_at(column, "Expected to find an operand after ,"));
+    }
+    blanked.push_str(&ctxt.text[code.len()..]);
+    Ok(blanked)
+}
+
+fn validate_line_is_over(parts: &mut Tokens, op: OpCode, ctxt: &Ctxt) -> Result<(), ParseError> {
+    if let Some(next) = parts.next() {
+        Err(ctxt.error_at(next, format!("{} expected to find end of line but got {}", op, next)))
     } else {
         Ok(())
     }
@@ -348,7 +1515,7 @@ mod tests {
         let raw_code = "HALT".to_string();
         let code = parse_string(&raw_code, dummy_ctxt());
         assert!(code.is_ok());
-        assert_eq!(code.unwrap(), vec![Code::Op(OpCode::HALT)]);
+        assert_eq!(code.unwrap().code, vec![Code::Op(OpCode::HALT)]);
     }
 
     #[test]
@@ -370,7 +1537,7 @@ mod tests {
         ];
 
         assert!(code.is_ok());
-        assert_eq!(code.unwrap(), expected_code);
+        assert_eq!(code.unwrap().code, expected_code);
     }
 
     #[test]
@@ -378,15 +1545,15 @@ mod tests {
         let raw_code = "HALT\nHALT\nSET 2 r-2".to_string();
         let code = parse_string(&raw_code, Ctxt::new("fff".to_string()));
         assert!(code.is_err());
-        assert!(code.unwrap_err().contains("fff.3"));
+        assert!(code.unwrap_err().to_string().contains("fff.3"));
     }
 
     #[test]
     fn shows_opcode_on_reg_error() {
         let raw_code = "SET 0 0";
-        let code = parse_string(&raw_code.to_string(), dummy_ctxt());
+        let code = parse_string(raw_code, dummy_ctxt());
         assert!(code.is_err());
-        assert!(code.unwrap_err().contains("SET"));
+        assert!(code.unwrap_err().to_string().contains("SET"));
     }
 
     #[test]
@@ -394,7 +1561,10 @@ mod tests {
         let raw_code = "SET r2 r0".to_string();
         let code = parse_string(&raw_code, dummy_ctxt());
         assert!(code.is_err());
-        assert!(code.unwrap_err().contains("expected to find an integer but"));
+        assert!(code
+            .unwrap_err()
+            .to_string()
+            .contains("expected to find an integer but"));
     }
 
     #[test]
@@ -402,7 +1572,10 @@ mod tests {
         let raw_code = "SET 2.0 r0".to_string();
         let code = parse_string(&raw_code, dummy_ctxt());
         assert!(code.is_err());
-        assert!(code.unwrap_err().contains("expected to find an integer but"));
+        assert!(code
+            .unwrap_err()
+            .to_string()
+            .contains("expected to find an integer but"));
     }
 
     #[test]
@@ -410,7 +1583,10 @@ mod tests {
         let raw_code = "SET 2 0".to_string();
         let code = parse_string(&raw_code, dummy_ctxt());
         assert!(code.is_err());
-        assert!(code.unwrap_err().contains("expected to find a register but"));
+        assert!(code
+            .unwrap_err()
+            .to_string()
+            .contains("expected to find a register but"));
     }
 
     #[test]
@@ -418,7 +1594,10 @@ mod tests {
         let raw_code = "SET 2 r".to_string();
         let code = parse_string(&raw_code, dummy_ctxt());
         assert!(code.is_err());
-        assert!(code.unwrap_err().contains("expected to find a register but got"));
+        assert!(code
+            .unwrap_err()
+            .to_string()
+            .contains("expected to find a register but got"));
     }
 
     #[test]
@@ -426,7 +1605,10 @@ mod tests {
         let raw_code = "SET 2 r2.0".to_string();
         let code = parse_string(&raw_code, dummy_ctxt());
         assert!(code.is_err());
-        assert!(code.unwrap_err().contains("expected to find a register but got"));
+        assert!(code
+            .unwrap_err()
+            .to_string()
+            .contains("expected to find a register but got"));
     }
 
     #[test]
@@ -434,15 +1616,18 @@ mod tests {
         let raw_code = "SET 2 r-2".to_string();
         let code = parse_string(&raw_code, dummy_ctxt());
         assert!(code.is_err());
-        assert!(code.unwrap_err().contains("expected to find a register but got"));
+        assert!(code
+            .unwrap_err()
+            .to_string()
+            .contains("expected to find a register but got"));
     }
 
     #[test]
     fn fails_on_unexpected_opcode() {
         let raw_code = "crymeariver";
-        let code = parse_string(&raw_code.to_string(), dummy_ctxt());
+        let code = parse_string(raw_code, dummy_ctxt());
         assert!(code.is_err());
-        assert!(code.unwrap_err().contains("Expected to find an OpCode but"));
+        assert!(code.unwrap_err().to_string().contains("Expected to find an OpCode but"));
     }
 
     #[test]
@@ -450,6 +1635,689 @@ mod tests {
         let raw_code = "HALT 2".to_string();
         let code = parse_string(&raw_code, dummy_ctxt());
         assert!(code.is_err());
-        assert!(code.unwrap_err().contains("expected to find end of line but"));
+        assert!(code
+            .unwrap_err()
+            .to_string()
+            .contains("expected to find end of line but"));
+    }
+
+    #[test]
+    fn test_parsing_consts() {
+        #[rustfmt::skip]
+        let raw_code = "
+            .const WIDTH 80
+            .const RATIO 0.5
+            SET WIDTH r0
+            SETF RATIO r1
+            SETF WIDTH r2
+            HALT";
+        let code = parse_string(raw_code, dummy_ctxt());
+
+        #[rustfmt::skip]
+        let expected_code = vec![
+            Code::Op(OpCode::SET), Code::Int(80), Code::Reg(0),
+            Code::Op(OpCode::SETF), Code::Real(0.5), Code::Reg(1),
+            Code::Op(OpCode::SETF), Code::Real(80.0), Code::Reg(2),
+            Code::Op(OpCode::HALT),
+        ];
+
+        assert!(code.is_ok());
+        assert_eq!(code.unwrap().code, expected_code);
+    }
+
+    #[test]
+    fn fails_on_const_redefinition() {
+        let raw_code = ".const WIDTH 80\n.const WIDTH 81";
+        let code = parse_string(raw_code, Ctxt::new("fff".to_string()));
+        assert!(code.is_err());
+        let err = code.unwrap_err().to_string();
+        assert!(err.contains("fff.2"));
+        assert!(err.contains("Constant WIDTH already defined"));
+    }
+
+    #[test]
+    fn fails_on_undefined_const() {
+        let raw_code = ".const WIDTH 80\nSET HEIGHT r0";
+        let code = parse_string(raw_code, Ctxt::new("fff".to_string()));
+        assert!(code.is_err());
+        let err = code.unwrap_err().to_string();
+        assert!(err.contains("fff.2"));
+        assert!(err.contains("undefined constant HEIGHT"));
+    }
+
+    #[test]
+    fn fails_on_real_const_as_integer() {
+        let raw_code = ".const RATIO 0.5\nSET RATIO r0";
+        let code = parse_string(raw_code, dummy_ctxt());
+        assert!(code.is_err());
+        assert!(code.unwrap_err().to_string().contains("constant RATIO is a real"));
+    }
+
+    #[test]
+    fn test_parsing_radix_literals() {
+        #[rustfmt::skip]
+        let raw_code = "
+            SET 0xFF r0
+            SET 0b1111 r1
+            SET -0x10 r2
+            SET 0o17 r3
+            SET -0x8000000000000000 r4";
+        let code = parse_string(raw_code, dummy_ctxt());
+
+        #[rustfmt::skip]
+        let expected_code = vec![
+            Code::Op(OpCode::SET), Code::Int(255), Code::Reg(0),
+            Code::Op(OpCode::SET), Code::Int(15), Code::Reg(1),
+            Code::Op(OpCode::SET), Code::Int(-16), Code::Reg(2),
+            Code::Op(OpCode::SET), Code::Int(15), Code::Reg(3),
+            Code::Op(OpCode::SET), Code::Int(i64::MIN), Code::Reg(4),
+        ];
+
+        assert!(code.is_ok());
+        assert_eq!(code.unwrap().code, expected_code);
+    }
+
+    #[test]
+    fn fails_on_malformed_radix_literal() {
+        for raw_code in ["SET 0xZZ r0", "SET 0b102 r0", "SET 0x r0", "SET 0x-10 r0"] {
+            let code = parse_string(raw_code, Ctxt::new("fff".to_string()));
+            assert!(code.is_err());
+            let err = code.unwrap_err().to_string();
+            assert!(err.contains("fff.1"));
+            assert!(err.contains("SET expected to find an integer but got"));
+        }
+    }
+
+    #[test]
+    fn test_parsing_char_literals() {
+        #[rustfmt::skip]
+        let raw_code = r"
+            SET 'A' r0
+            SET '\n' r1
+            SET '\\' r2
+            SET '\'' r3
+            SET ' ' r4";
+        let code = parse_string(raw_code, dummy_ctxt());
+
+        #[rustfmt::skip]
+        let expected_code = vec![
+            Code::Op(OpCode::SET), Code::Int(65), Code::Reg(0),
+            Code::Op(OpCode::SET), Code::Int(10), Code::Reg(1),
+            Code::Op(OpCode::SET), Code::Int(92), Code::Reg(2),
+            Code::Op(OpCode::SET), Code::Int(39), Code::Reg(3),
+            Code::Op(OpCode::SET), Code::Int(32), Code::Reg(4),
+        ];
+
+        assert!(code.is_ok());
+        assert_eq!(code.unwrap().code, expected_code);
+    }
+
+    #[test]
+    fn fails_on_malformed_char_literal() {
+        for raw_code in ["SET 'ab' r0", "SET 'A r0", "SET '' r0", r"SET '\q' r0"] {
+            let code = parse_string(raw_code, Ctxt::new("fff".to_string()));
+            assert!(code.is_err());
+            let err = code.unwrap_err().to_string();
+            assert!(err.contains("fff.1"));
+            assert!(err.contains("invalid character literal"));
+        }
+    }
+
+    #[test]
+    fn test_parsing_data_section() {
+        #[rustfmt::skip]
+        let raw_code = "
+            .data
+            first: 1 2 3
+                   4
+            second: 'A' first
+            .text
+            SET second r0
+            HALT";
+        let program = parse_string(raw_code, dummy_ctxt());
+
+        #[rustfmt::skip]
+        let expected_code = vec![
+            Code::Op(OpCode::SET), Code::Int(4), Code::Reg(0),
+            Code::Op(OpCode::HALT),
+        ];
+
+        assert!(program.is_ok());
+        let program = program.unwrap();
+        assert_eq!(program.code, expected_code);
+        assert_eq!(program.data, [1, 2, 3, 4, 65, 0]);
+    }
+
+    #[test]
+    fn fails_on_invalid_data() {
+        for raw_code in [".data\nxs: 1 two", ".data\nxs: 1\nxs: 2", ".data\n.const xs 1\nxs: 2"] {
+            let program = parse_string(raw_code, Ctxt::new("fff".to_string()));
+            assert!(program.is_err());
+            assert!(program.unwrap_err().to_string().contains("fff."));
+        }
+
+        // parse_source only returns the code, so it refuses to silently drop the data
+        assert!(parse_source(".data\nxs: 1\n.text\nHALT").is_err());
+    }
+
+    #[test]
+    fn fails_on_malformed_include() {
+        for raw_code in [
+            "HALT\n.include helper.uvm",
+            "HALT\n.include",
+            "HALT\n.include \"missing.uvm\"",
+        ] {
+            let code = parse_string(raw_code, Ctxt::new("fff".to_string()));
+            assert!(code.is_err());
+            assert!(code.unwrap_err().to_string().contains("fff.2"));
+        }
+    }
+
+    #[test]
+    fn test_parsing_macros() {
+        // macros can be used before being defined
+        #[rustfmt::skip]
+        let raw_code = "
+            SWAP r0 r1
+            SWAP r2 r3
+
+            .macro SWAP a b
+                PUSH a  // comments are fine inside macros
+                MOV b a
+            .endmacro";
+        let code = parse_string(raw_code, dummy_ctxt());
+
+        #[rustfmt::skip]
+        let expected_code = vec![
+            Code::Op(OpCode::PUSH), Code::Reg(0),
+            Code::Op(OpCode::MOV), Code::Reg(1), Code::Reg(0),
+            Code::Op(OpCode::PUSH), Code::Reg(2),
+            Code::Op(OpCode::MOV), Code::Reg(3), Code::Reg(2),
+        ];
+
+        assert!(code.is_ok());
+        assert_eq!(code.unwrap().code, expected_code);
+
+        // commas work in both the body and the invocation
+        let code = parse_string(".macro MV a, b\nMOV a, b\n.endmacro\nMV r0, r1", dummy_ctxt());
+        #[rustfmt::skip]
+        assert_eq!(code.unwrap().code, vec![
+            Code::Op(OpCode::MOV), Code::Reg(0), Code::Reg(1),
+        ]);
+    }
+
+    #[test]
+    fn fails_on_macro_arity_mismatch() {
+        let raw_code = ".macro TWICE x\nINC x\nINC x\n.endmacro\nTWICE r0 r1";
+        let code = parse_string(raw_code, Ctxt::new("fff".to_string()));
+        assert!(code.is_err());
+        assert!(code
+            .unwrap_err()
+            .to_string()
+            .contains("fff.5: Macro TWICE expects 1 arguments but got 2"));
+    }
+
+    #[test]
+    fn fails_on_recursive_macro() {
+        let raw_code = ".macro LOOP\nLOOP\n.endmacro\nHALT\nLOOP";
+        let code = parse_string(raw_code, Ctxt::new("fff".to_string()));
+        assert!(code.is_err());
+        assert!(code
+            .unwrap_err()
+            .to_string()
+            .contains("fff.5: Expanding macro LOOP exceeded the maximum nesting depth"));
+    }
+
+    #[test]
+    fn test_parsing_int_int_reg() {
+        let code = parse_string(".const MAX 0xFF\nENSURE -1 MAX r2", dummy_ctxt());
+
+        #[rustfmt::skip]
+        let expected_code = vec![
+            Code::Op(OpCode::ENSURE), Code::Int(-1), Code::Int(255), Code::Reg(2),
+        ];
+
+        assert!(code.is_ok());
+        assert_eq!(code.unwrap().code, expected_code);
+    }
+
+    #[test]
+    fn fails_with_hint_on_real_instead_of_int() {
+        let code = parse_string("SET 3.0 r0", Ctxt::new("fff".to_string()));
+        assert!(code.is_err());
+        assert!(code.unwrap_err().to_string().contains(
+            "fff.1:5: SET expected to find an integer but got 3.0 (SET takes an integer; for a float use SETF)"
+        ));
+
+        let code = parse_string("PUSHL 1.5", Ctxt::new("fff".to_string()));
+        assert!(code.is_err());
+        assert!(code
+            .unwrap_err()
+            .to_string()
+            .ends_with("fff.1:7: PUSHL expected to find an integer but got invalid digit found in string"));
+    }
+
+    #[test]
+    fn test_parsing_conditionals() {
+        let parse_with = |defs: &str| {
+            let raw_code = format!(
+                "{}
+                .if DEBUG
+                    SET 1 r0
+                    .if VERBOSE
+                        SET 2 r0
+                    .endif
+                .else
+                    SET 3 r0
+                .endif
+                HALT",
+                defs
+            );
+            parse_string(&raw_code, dummy_ctxt()).unwrap().code
+        };
+
+        #[rustfmt::skip]
+        assert_eq!(parse_with(".def DEBUG"), vec![
+            Code::Op(OpCode::SET), Code::Int(1), Code::Reg(0),
+            Code::Op(OpCode::HALT),
+        ]);
+        #[rustfmt::skip]
+        assert_eq!(parse_with(".def DEBUG\n.def VERBOSE"), vec![
+            Code::Op(OpCode::SET), Code::Int(1), Code::Reg(0),
+            Code::Op(OpCode::SET), Code::Int(2), Code::Reg(0),
+            Code::Op(OpCode::HALT),
+        ]);
+        // VERBOSE alone doesn't matter, since its block is inside DEBUG's
+        #[rustfmt::skip]
+        assert_eq!(parse_with(".def VERBOSE"), vec![
+            Code::Op(OpCode::SET), Code::Int(3), Code::Reg(0),
+            Code::Op(OpCode::HALT),
+        ]);
+
+        // definitions in a block that is left out don't count
+        let code = parse_string(".if DEBUG\n.def VERBOSE\n.endif\n.if VERBOSE\nSET 1 r0\n.endif\nHALT", dummy_ctxt());
+        assert_eq!(code.unwrap().code, vec![Code::Op(OpCode::HALT)]);
+
+        // neither are includes, which aren't even opened
+        let raw_code = ".if DEBUG\n.include \"nope.uvm\"\n.endif\nHALT";
+        assert_eq!(parse_string(raw_code, dummy_ctxt()).unwrap().code, vec![Code::Op(OpCode::HALT)]);
+        let code = parse_string(&format!(".def DEBUG\n{}", raw_code), dummy_ctxt());
+        assert!(code.unwrap_err().to_string().contains("3: Failed to include nope.uvm"));
+    }
+
+    #[test]
+    fn fails_on_unbalanced_conditionals() {
+        for (raw_code, message) in [
+            (".if DEBUG\nHALT", "1: .if is missing its .endif"),
+            ("HALT\n.endif", "2: .endif found outside of an .if"),
+            (".else", "1: .else found outside of an .if"),
+            (".if DEBUG\n.else\n.else\n.endif", "3: .if at test.1 already has an .else"),
+            (".if\n.endif", "1: .if expected to find a symbol name"),
+            (".def DEBUG 1", "1: .def expected to find end of line but got 1"),
+        ] {
+            let code = parse_string(raw_code, dummy_ctxt());
+            assert!(code.is_err());
+            assert!(code.unwrap_err().to_string().contains(message), "{}", raw_code);
+        }
+    }
+
+    #[test]
+    fn test_parsing_xchg() {
+        let code = parse_string("XCHG r3 r12", dummy_ctxt());
+
+        #[rustfmt::skip]
+        let expected_code = vec![
+            Code::Op(OpCode::XCHG), Code::Reg(3), Code::Reg(12),
+        ];
+
+        assert!(code.is_ok());
+        assert_eq!(code.unwrap().code, expected_code);
+    }
+
+    #[test]
+    fn test_parsing_reg_reg_int() {
+        let code = parse_string("DOT r0 r8 3", dummy_ctxt());
+
+        #[rustfmt::skip]
+        let expected_code = vec![
+            Code::Op(OpCode::DOT), Code::Reg(0), Code::Reg(8), Code::Int(3),
+        ];
+
+        assert!(code.is_ok());
+        assert_eq!(code.unwrap().code, expected_code);
+    }
+
+    #[test]
+    fn test_parsing_reg_reg_reg() {
+        let code = parse_string(".reg key r7\nBSEARCH r0 r1 key", dummy_ctxt());
+
+        #[rustfmt::skip]
+        let expected_code = vec![
+            Code::Op(OpCode::BSEARCH), Code::Reg(0), Code::Reg(1), Code::Reg(7),
+        ];
+
+        assert!(code.is_ok());
+        assert_eq!(code.unwrap().code, expected_code);
+    }
+
+    #[test]
+    fn test_parsing_initial_registers() {
+        let program = parse_string(".const RATIO 0.5\n.regs 1 -0x10 'A' 1.5 RATIO\nHALT", dummy_ctxt()).unwrap();
+        assert_eq!(program.registers, [1, -16, 65, f2i(1.5), f2i(0.5)]);
+        assert_eq!(program.code, [Code::Op(OpCode::HALT)]);
+
+        for (raw_code, message) in [
+            (".regs 1\n.regs 2", "test.2: Initial registers already set by .regs"),
+            (".regs 1 r2", "test.1:9: .regs expected to find an integer or a real but got r2"),
+        ] {
+            let program = parse_string(raw_code, dummy_ctxt());
+            assert!(program.is_err());
+            assert!(program.unwrap_err().to_string().contains(message), "{}", raw_code);
+        }
+        assert!(parse_source(".regs 1\nHALT").is_err());
+    }
+
+    #[test]
+    fn test_parsing_hamming() {
+        let code = parse_string("HAMMING r2 r7", dummy_ctxt());
+
+        #[rustfmt::skip]
+        let expected_code = vec![
+            Code::Op(OpCode::HAMMING), Code::Reg(2), Code::Reg(7),
+        ];
+
+        assert!(code.is_ok());
+        assert_eq!(code.unwrap().code, expected_code);
+    }
+
+    #[test]
+    fn test_structured_errors() {
+        let code = parse_string("HALT\n\nSET r0 r1\n", Ctxt::new("fff".to_string()));
+        let err = code.unwrap_err();
+        assert_eq!(err.filename, "fff");
+        assert_eq!(err.line, Some(3));
+        assert_eq!(err.column, Some(5));
+        assert_eq!(err.message, "SET expected to find an integer but got undefined constant r0");
+        assert_eq!(
+            err.to_json(),
+            r#"{"filename": "fff", "line": 3, "column": 5, "message": "SET expected to find an integer but got undefined constant r0"}"#
+        );
+
+        let err = ParseError {
+            filename: "a \"b\"\\c".to_string(),
+            line: None,
+            column: None,
+            message: "x\ny".to_string(),
+        };
+        assert_eq!(
+            err.to_json(),
+            r#"{"filename": "a \"b\"\\c", "line": null, "column": null, "message": "x\ny"}"#
+        );
+        assert_eq!(err.to_string(), "a \"b\"\\c: x\ny");
+    }
+
+    #[test]
+    fn test_errors_report_the_column() {
+        let error_at = |raw_code: &str| {
+            parse_string(raw_code, Ctxt::new("fff".to_string()))
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert!(error_at("HALT\n  ADD r0 x1").starts_with("fff.2:10: ADD expected to find a register but got x1"));
+        assert!(error_at("HALT\n\tADD r0 r999").starts_with("fff.2:9: ADD expected to find a register but got"));
+        assert!(error_at("SETF 1.5 r0\nSET 1.5 r0").starts_with("fff.2:5: SET expected to find an integer"));
+        assert!(error_at("SETF abc r0").starts_with("fff.1:6: SETF expected to find a real"));
+        assert!(error_at("INC r0 r1 // r1").starts_with("fff.1:8: INC expected to find end of line but got r1"));
+        assert!(error_at("  FOO r0").starts_with("fff.1:3: Expected to find an OpCode but found FOO"));
+        assert!(error_at(".const A 1 2").starts_with("fff.1:12: .const expected to find end of line"));
+        // columns count characters, not bytes
+        assert!(error_at("SET 'é' x").starts_with("fff.1:9: SET expected to find a register but got x"));
+
+        // errors that aren't about a specific token don't have one
+        assert!(error_at("ADD r0").starts_with("fff.1: ADD expected to find a register but found nothing"));
+    }
+
+    #[test]
+    fn test_parsing_opcodes_in_any_case() {
+        let upper = "SET 10 r0\nloop:\nDEC r0\nCMPL 0 r0\nJNE loop\nHALT";
+        let lower = "set 10 r0\nloop:\ndec r0\ncmpl 0 r0\njne loop\nhalt";
+        let mixed = "Set 10 r0\nloop:\nDeC r0\ncmpL 0 r0\nJne loop\nhAlT";
+
+        let expected_code = parse_string(upper, dummy_ctxt()).unwrap().code;
+        assert_eq!(parse_string(lower, dummy_ctxt()).unwrap().code, expected_code);
+        assert_eq!(parse_string(mixed, dummy_ctxt()).unwrap().code, expected_code);
+
+        // only the opcode is case-insensitive
+        assert!(parse_string("set 10 R0", dummy_ctxt()).is_err());
+        assert!(parse_string("LOOP:\njmp loop", dummy_ctxt()).is_err());
+        assert!(parse_string(".macro halt\nNOP\n.endmacro", dummy_ctxt())
+            .unwrap_err()
+            .to_string()
+            .contains("Macro halt would shadow an opcode"));
+    }
+
+    #[test]
+    fn test_parsing_statements_separated_by_semicolons() {
+        let code = parse_string("SET 1 r0; INC r0; HALT", dummy_ctxt());
+
+        #[rustfmt::skip]
+        let expected_code = vec![
+            Code::Op(OpCode::SET), Code::Int(1), Code::Reg(0),
+            Code::Op(OpCode::INC), Code::Reg(0),
+            Code::Op(OpCode::HALT),
+        ];
+        assert_eq!(code.unwrap().code, expected_code);
+
+        // labels work as statements of their own, and empty ones are skipped
+        let code = parse_string("SET 1 r0; loop:; INC r0;; JMP loop; // a comment; with a semicolon", dummy_ctxt());
+
+        #[rustfmt::skip]
+        let expected_code = vec![
+            Code::Op(OpCode::SET), Code::Int(1), Code::Reg(0),
+            Code::Op(OpCode::INC), Code::Reg(0),
+            Code::Op(OpCode::JMP), Code::Addr(3),
+        ];
+        assert_eq!(code.unwrap().code, expected_code);
+
+        // a semicolon character literal isn't a separator
+        let code = parse_string("SET ';' r0; SET '\\'' r1", dummy_ctxt());
+        assert_eq!(
+            code.unwrap().code[..6],
+            [
+                Code::Op(OpCode::SET),
+                Code::Int(59),
+                Code::Reg(0),
+                Code::Op(OpCode::SET),
+                Code::Int(39),
+                Code::Reg(1)
+            ]
+        );
+
+        // errors point to the line (and column) of the statement they're in
+        let code = parse_string("HALT\nSET 1 r0; INC x0; HALT", dummy_ctxt());
+        assert!(code
+            .unwrap_err()
+            .to_string()
+            .starts_with("test.2:15: INC expected to find a register but got x0"));
+    }
+
+    #[test]
+    fn test_parsing_org() {
+        let program = parse_string("JMP start\n.org 16\nstart:\nHALT", dummy_ctxt()).unwrap();
+
+        let mut expected_code = vec![Code::Op(OpCode::JMP), Code::Addr(16)];
+        expected_code.resize(16, Code::Op(OpCode::NOP));
+        expected_code.push(Code::Op(OpCode::HALT));
+        assert_eq!(program.code, expected_code);
+        assert!(program.symbols.labels.contains(&("start".to_string(), 16)));
+
+        // .org to where the code already is changes nothing, and constants can be used
+        let program = parse_string(".const BASE 3\nSET 1 r0\n.org 3\n.org BASE\nHALT", dummy_ctxt()).unwrap();
+        assert_eq!(program.code.len(), 4);
+
+        for (raw_code, message) in [
+            (
+                "SET 1 r0\n.org 2",
+                "test.2:6: .org 2 would move back past code already placed up to address 2",
+            ),
+            (".org", "test.1: .org expected to find an address but found nothing"),
+            (".org -1", "test.1:6: .org expected to find an address but got -1"),
+            (".org 4 5", "test.1:8: .org expected to find end of line but got 5"),
+        ] {
+            let program = parse_string(raw_code, dummy_ctxt());
+            assert!(program.unwrap_err().to_string().starts_with(message), "{}", raw_code);
+        }
+    }
+
+    #[test]
+    fn test_parsing_cmpf_and_jun() {
+        let code = parse_string("nan:\nCMPF r0 r1\nJUN nan", dummy_ctxt());
+
+        #[rustfmt::skip]
+        let expected_code = vec![
+            Code::Op(OpCode::CMPF), Code::Reg(0), Code::Reg(1),
+            Code::Op(OpCode::JUN), Code::Addr(0),
+        ];
+
+        assert!(code.is_ok());
+        assert_eq!(code.unwrap().code, expected_code);
+    }
+
+    #[test]
+    fn test_block_comments() {
+        let code = parse_string(
+            "SET 1 r0\n/* everything here\nHALT\nis left out */ SET 2 r1 /* and this */\nSET '/' r2 // /* not one\nHALT",
+            dummy_ctxt(),
+        );
+
+        #[rustfmt::skip]
+        let expected_code = vec![
+            Code::Op(OpCode::SET), Code::Int(1), Code::Reg(0),
+            Code::Op(OpCode::SET), Code::Int(2), Code::Reg(1),
+            Code::Op(OpCode::SET), Code::Int('/' as i64), Code::Reg(2),
+            Code::Op(OpCode::HALT),
+        ];
+
+        assert_eq!(code.unwrap().code, expected_code);
+
+        // lines and columns after a block comment still point to the right place
+        let err = parse_string("/*\n*/\nSET /* x */ r0 r1", dummy_ctxt()).unwrap_err();
+        assert_eq!((err.line, err.column), (Some(3), Some(13)));
+    }
+
+    #[test]
+    fn test_broken_block_comments() {
+        let err = parse_string("HALT\n  /* never\nclosed", dummy_ctxt()).unwrap_err();
+        assert_eq!(err.to_string(), "test.2:3: Block comment is never closed");
+
+        let err = parse_string("/* a /* b */ */", dummy_ctxt()).unwrap_err();
+        assert_eq!(err.to_string(), "test.1:6: Block comments can't be nested");
+    }
+
+    #[test]
+    fn test_comma_separated_operands() {
+        let with_commas = parse_string("SET 2, r0\nADD r0,r1\nSETF 1.5, r2 // a, comment\nSET ',', r3", dummy_ctxt());
+        let without_commas = parse_string("SET 2 r0\nADD r0 r1\nSETF 1.5 r2\nSET ',' r3", dummy_ctxt());
+        assert_eq!(with_commas.unwrap().code, without_commas.unwrap().code);
+
+        // columns of what comes after a comma aren't affected
+        let err = parse_string("ADD r0, x1", dummy_ctxt()).unwrap_err();
+        assert_eq!((err.line, err.column), (Some(1), Some(9)));
+    }
+
+    #[test]
+    fn test_stray_commas() {
+        let err = parse_string("SET 2, , r0", dummy_ctxt()).unwrap_err();
+        assert_eq!(err.to_string(), "test.1:8: Expected to find an operand before ,");
+
+        let err = parse_string("ADD , r0 r1", dummy_ctxt()).unwrap_err();
+        assert_eq!(err.to_string(), "test.1:5: Expected to find an operand before ,");
+
+        let err = parse_string("ADD r0, r1,", dummy_ctxt()).unwrap_err();
+        assert_eq!(err.to_string(), "test.1:11: Expected to find an operand after ,");
+
+        let err = parse_string("ADD, r0 r1", dummy_ctxt()).unwrap_err();
+        assert_eq!(err.to_string(), "test.1:1: Expected to find an OpCode but found ADD,");
+    }
+
+    #[test]
+    fn test_parsing_clr() {
+        let code = parse_string("CLR r3\nCLRA", dummy_ctxt());
+
+        #[rustfmt::skip]
+        let expected_code = vec![
+            Code::Op(OpCode::CLR), Code::Reg(3),
+            Code::Op(OpCode::CLRA),
+        ];
+
+        assert!(code.is_ok());
+        assert_eq!(code.unwrap().code, expected_code);
+        assert!(parse_string("CLRA r0", dummy_ctxt()).is_err());
+    }
+
+    #[test]
+    fn test_parsing_bit_counts() {
+        let code = parse_string("POPCNT r1\nCLZ r2\nCTZ r3", dummy_ctxt());
+
+        #[rustfmt::skip]
+        let expected_code = vec![
+            Code::Op(OpCode::POPCNT), Code::Reg(1),
+            Code::Op(OpCode::CLZ), Code::Reg(2),
+            Code::Op(OpCode::CTZ), Code::Reg(3),
+        ];
+
+        assert!(code.is_ok());
+        assert_eq!(code.unwrap().code, expected_code);
+    }
+
+    #[test]
+    fn test_parsing_spget_and_cspget() {
+        let code = parse_string("SPGET r1\nCSPGET r2", dummy_ctxt());
+
+        #[rustfmt::skip]
+        let expected_code = vec![
+            Code::Op(OpCode::SPGET), Code::Reg(1),
+            Code::Op(OpCode::CSPGET), Code::Reg(2),
+        ];
+
+        assert!(code.is_ok());
+        assert_eq!(code.unwrap().code, expected_code);
+    }
+
+    #[test]
+    fn test_parsing_drop() {
+        let code = parse_string("DROP 3", dummy_ctxt());
+        assert!(code.is_ok());
+        assert_eq!(code.unwrap().code, vec![Code::Op(OpCode::DROP), Code::Int(3)]);
+        assert!(parse_string("DROP r0", dummy_ctxt()).is_err());
+    }
+
+    #[test]
+    fn test_parsing_dbgstack() {
+        let code = parse_string("DBGSTACK\nDBGSTACKN 4", dummy_ctxt());
+
+        #[rustfmt::skip]
+        let expected_code = vec![
+            Code::Op(OpCode::DBGSTACK),
+            Code::Op(OpCode::DBGSTACKN), Code::Int(4),
+        ];
+
+        assert!(code.is_ok());
+        assert_eq!(code.unwrap().code, expected_code);
+    }
+
+    #[test]
+    fn test_parsing_cmov() {
+        let code = parse_string("CMOVEQ r0 r1\ncmovge r2 r3", dummy_ctxt());
+
+        #[rustfmt::skip]
+        let expected_code = vec![
+            Code::Op(OpCode::CMOVEQ), Code::Reg(0), Code::Reg(1),
+            Code::Op(OpCode::CMOVGE), Code::Reg(2), Code::Reg(3),
+        ];
+
+        assert!(code.is_ok());
+        assert_eq!(code.unwrap().code, expected_code);
     }
 }
@@ -1,22 +1,107 @@
-use std::io::Write;
+use std::{collections::HashMap, io::Write};
 
 use crate::{
-    asm::{Code, OpArgT, OpCode, OP_ARG_TYPES},
-    parser::parse_file,
+    asm::{check_operands, displayable_code, Code, DebugSymbols, OpArgT, OpCode, Program, OP_ARG_TYPES},
+    parser::parse_program,
 };
 
 const UVM_SIGNATURE_LEN: usize = 15;
 const UVM_BINARY_SIGNATURE: [u8; UVM_SIGNATURE_LEN] = [
     0x56, 0x69, 0x63, 0x74, 0x68, 0x6f, 0x72, 0x20, 0x69, 0x73, 0x20, 0x43, 0x30, 0x30, 0x4c,
 ];
-const UVM_BINARY_VERSION: u8 = 0x01;
+const UVM_BINARY_VERSION: u8 = 0x05;
+const UVM_CHECKSUM_LEN: usize = 4;
 
-pub fn serialize(code: &Vec<Code>) -> Result<Vec<u8>, String> {
+pub fn serialize(code: &[Code]) -> Result<Vec<u8>, String> {
+    serialize_with_symbols(code, None)
+}
+
+pub fn serialize_with_symbols(code: &[Code], symbols: Option<&DebugSymbols>) -> Result<Vec<u8>, String> {
+    serialize_parts(&[], code, symbols)
+}
+
+/// Serializes a whole program: its initial register values, its code and, if `with_symbols` is set,
+/// its debug symbols. The data section can't be serialized yet, so programs with one are rejected.
+pub fn serialize_program(program: &Program, with_symbols: bool) -> Result<Vec<u8>, String> {
+    if !program.data.is_empty() {
+        return Err("Binaries cannot hold a .data section yet".to_string());
+    }
+    let symbols = if with_symbols { Some(&program.symbols) } else { None };
+    serialize_parts(&program.registers, &program.code, symbols)
+}
+
+fn serialize_parts(registers: &[i64], code: &[Code], symbols: Option<&DebugSymbols>) -> Result<Vec<u8>, String> {
     // start with randomly generated signature of 8 bytes PLUS a byte indicating the current version
     // so that when reading binaries we can check if they they are actually compatible uvm binaries
     let mut binary = Vec::from(UVM_BINARY_SIGNATURE);
     binary.push(UVM_BINARY_VERSION);
 
+    // then a CRC32 of everything that follows it, so corrupted binaries are caught before parsing
+    let payload = {
+        let payload = serialize_payload(registers, code, symbols);
+        if payload.is_err() {
+            return Err(payload.unwrap_err());
+        }
+        payload.unwrap()
+    };
+    binary.extend(crc32(&payload).to_le_bytes());
+    binary.extend(payload);
+
+    Ok(binary)
+}
+
+fn serialize_payload(registers: &[i64], code: &[Code], symbols: Option<&DebugSymbols>) -> Result<Vec<u8>, String> {
+    let mut binary = Vec::new();
+
+    // the initial values of the first registers (as set by `.regs`), prefixed by how many there are
+    binary.extend((registers.len() as u64).to_le_bytes());
+    for val in registers {
+        binary.extend(val.to_le_bytes());
+    }
+
+    // the code is prefixed by its length in bytes, so we know where the debug section starts
+    let encoded_code = encode_code(code);
+    if encoded_code.is_err() {
+        return Err(encoded_code.unwrap_err());
+    }
+    let encoded_code = encoded_code.unwrap();
+    binary.extend((encoded_code.len() as u64).to_le_bytes());
+    binary.extend(encoded_code);
+
+    // the debug section is optional, and is there if anything follows the code:
+    // number of labels, then each label's name length, name and address
+    // number of lines, then each instruction's address and source line
+    // number of register aliases, then each alias' name length, name and register
+    if let Some(symbols) = symbols {
+        binary.extend((symbols.labels.len() as u64).to_le_bytes());
+        for (label, addr) in &symbols.labels {
+            binary.extend((label.len() as u64).to_le_bytes());
+            binary.extend(label.as_bytes());
+            binary.extend((*addr as u64).to_le_bytes());
+        }
+        binary.extend((symbols.lines.len() as u64).to_le_bytes());
+        for (addr, line) in &symbols.lines {
+            binary.extend((*addr as u64).to_le_bytes());
+            binary.extend((*line as u64).to_le_bytes());
+        }
+        binary.extend((symbols.registers.len() as u64).to_le_bytes());
+        for (name, reg) in &symbols.registers {
+            binary.extend((name.len() as u64).to_le_bytes());
+            binary.extend(name.as_bytes());
+            binary.extend(reg.to_le_bytes());
+        }
+    }
+
+    Ok(binary)
+}
+
+fn encode_code(code: &[Code]) -> Result<Vec<u8>, String> {
+    let check = check_operands(code);
+    if check.is_err() {
+        return Err(check.unwrap_err());
+    }
+
+    let mut binary = Vec::new();
     let mut idx = 0;
 
     // we use variable sizes not to waste space (especially because opcodes and registers
@@ -30,7 +115,7 @@ pub fn serialize(code: &Vec<Code>) -> Result<Vec<u8>, String> {
     while idx < code.len() {
         let op = match code[idx] {
             Code::Op(op) => op,
-            _ => return Err(err!("Expected an opcode, but got {}", code[idx])),
+            _ => return Err(format!("Expected an opcode, but got {}", code[idx])),
         };
         let arg_t = OP_ARG_TYPES[op as usize];
 
@@ -44,7 +129,7 @@ pub fn serialize(code: &Vec<Code>) -> Result<Vec<u8>, String> {
 
                 let reg = match code[idx + 1] {
                     Code::Reg(reg) => reg,
-                    _ => return Err(err!("Expected a register, but got {}", code[idx + 1])),
+                    _ => return Err(format!("Expected a register, but got {}", code[idx + 1])),
                 };
                 binary.extend(reg.to_le_bytes());
                 idx += 2;
@@ -54,13 +139,13 @@ pub fn serialize(code: &Vec<Code>) -> Result<Vec<u8>, String> {
 
                 let int = match code[idx + 1] {
                     Code::Int(int) => int,
-                    _ => return Err(err!("Expected an integer, but got {}", code[idx + 1])),
+                    _ => return Err(format!("Expected an integer, but got {}", code[idx + 1])),
                 };
                 binary.extend(int.to_le_bytes());
 
                 let reg = match code[idx + 2] {
                     Code::Reg(reg) => reg,
-                    _ => return Err(err!("Expected a register, but got {}", code[idx + 2])),
+                    _ => return Err(format!("Expected a register, but got {}", code[idx + 2])),
                 };
                 binary.extend(reg.to_le_bytes());
                 idx += 3;
@@ -70,13 +155,13 @@ pub fn serialize(code: &Vec<Code>) -> Result<Vec<u8>, String> {
 
                 let reg1 = match code[idx + 1] {
                     Code::Reg(reg) => reg,
-                    _ => return Err(err!("Expected a register, but got {}", code[idx + 1])),
+                    _ => return Err(format!("Expected a register, but got {}", code[idx + 1])),
                 };
                 binary.extend(reg1.to_le_bytes());
 
                 let reg2 = match code[idx + 2] {
                     Code::Reg(reg) => reg,
-                    _ => return Err(err!("Expected a register, but got {}", code[idx + 2])),
+                    _ => return Err(format!("Expected a register, but got {}", code[idx + 2])),
                 };
                 binary.extend(reg2.to_le_bytes());
                 idx += 3;
@@ -86,7 +171,7 @@ pub fn serialize(code: &Vec<Code>) -> Result<Vec<u8>, String> {
 
                 let addr = match code[idx + 1] {
                     Code::Addr(addr) => addr,
-                    _ => return Err(err!("Expected an address, but got {}", code[idx + 1])),
+                    _ => return Err(format!("Expected an address, but got {}", code[idx + 1])),
                 };
                 binary.extend(addr.to_le_bytes());
                 idx += 2;
@@ -96,7 +181,7 @@ pub fn serialize(code: &Vec<Code>) -> Result<Vec<u8>, String> {
 
                 let int = match code[idx + 1] {
                     Code::Int(int) => int,
-                    _ => return Err(err!("Expected an integer, but got {}", code[idx + 1])),
+                    _ => return Err(format!("Expected an integer, but got {}", code[idx + 1])),
                 };
                 binary.extend(int.to_le_bytes());
                 idx += 2;
@@ -106,17 +191,65 @@ pub fn serialize(code: &Vec<Code>) -> Result<Vec<u8>, String> {
 
                 let real = match code[idx + 1] {
                     Code::Real(real) => real,
-                    _ => return Err(err!("Expected a real, but got {}", code[idx + 1])),
+                    _ => return Err(format!("Expected a real, but got {}", code[idx + 1])),
                 };
                 binary.extend(real.to_le_bytes());
 
                 let reg = match code[idx + 2] {
                     Code::Reg(reg) => reg,
-                    _ => return Err(err!("Expected a register, but got {}", code[idx + 2])),
+                    _ => return Err(format!("Expected a register, but got {}", code[idx + 2])),
                 };
                 binary.extend(reg.to_le_bytes());
                 idx += 3;
             }
+            OpArgT::IntIntReg => {
+                binary.extend(op.to_le_bytes());
+
+                for offset in 1..=2 {
+                    let int = match code[idx + offset] {
+                        Code::Int(int) => int,
+                        _ => return Err(format!("Expected an integer, but got {}", code[idx + offset])),
+                    };
+                    binary.extend(int.to_le_bytes());
+                }
+
+                let reg = match code[idx + 3] {
+                    Code::Reg(reg) => reg,
+                    _ => return Err(format!("Expected a register, but got {}", code[idx + 3])),
+                };
+                binary.extend(reg.to_le_bytes());
+                idx += 4;
+            }
+            OpArgT::RegRegInt => {
+                binary.extend(op.to_le_bytes());
+
+                for offset in 1..=2 {
+                    let reg = match code[idx + offset] {
+                        Code::Reg(reg) => reg,
+                        _ => return Err(format!("Expected a register, but got {}", code[idx + offset])),
+                    };
+                    binary.extend(reg.to_le_bytes());
+                }
+
+                let int = match code[idx + 3] {
+                    Code::Int(int) => int,
+                    _ => return Err(format!("Expected an integer, but got {}", code[idx + 3])),
+                };
+                binary.extend(int.to_le_bytes());
+                idx += 4;
+            }
+            OpArgT::RegRegReg => {
+                binary.extend(op.to_le_bytes());
+
+                for offset in 1..=3 {
+                    let reg = match code[idx + offset] {
+                        Code::Reg(reg) => reg,
+                        _ => return Err(format!("Expected a register, but got {}", code[idx + offset])),
+                    };
+                    binary.extend(reg.to_le_bytes());
+                }
+                idx += 4;
+            }
         }
     }
 
@@ -124,120 +257,363 @@ pub fn serialize(code: &Vec<Code>) -> Result<Vec<u8>, String> {
 }
 
 pub fn deserialize(binary: Vec<u8>) -> Result<Vec<Code>, String> {
-    let mut code = Vec::new();
-    let mut idx = 0;
+    let deserialized = deserialize_with_symbols(binary);
+    if deserialized.is_err() {
+        return Err(deserialized.unwrap_err());
+    }
+    Ok(deserialized.unwrap().0)
+}
+
+/// Like `deserialize`, but also returns the debug symbols if the binary has them.
+pub fn deserialize_with_symbols(binary: Vec<u8>) -> Result<(Vec<Code>, Option<DebugSymbols>), String> {
+    let deserialized = deserialize_parts(binary);
+    if deserialized.is_err() {
+        return Err(deserialized.unwrap_err());
+    }
+    let (program, has_symbols) = deserialized.unwrap();
+    Ok((program.code, if has_symbols { Some(program.symbols) } else { None }))
+}
+
+/// Deserializes everything a binary holds: the initial register values, the code and the debug
+/// symbols (left empty if the binary has none).
+pub fn deserialize_program(binary: Vec<u8>) -> Result<Program, String> {
+    let deserialized = deserialize_parts(binary);
+    if deserialized.is_err() {
+        return Err(deserialized.unwrap_err());
+    }
+    Ok(deserialized.unwrap().0)
+}
 
+// the deserialized program along with whether the binary had a debug section
+fn deserialize_parts(binary: Vec<u8>) -> Result<(Program, bool), String> {
     if binary.len() < UVM_SIGNATURE_LEN + 1 {
-        return Err(err!("Binary is too short to be a valid uvm binary ({} bytes)", binary.len()));
+        return Err(format!("Binary is too short to be a valid uvm binary ({} bytes)", binary.len()));
     }
 
     if binary[..UVM_SIGNATURE_LEN] != UVM_BINARY_SIGNATURE {
-        return Err(format!("Binary signature is invalid, this is not a UVM binary"));
+        return Err("Binary signature is invalid, this is not a UVM binary".to_string());
     }
 
     // check version
-    if binary[UVM_SIGNATURE_LEN] != UVM_BINARY_VERSION {
-        return Err(err!(
+    let version = binary[UVM_SIGNATURE_LEN];
+    if version < UVM_BINARY_VERSION {
+        return Err(format!(
+            "Binary was written with version {} which is no longer supported, reassemble it to get version {}",
+            version, UVM_BINARY_VERSION
+        ));
+    }
+    if version != UVM_BINARY_VERSION {
+        return Err(format!(
             "Binary version is invalid, written with {} but current version is {}",
-            binary[UVM_SIGNATURE_LEN],
-            UVM_BINARY_VERSION
+            version, UVM_BINARY_VERSION
         ));
     }
+    let mut idx = UVM_SIGNATURE_LEN + 1;
 
-    idx += UVM_SIGNATURE_LEN + 1;
+    // check the checksum of everything following it
+    if binary.len() < idx + UVM_CHECKSUM_LEN {
+        return Err(format!("Binary is too short to be a valid uvm binary ({} bytes)", binary.len()));
+    }
+    let mut checksum = [0; UVM_CHECKSUM_LEN];
+    checksum.copy_from_slice(&binary[idx..idx + UVM_CHECKSUM_LEN]);
+    idx += UVM_CHECKSUM_LEN;
+    if u32::from_le_bytes(checksum) != crc32(&binary[idx..]) {
+        return Err("Binary checksum mismatch".to_string());
+    }
 
+    let num_registers = read_u64(&binary, &mut idx, "the number of initial registers");
+    if num_registers.is_err() {
+        return Err(num_registers.unwrap_err());
+    }
+    let mut registers = Vec::new();
+    for _ in 0..num_registers.unwrap() {
+        let val = read_int(&binary, &mut idx);
+        if val.is_err() {
+            return Err(val.unwrap_err());
+        }
+        registers.push(val.unwrap());
+    }
+
+    let code_len = read_u64(&binary, &mut idx, "the code length");
+    if code_len.is_err() {
+        return Err(code_len.unwrap_err());
+    }
+    let code_len = code_len.unwrap() as usize;
+    if code_len > binary.len() - idx {
+        return Err("Unexpected end of binary while reading the code".to_string());
+    }
+
+    let code = decode_code(&binary[idx..idx + code_len]);
+    if code.is_err() {
+        return Err(code.unwrap_err());
+    }
+    let code = code.unwrap();
+    idx += code_len;
+
+    if idx == binary.len() {
+        let program = Program {
+            code,
+            registers,
+            ..Default::default()
+        };
+        return Ok((program, false));
+    }
+
+    let symbols = decode_symbols(&binary, &mut idx);
+    if symbols.is_err() {
+        return Err(symbols.unwrap_err());
+    }
+    if idx != binary.len() {
+        return Err(format!("Binary has {} unexpected trailing bytes", binary.len() - idx));
+    }
+
+    let program = Program {
+        code,
+        registers,
+        symbols: symbols.unwrap(),
+        ..Default::default()
+    };
+    Ok((program, true))
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    // the usual CRC-32 (as used by zip and png), computed bit by bit since binaries are small
+    let mut crc = 0xFFFFFFFFu32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB88320 & mask);
+        }
+    }
+    !crc
+}
+
+fn read_bytes<const N: usize>(binary: &[u8], idx: &mut usize, thing: &str) -> Result<[u8; N], String> {
+    if binary.len() - *idx < N {
+        return Err(format!("Unexpected end of binary while reading {}", thing));
+    }
+    let mut bytes = [0; N];
+    bytes.copy_from_slice(&binary[*idx..*idx + N]);
+    *idx += N;
+    Ok(bytes)
+}
+
+fn read_u64(binary: &[u8], idx: &mut usize, thing: &str) -> Result<u64, String> {
+    let bytes = read_bytes(binary, idx, thing);
+    if bytes.is_err() {
+        return Err(bytes.unwrap_err());
+    }
+    Ok(u64::from_le_bytes(bytes.unwrap()))
+}
+
+fn read_int(binary: &[u8], idx: &mut usize) -> Result<i64, String> {
+    let bytes = read_bytes(binary, idx, "an integer");
+    if bytes.is_err() {
+        return Err(bytes.unwrap_err());
+    }
+    Ok(i64::from_le_bytes(bytes.unwrap()))
+}
+
+fn read_reg(binary: &[u8], idx: &mut usize) -> Result<u8, String> {
+    let bytes = read_bytes(binary, idx, "a register");
+    if bytes.is_err() {
+        return Err(bytes.unwrap_err());
+    }
+    Ok(u8::from_le_bytes(bytes.unwrap()))
+}
+
+/// Reads a name prefixed by its length, where `thing` says what it names for error messages.
+fn read_name(binary: &[u8], idx: &mut usize, thing: &str) -> Result<String, String> {
+    let len = read_u64(binary, idx, &format!("a {} length", thing));
+    if len.is_err() {
+        return Err(len.unwrap_err());
+    }
+    let len = len.unwrap() as usize;
+    if len > binary.len() - *idx {
+        return Err(format!("Unexpected end of binary while reading a {}", thing));
+    }
+    let name = String::from_utf8(binary[*idx..*idx + len].to_vec());
+    if name.is_err() {
+        return Err(format!("Binary has a {} that is not valid UTF-8 at {}", thing, idx));
+    }
+    *idx += len;
+    Ok(name.unwrap())
+}
+
+fn decode_symbols(binary: &[u8], idx: &mut usize) -> Result<DebugSymbols, String> {
+    let mut symbols = DebugSymbols::default();
+
+    let num_labels = read_u64(binary, idx, "the number of labels");
+    if num_labels.is_err() {
+        return Err(num_labels.unwrap_err());
+    }
+    for _ in 0..num_labels.unwrap() {
+        let label = read_name(binary, idx, "label");
+        if label.is_err() {
+            return Err(label.unwrap_err());
+        }
+
+        let addr = read_u64(binary, idx, "a label address");
+        if addr.is_err() {
+            return Err(addr.unwrap_err());
+        }
+        symbols.labels.push((label.unwrap(), addr.unwrap() as usize));
+    }
+
+    let num_lines = read_u64(binary, idx, "the number of lines");
+    if num_lines.is_err() {
+        return Err(num_lines.unwrap_err());
+    }
+    for _ in 0..num_lines.unwrap() {
+        let addr = read_u64(binary, idx, "an instruction address");
+        if addr.is_err() {
+            return Err(addr.unwrap_err());
+        }
+        let line = read_u64(binary, idx, "a line number");
+        if line.is_err() {
+            return Err(line.unwrap_err());
+        }
+        symbols.lines.push((addr.unwrap() as usize, line.unwrap() as usize));
+    }
+
+    let num_registers = read_u64(binary, idx, "the number of register aliases");
+    if num_registers.is_err() {
+        return Err(num_registers.unwrap_err());
+    }
+    for _ in 0..num_registers.unwrap() {
+        let name = read_name(binary, idx, "register alias");
+        if name.is_err() {
+            return Err(name.unwrap_err());
+        }
+        let reg = read_reg(binary, idx);
+        if reg.is_err() {
+            return Err(reg.unwrap_err());
+        }
+        symbols.registers.push((name.unwrap(), reg.unwrap()));
+    }
+
+    Ok(symbols)
+}
+
+fn decode_code(binary: &[u8]) -> Result<Vec<Code>, String> {
+    let mut code = Vec::new();
+    let mut idx = 0;
+
+    // every read checks there are enough bytes left, so truncated binaries error out instead of panicking
     while idx < binary.len() {
         // get the opcode
         let op = match OpCode::from_le_bytes([binary[idx]]) {
             Some(op) => op,
-            None => return Err(err!("Invalid opcode: {}", binary[idx])),
+            None => return Err(format!("Invalid opcode: {}", binary[idx])),
         };
+        code.push(Code::Op(op));
+        idx += 1;
 
         // figure out what we should be reading next
         let arg_t = OP_ARG_TYPES[op as usize];
 
         match arg_t {
-            OpArgT::Nil => {
-                code.push(Code::Op(op));
-                idx += 1;
-            }
+            OpArgT::Nil => {}
             OpArgT::Reg => {
-                let reg = u8::from_le_bytes([binary[idx + 1]]);
-                code.push(Code::Op(op));
-                code.push(Code::Reg(reg));
-                idx += 2;
+                let reg = read_reg(binary, &mut idx);
+                if reg.is_err() {
+                    return Err(reg.unwrap_err());
+                }
+                code.push(Code::Reg(reg.unwrap()));
             }
             OpArgT::IntReg => {
-                let int = i64::from_le_bytes([
-                    binary[idx + 1],
-                    binary[idx + 2],
-                    binary[idx + 3],
-                    binary[idx + 4],
-                    binary[idx + 5],
-                    binary[idx + 6],
-                    binary[idx + 7],
-                    binary[idx + 8],
-                ]);
-                let reg = u8::from_le_bytes([binary[idx + 9]]);
-                code.push(Code::Op(op));
-                code.push(Code::Int(int));
-                code.push(Code::Reg(reg));
-                idx += 10;
+                let val = read_int(binary, &mut idx);
+                if val.is_err() {
+                    return Err(val.unwrap_err());
+                }
+                code.push(Code::Int(val.unwrap()));
+                let reg = read_reg(binary, &mut idx);
+                if reg.is_err() {
+                    return Err(reg.unwrap_err());
+                }
+                code.push(Code::Reg(reg.unwrap()));
             }
             OpArgT::RegReg => {
-                let reg1 = u8::from_le_bytes([binary[idx + 1]]);
-                let reg2 = u8::from_le_bytes([binary[idx + 2]]);
-                code.push(Code::Op(op));
-                code.push(Code::Reg(reg1));
-                code.push(Code::Reg(reg2));
-                idx += 3;
+                let reg = read_reg(binary, &mut idx);
+                if reg.is_err() {
+                    return Err(reg.unwrap_err());
+                }
+                code.push(Code::Reg(reg.unwrap()));
+                let reg = read_reg(binary, &mut idx);
+                if reg.is_err() {
+                    return Err(reg.unwrap_err());
+                }
+                code.push(Code::Reg(reg.unwrap()));
             }
             OpArgT::Addr => {
-                let addr = usize::from_le_bytes([
-                    binary[idx + 1],
-                    binary[idx + 2],
-                    binary[idx + 3],
-                    binary[idx + 4],
-                    binary[idx + 5],
-                    binary[idx + 6],
-                    binary[idx + 7],
-                    binary[idx + 8],
-                ]);
-                code.push(Code::Op(op));
-                code.push(Code::Addr(addr));
-                idx += 9;
+                let addr = read_u64(binary, &mut idx, "an address");
+                if addr.is_err() {
+                    return Err(addr.unwrap_err());
+                }
+                code.push(Code::Addr(addr.unwrap() as usize));
             }
             OpArgT::Int => {
-                let int = i64::from_le_bytes([
-                    binary[idx + 1],
-                    binary[idx + 2],
-                    binary[idx + 3],
-                    binary[idx + 4],
-                    binary[idx + 5],
-                    binary[idx + 6],
-                    binary[idx + 7],
-                    binary[idx + 8],
-                ]);
-                code.push(Code::Op(op));
-                code.push(Code::Int(int));
-                idx += 9;
+                let val = read_int(binary, &mut idx);
+                if val.is_err() {
+                    return Err(val.unwrap_err());
+                }
+                code.push(Code::Int(val.unwrap()));
             }
             OpArgT::RealReg => {
-                let real = f64::from_le_bytes([
-                    binary[idx + 1],
-                    binary[idx + 2],
-                    binary[idx + 3],
-                    binary[idx + 4],
-                    binary[idx + 5],
-                    binary[idx + 6],
-                    binary[idx + 7],
-                    binary[idx + 8],
-                ]);
-                let reg = u8::from_le_bytes([binary[idx + 9]]);
-                code.push(Code::Op(op));
-                code.push(Code::Real(real));
-                code.push(Code::Reg(reg));
-                idx += 10;
+                let bytes = read_bytes(binary, &mut idx, "a real");
+                if bytes.is_err() {
+                    return Err(bytes.unwrap_err());
+                }
+                code.push(Code::Real(f64::from_le_bytes(bytes.unwrap())));
+                let reg = read_reg(binary, &mut idx);
+                if reg.is_err() {
+                    return Err(reg.unwrap_err());
+                }
+                code.push(Code::Reg(reg.unwrap()));
+            }
+            OpArgT::IntIntReg => {
+                let val = read_int(binary, &mut idx);
+                if val.is_err() {
+                    return Err(val.unwrap_err());
+                }
+                code.push(Code::Int(val.unwrap()));
+                let val = read_int(binary, &mut idx);
+                if val.is_err() {
+                    return Err(val.unwrap_err());
+                }
+                code.push(Code::Int(val.unwrap()));
+                let reg = read_reg(binary, &mut idx);
+                if reg.is_err() {
+                    return Err(reg.unwrap_err());
+                }
+                code.push(Code::Reg(reg.unwrap()));
+            }
+            OpArgT::RegRegInt => {
+                let reg = read_reg(binary, &mut idx);
+                if reg.is_err() {
+                    return Err(reg.unwrap_err());
+                }
+                code.push(Code::Reg(reg.unwrap()));
+                let reg = read_reg(binary, &mut idx);
+                if reg.is_err() {
+                    return Err(reg.unwrap_err());
+                }
+                code.push(Code::Reg(reg.unwrap()));
+                let val = read_int(binary, &mut idx);
+                if val.is_err() {
+                    return Err(val.unwrap_err());
+                }
+                code.push(Code::Int(val.unwrap()));
+            }
+            OpArgT::RegRegReg => {
+                for _ in 0..3 {
+                    let reg = read_reg(binary, &mut idx);
+                    if reg.is_err() {
+                        return Err(reg.unwrap_err());
+                    }
+                    code.push(Code::Reg(reg.unwrap()));
+                }
             }
         }
     }
@@ -245,9 +621,84 @@ pub fn deserialize(binary: Vec<u8>) -> Result<Vec<Code>, String> {
     Ok(code)
 }
 
-pub fn assemble(input_path: String, output_path: String) -> Result<(), String> {
-    let code = {
-        let parsed = parse_file(input_path);
+pub fn diff(a: &[u8], b: &[u8]) -> Result<Vec<String>, String> {
+    // Compares two binaries instruction by instruction and reports the first instruction that
+    // differs. An empty vector means both binaries hold the same program.
+    let code_a = {
+        let code_a = deserialize(a.to_vec());
+        if code_a.is_err() {
+            return Err(code_a.unwrap_err());
+        }
+        code_a.unwrap()
+    };
+    let code_b = {
+        let code_b = deserialize(b.to_vec());
+        if code_b.is_err() {
+            return Err(code_b.unwrap_err());
+        }
+        code_b.unwrap()
+    };
+
+    let listing_a = displayable_code(&code_a);
+    if listing_a.is_err() {
+        return Err(listing_a.unwrap_err());
+    }
+    let (instrs_a, _, idx2addr_a) = listing_a.unwrap();
+    let listing_b = displayable_code(&code_b);
+    if listing_b.is_err() {
+        return Err(listing_b.unwrap_err());
+    }
+    let (instrs_b, _, idx2addr_b) = listing_b.unwrap();
+
+    // the listings make opcodes bold with ANSI escapes, which don't belong in a plain text report
+    let plain = |code: &[Code], idx2addr: &HashMap<usize, usize>, len: usize| -> Vec<String> {
+        (0..len)
+            .map(|idx| {
+                let addr = idx2addr[&idx];
+                let num_args = match code[addr] {
+                    Code::Op(op) => OP_ARG_TYPES[op as usize].num_args(),
+                    _ => unreachable!(),
+                };
+                let instr: Vec<String> = code[addr..=addr + num_args].iter().map(Code::to_plain_string).collect();
+                instr.join(" ")
+            })
+            .collect()
+    };
+    let instrs_a = plain(&code_a, &idx2addr_a, instrs_a.len());
+    let instrs_b = plain(&code_b, &idx2addr_b, instrs_b.len());
+
+    let mut report = Vec::new();
+
+    for idx in 0..std::cmp::max(instrs_a.len(), instrs_b.len()) {
+        match (instrs_a.get(idx), instrs_b.get(idx)) {
+            (Some(instr_a), Some(instr_b)) => {
+                if instr_a != instr_b {
+                    report.push(format!("Instruction #{} differs:", idx));
+                    report.push(format!("  a: {:04} {}", idx2addr_a[&idx], instr_a));
+                    report.push(format!("  b: {:04} {}", idx2addr_b[&idx], instr_b));
+                    break;
+                }
+            }
+            (Some(instr_a), None) => {
+                report.push(format!("Instruction #{} only exists in a:", idx));
+                report.push(format!("  a: {:04} {}", idx2addr_a[&idx], instr_a));
+                break;
+            }
+            (None, Some(instr_b)) => {
+                report.push(format!("Instruction #{} only exists in b:", idx));
+                report.push(format!("  b: {:04} {}", idx2addr_b[&idx], instr_b));
+                break;
+            }
+            (None, None) => unreachable!(),
+        }
+    }
+
+    Ok(report)
+}
+
+pub fn assemble(input_path: String, output_path: String, with_symbols: bool) -> Result<(), String> {
+    let program = {
+        let parsed = parse_program(input_path);
         if parsed.is_err() {
             return Err(parsed.unwrap_err());
         }
@@ -255,7 +706,7 @@ pub fn assemble(input_path: String, output_path: String) -> Result<(), String> {
     };
 
     let serialized = {
-        let serialized = serialize(&code);
+        let serialized = serialize_program(&program, with_symbols);
         if serialized.is_err() {
             return Err(serialized.unwrap_err());
         }
@@ -278,7 +729,7 @@ pub fn assemble(input_path: String, output_path: String) -> Result<(), String> {
     }
 }
 
-pub fn disassemble(input_path: String) -> Result<Vec<Code>, String> {
+pub fn disassemble(input_path: String) -> Result<Program, String> {
     let binary = {
         let binary = std::fs::read(input_path);
         if binary.is_err() {
@@ -287,13 +738,66 @@ pub fn disassemble(input_path: String) -> Result<Vec<Code>, String> {
         binary.unwrap()
     };
 
-    let deserialized = {
-        let deserialized = deserialize(binary);
-        if deserialized.is_err() {
-            return Err(deserialized.unwrap_err());
+    deserialize_program(binary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+
+    #[test]
+    fn test_decode_truncated_code() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(42), Code::Reg(0),
+            Code::Op(OpCode::SETF), Code::Real(1.5), Code::Reg(1),
+            Code::Op(OpCode::ENSURE), Code::Int(0), Code::Int(50), Code::Reg(0),
+            Code::Op(OpCode::JMP), Code::Addr(0),
+        ];
+        let encoded = encode_code(&code).unwrap();
+        assert_eq!(decode_code(&encoded), Ok(code));
+
+        // cutting the code anywhere other than between instructions is caught
+        let boundaries = [0, 10, 20, 38, encoded.len()];
+        for len in 0..encoded.len() {
+            let decoded = decode_code(&encoded[..len]);
+            if boundaries.contains(&len) {
+                assert!(decoded.is_ok());
+            } else {
+                assert!(decoded.unwrap_err().contains("Unexpected end of binary while reading"));
+            }
         }
-        deserialized.unwrap()
-    };
 
-    Ok(deserialized)
+        for code in [
+            vec![Code::Op(OpCode::DOT), Code::Reg(0), Code::Reg(8), Code::Int(3)],
+            vec![Code::Op(OpCode::BSEARCH), Code::Reg(0), Code::Reg(1), Code::Reg(2)],
+            vec![Code::Op(OpCode::CLR), Code::Reg(7), Code::Op(OpCode::CLRA)],
+            vec![Code::Op(OpCode::POPCNT), Code::Reg(1)],
+            vec![Code::Op(OpCode::CLZ), Code::Reg(2)],
+            vec![Code::Op(OpCode::CTZ), Code::Reg(3)],
+            vec![Code::Op(OpCode::SPGET), Code::Reg(4)],
+            vec![Code::Op(OpCode::CSPGET), Code::Reg(5)],
+            vec![Code::Op(OpCode::DROP), Code::Int(3)],
+            vec![Code::Op(OpCode::DBGSTACK), Code::Op(OpCode::DBGSTACKN), Code::Int(2)],
+            vec![Code::Op(OpCode::CMOVLT), Code::Reg(0), Code::Reg(1)],
+        ] {
+            assert_eq!(decode_code(&encode_code(&code).unwrap()), Ok(code));
+        }
+    }
+
+    #[test]
+    fn test_serialize_malformed_code() {
+        let code = vec![Code::Op(OpCode::HALT), Code::Op(OpCode::SET), Code::Int(1)];
+        assert_eq!(serialize(&code), Err("SET at 1 takes 2 operands but only 1 follow it".to_string()));
+        assert_eq!(crate::asm::display_code(&code, false).map(|_| ()), serialize(&code).map(|_| ()));
+
+        let code = vec![Code::Int(1), Code::Op(OpCode::HALT)];
+        assert_eq!(serialize(&code), Err("Expected an opcode, but got 1i at 0".to_string()));
+    }
 }
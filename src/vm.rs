@@ -1,194 +1,1004 @@
-use std::io::Write;
+use std::{
+    collections::HashMap,
+    io::{BufRead, Write},
+    ops::Range,
+};
 
 use crate::{
-    asm::{displayable_code, Code, OpCode},
+    asm::{displayable_code, Code, DebugSymbols, OpArgT, OpCode, Program, SourceSpan, OP_ARG_TYPES},
+    parser::parse_int_literal,
     utils::{f2i, i2f},
 };
 
-const NUM_REGISTERS: usize = 16;
-const STACK_SIZE: usize = 8 * 1024;
-const CALL_STACK_SIZE: usize = 1 * 1024;
+pub const NUM_REGISTERS: usize = 16;
+pub const STACK_SIZE: usize = 8 * 1024;
+pub const CALL_STACK_SIZE: usize = 1024;
+/// What `CMPF` stores in `cmp` when either float is NaN. Only `JNE`, `CMOVNE` and `JUN` act on it, since NaN
+/// is neither less than, equal to nor greater than anything.
+pub const CMP_UNORDERED: i8 = 2;
+/// What `RAND` and `RANDF` are seeded with unless `VM::with_seed` says otherwise.
+pub const DEFAULT_SEED: u64 = 0x2545_F491_4F6C_DD1D;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct VmConfig {
+    pub num_registers: usize,
+    pub stack_size: usize,
+    pub call_stack_size: usize,
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        Self {
+            num_registers: NUM_REGISTERS,
+            stack_size: STACK_SIZE,
+            call_stack_size: CALL_STACK_SIZE,
+        }
+    }
+}
+
+/// A host function run by `SYSCALL`, which can read and change the state of the VM that runs it.
+pub type Syscall = Box<dyn FnMut(&mut VM) + Send>;
+
+/// Called by the VM before running each instruction, with its address and opcode.
+pub type Tracer = Box<dyn FnMut(usize, OpCode) + Send>;
 
+/// A VM is `Send`, so it can be moved into a worker thread and driven from another one over a channel
+/// (see `examples/threaded_stepping.rs`). It isn't `Sync`: only the thread that owns it can touch it.
 pub struct VM {
-    regs: [i64; NUM_REGISTERS],
-    stack: [i64; STACK_SIZE],
-    call_stack: [usize; CALL_STACK_SIZE],
+    config: VmConfig,
+    regs: Vec<i64>,
+    stack: Vec<i64>,
+    call_stack: Vec<usize>,
     code: Vec<Code>,
     pc: usize,
     sp: usize,
+    bp: usize, // base of the current stack frame, set by ENTER
     csp: usize,
     cmp: i8,
     capture_output: bool,
+    captured_output: String,
+    output_limit: Option<usize>, // how many bytes of output can be captured
+    output: Box<dyn Write + Send>,
+    step_limit: Option<u64>,
+    steps: u64,
+    checked_arithmetic: bool, // whether integer overflow is an error instead of wrapping around
+    rng: u64,                 // xorshift64 state, never zero
+    seed: u64,                // where `rng` started, for `reset`
+    initial_regs: Vec<i64>,   // values given to `with_registers`, for `reset`
+    initial_data: Vec<i64>,   // values given to `with_data`, for `reset`
+    op_pc: usize,             // address of the instruction currently being executed
+    halted: bool,
+    result: Option<i64>, // the value given to `RESULT`, if the program halted through it
+    symbols: DebugSymbols,
+    op_counts: Vec<u64>, // how many times each opcode was executed, indexed by its discriminant
+    step_input: Option<Box<dyn BufRead + Send>>, // where to wait for a line before each instruction when single-stepping
+    spans: Option<Vec<SourceSpan>>,              // where each entry of the code was written, if known
+    boundaries: Vec<bool>,                       // whether each entry of the code is the start of an instruction
+    syscalls: HashMap<i64, Syscall>,
+    tracer: Option<Tracer>,
+    decoded: Vec<Option<Instruction>>, // each well-formed instruction decoded ahead of time, by address
+    operands: [u64; 3],                // operands of the instruction being executed, if it was decoded
+    next_operand: Option<usize>,       // which of `operands` comes next, or None to read them from the code
+}
+
+/// A copy of everything a program can change while running, taken by `VM::snapshot()`. The code is
+/// not part of it: a snapshot is meant to be restored into the VM it was taken from, whose code never
+/// changes. Output already written, the step count and the opcode counters are not rolled back either.
+/// Only the cells of each stack that are in use are kept, since those above `sp`/`csp` can't be read.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VmSnapshot {
+    regs: Vec<i64>,
+    stack: Vec<i64>,
+    call_stack: Vec<usize>,
+    pc: usize,
+    sp: usize,
+    bp: usize,
+    csp: usize,
+    cmp: i8,
+    halted: bool,
+    result: Option<i64>,
+    rng: u64,
+}
+
+/// How many instructions the debugger's `list` command shows at most.
+const DEBUGGER_LIST_LEN: usize = 64;
+
+/// How many instructions the debugger remembers so that they can be stepped back over.
+const DEBUGGER_HISTORY_LEN: usize = 1024;
+
+/// How many entries of each stack are shown when reporting an overflow.
+const OVERFLOW_CONTEXT_ENTRIES: usize = 4;
+
+/// Where an overflow happened and what was on top of the stacks at the time,
+/// which is usually enough to spot a runaway recursion or a leak.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OverflowContext {
+    pub pc: usize,
+    pub instruction: String,
+    pub stack_top: Vec<i64>,
+    pub call_stack_top: Vec<usize>,
+}
+
+impl std::fmt::Display for OverflowContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "at pc {} ({}), stack top: {:?}, call stack top: {:?}",
+            self.pc, self.instruction, self.stack_top, self.call_stack_top
+        )
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum VmError {
+    StackOverflow(OverflowContext),
+    StackUnderflow,
+    FrameStackOverflow(OpCode, usize, OverflowContext),
+    FrameStackUnderflow(OpCode, usize),
+    FrameSizeOutOfBounds(OpCode, i64),
+    CallStackOverflow(OverflowContext),
+    CallStackUnderflow,
+    RegisterOutOfBounds(i64),
+    UnexpectedCode {
+        expected: &'static str,
+        found: Code,
+        addr: usize,
+    },
+    DivisionByZero,
+    IntegerOverflow(OpCode),
+    ConversionOverflow(OpCode),
+    PcOutOfBounds(usize),
+    StepLimitExceeded(u64),
+    OutputLimitExceeded(usize),
+    OutputFailed(String),
+    StackIndexOutOfBounds(OpCode, i64),
+    EnsureFailed {
+        reg: usize,
+        val: i64,
+        lo: i64,
+        hi: i64,
+    },
+    InvalidOpCode(i64),
+    NegativeOperand(OpCode, i64),
+    InvalidBase(i64),
+    InvalidJump(usize),
+    UnknownLimit(i64),
+    UnknownSyscall(i64),
+}
+
+impl std::fmt::Display for VmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            VmError::StackOverflow(ctx) => write!(f, "Stack overflow {}", ctx),
+            VmError::StackUnderflow => write!(f, "Stack underflow"),
+            VmError::FrameStackOverflow(op, size, ctx) => {
+                write!(f, "{} {}: stack overflow {}", op, size, ctx)
+            }
+            VmError::FrameStackUnderflow(op, size) => write!(f, "{} {}: stack underflow", op, size),
+            VmError::FrameSizeOutOfBounds(op, size) => {
+                write!(f, "{} received a register frame size of {} out of bounds", op, size)
+            }
+            VmError::CallStackOverflow(ctx) => write!(f, "Call stack overflow {}", ctx),
+            VmError::CallStackUnderflow => write!(f, "Call stack underflow"),
+            VmError::RegisterOutOfBounds(reg) => write!(f, "Register index out of bounds: {}", reg),
+            VmError::UnexpectedCode { expected, found, addr } => {
+                write!(f, "Expected {}, but got {} at {}", expected, found, addr)
+            }
+            VmError::DivisionByZero => write!(f, "Division by zero"),
+            VmError::IntegerOverflow(op) => write!(f, "Integer overflow in {}", op),
+            VmError::ConversionOverflow(op) => write!(f, "{} overflow", op),
+            VmError::PcOutOfBounds(pc) => write!(f, "Program counter out of bounds: {}", pc),
+            VmError::StepLimitExceeded(_) => write!(f, "Instruction limit exceeded"),
+            VmError::OutputLimitExceeded(_) => write!(f, "Output limit exceeded"),
+            VmError::OutputFailed(msg) => write!(f, "Failed to write output: {}", msg),
+            VmError::StackIndexOutOfBounds(op, idx) => {
+                write!(f, "{} received a stack index out of bounds: {}", op, idx)
+            }
+            VmError::InvalidOpCode(op) => write!(f, "There is no opcode numbered {}", op),
+            VmError::InvalidJump(addr) => write!(f, "Jump to invalid address {}", addr),
+            VmError::InvalidBase(base) => {
+                write!(f, "PRINTB received base {} but it must be from 2 to 36", base)
+            }
+            VmError::UnknownLimit(which) => {
+                write!(f, "GETLIMIT received limit {} but it must be from 0 to 3", which)
+            }
+            VmError::UnknownSyscall(id) => write!(f, "There is no syscall registered as {}", id),
+            VmError::NegativeOperand(op, val) => write!(f, "{} received a negative operand: {}", op, val),
+            VmError::EnsureFailed { reg, val, lo, hi } => {
+                write!(f, "ENSURE failed: r{} = {} is outside [{}, {}]", reg, val, lo, hi)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+// Rust's `/` and `%` panic both on a zero divisor and on `i64::MIN / -1`, so the VM
+// goes through these to report them as errors instead.
+
+fn int_div(op: OpCode, dividend: i64, divisor: i64) -> Result<i64, VmError> {
+    if divisor == 0 {
+        return Err(VmError::DivisionByZero);
+    }
+    dividend.checked_div(divisor).ok_or(VmError::IntegerOverflow(op))
+}
+
+fn int_rem(op: OpCode, dividend: i64, divisor: i64) -> Result<i64, VmError> {
+    if divisor == 0 {
+        return Err(VmError::DivisionByZero);
+    }
+    dividend.checked_rem(divisor).ok_or(VmError::IntegerOverflow(op))
+}
+
+// Likewise `+`, `-` and `*` panic on overflow in debug builds but wrap in release ones, so the VM
+// picks one of these explicitly, depending on whether it runs with checked arithmetic.
+
+type IntArith = (fn(i64, i64) -> Option<i64>, fn(i64, i64) -> i64);
+
+const INT_ADD: IntArith = (i64::checked_add, i64::wrapping_add);
+const INT_SUB: IntArith = (i64::checked_sub, i64::wrapping_sub);
+const INT_MUL: IntArith = (i64::checked_mul, i64::wrapping_mul);
+
+/// Marks where each instruction starts by walking the code from the top, stopping at anything malformed
+/// (which `step` reports once execution gets there).
+fn instruction_boundaries(code: &[Code]) -> Vec<bool> {
+    let mut boundaries = vec![false; code.len()];
+    let mut addr = 0;
+    while let Some(Code::Op(op)) = code.get(addr) {
+        boundaries[addr] = true;
+        addr += 1 + OP_ARG_TYPES[*op as usize].num_args();
+    }
+    boundaries
+}
+
+/// An instruction decoded ahead of time, with operands that are known to be of the right kind (and
+/// registers known to be in range), so running it doesn't need to look at the code again. Registers
+/// and addresses are kept as indices and reals as their bits.
+#[derive(Copy, Clone)]
+struct Instruction {
+    op: OpCode,
+    args: [u64; 3],
+}
+
+/// What an operand of an instruction has to be.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum OperandKind {
+    Reg,
+    Int,
+    Addr,
+    Real,
+}
+
+impl OperandKind {
+    /// How `UnexpectedCode` errors describe it.
+    fn description(self) -> &'static str {
+        match self {
+            OperandKind::Reg => "a register",
+            OperandKind::Int => "an integer",
+            OperandKind::Addr => "an address",
+            OperandKind::Real => "a real",
+        }
+    }
+}
+
+/// What each operand of an instruction taking `arg_t` must be.
+fn expected_operands(arg_t: OpArgT) -> &'static [OperandKind] {
+    use OperandKind::*;
+    match arg_t {
+        OpArgT::Nil => &[],
+        OpArgT::Reg => &[Reg],
+        OpArgT::IntReg => &[Int, Reg],
+        OpArgT::RegReg => &[Reg, Reg],
+        OpArgT::Addr => &[Addr],
+        OpArgT::Int => &[Int],
+        OpArgT::RealReg => &[Real, Reg],
+        OpArgT::IntIntReg => &[Int, Int, Reg],
+        OpArgT::RegRegInt => &[Reg, Reg, Int],
+        OpArgT::RegRegReg => &[Reg, Reg, Reg],
+    }
+}
+
+/// Decodes every well-formed instruction found by walking the code from the top, indexed by its
+/// address. Anything else is left as `None`, for `step` to go through the code and report it.
+fn decode_instructions(code: &[Code], num_registers: usize) -> Vec<Option<Instruction>> {
+    let mut decoded = vec![None; code.len()];
+    let mut addr = 0;
+    while let Some(Code::Op(op)) = code.get(addr) {
+        let expected = expected_operands(OP_ARG_TYPES[*op as usize]);
+        let mut args = [0; 3];
+        let mut well_formed = addr + expected.len() < code.len();
+        for (idx, expected) in expected.iter().enumerate() {
+            let arg = match (code.get(addr + 1 + idx), *expected) {
+                (Some(Code::Reg(reg)), OperandKind::Reg) if (*reg as usize) < num_registers => *reg as u64,
+                (Some(Code::Int(val)), OperandKind::Int) => *val as u64,
+                (Some(Code::Addr(target)), OperandKind::Addr) => *target as u64,
+                (Some(Code::Real(val)), OperandKind::Real) => val.to_bits(),
+                _ => {
+                    well_formed = false;
+                    break;
+                }
+            };
+            args[idx] = arg;
+        }
+        if well_formed {
+            decoded[addr] = Some(Instruction { op: *op, args });
+        }
+        addr += 1 + expected.len();
+    }
+    decoded
 }
 
 struct StepResult {
     continue_running: bool,
+    yielded: bool,
     output: Option<String>,
 }
 
+/// Who a piece of program output is being shown to, which decides where it goes and how it looks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OutputKind {
+    /// Printed while running normally: captured if `capture_output` is set, written out otherwise.
+    Run,
+    /// Printed while stepping through the debugger: always written out, set apart from its own messages.
+    Debugger,
+}
+
+/// How a call to `resume()` gave control back, along with the output captured until then.
+#[derive(Debug, PartialEq)]
+pub enum RunResult {
+    Halted(String),
+    Yielded(String),
+    Error(VmError),
+}
+
+/// What the VM did on a single `tick()`, along with the output the instruction produced (if any).
+#[derive(Debug, PartialEq)]
+pub enum VmStatus {
+    Running(Option<String>),
+    Yielded(Option<String>),
+    Halted(Option<String>),
+}
+
 impl VM {
     pub fn new(code: Vec<Code>) -> Self {
+        Self::with_config(code, VmConfig::default())
+    }
+
+    pub fn with_config(code: Vec<Code>, config: VmConfig) -> Self {
         Self {
-            regs: [0; NUM_REGISTERS],
-            stack: [0; STACK_SIZE],
-            call_stack: [0; CALL_STACK_SIZE],
-            code,
+            config,
+            regs: vec![0; config.num_registers],
+            stack: vec![0; config.stack_size],
+            call_stack: vec![0; config.call_stack_size],
             pc: 0,
             sp: 0,
+            bp: 0,
             csp: 0,
             cmp: 0,
             capture_output: false,
+            captured_output: String::new(),
+            output_limit: None,
+            output: Box::new(std::io::stdout()),
+            step_limit: None,
+            checked_arithmetic: false,
+            steps: 0,
+            rng: DEFAULT_SEED,
+            seed: DEFAULT_SEED,
+            initial_regs: Vec::new(),
+            initial_data: Vec::new(),
+            op_pc: 0,
+            halted: false,
+            result: None,
+            symbols: DebugSymbols::default(),
+            op_counts: vec![0; OP_ARG_TYPES.len()],
+            step_input: None,
+            spans: None,
+            boundaries: instruction_boundaries(&code),
+            decoded: decode_instructions(&code, config.num_registers),
+            operands: [0; 3],
+            next_operand: None,
+            syscalls: HashMap::new(),
+            tracer: None,
+            code,
+        }
+    }
+
+    /// Creates a VM for a parsed program, placing its data section at the bottom of the stack.
+    pub fn from_program(program: Program) -> Result<Self, VmError> {
+        Self::new(program.code)
+            .with_symbols(program.symbols)
+            .with_registers(&program.registers)?
+            .with_data(&program.data)
+    }
+
+    /// Sets the first registers to `values` (r0 to the first one, and so on) before anything runs.
+    pub fn with_registers(mut self, values: &[i64]) -> Result<Self, VmError> {
+        if values.len() > self.config.num_registers {
+            return Err(VmError::RegisterOutOfBounds(self.config.num_registers as i64));
+        }
+        self.regs[..values.len()].copy_from_slice(values);
+        self.initial_regs = values.to_vec();
+        Ok(self)
+    }
+
+    /// Lets the debugger show label names and source lines, and set breakpoints on labels.
+    pub fn with_symbols(mut self, symbols: DebugSymbols) -> Self {
+        self.symbols = symbols;
+        self
+    }
+
+    /// Places `data` at the bottom of the stack, so that `sp` starts right after it.
+    pub fn with_data(mut self, data: &[i64]) -> Result<Self, VmError> {
+        if data.len() > self.config.stack_size {
+            let data_top = data.len().saturating_sub(OVERFLOW_CONTEXT_ENTRIES);
+            return Err(VmError::StackOverflow(OverflowContext {
+                pc: 0,
+                instruction: ".data".to_string(),
+                stack_top: data[data_top..].to_vec(),
+                call_stack_top: Vec::new(),
+            }));
         }
+        self.stack[..data.len()].copy_from_slice(data);
+        self.sp = data.len();
+        self.initial_data = data.to_vec();
+        Ok(self)
     }
 
+    /// Collects the program output and returns it from `run()` instead of writing it out.
+    /// Takes precedence over `with_output`, i.e. nothing reaches the writer while capturing.
     pub fn capture_output(mut self) -> Self {
         self.capture_output = true;
         self
     }
 
-    pub fn get_registers(&self) -> [i64; NUM_REGISTERS] {
-        self.regs
+    /// Stops execution with `VmError::OutputLimitExceeded` once the captured output grows past `bytes`
+    /// (see `capture_output`), so a program printing in a loop can't take up all the memory. There's no
+    /// limit by default, and none applies to output that's written out instead of captured.
+    pub fn with_output_limit(mut self, bytes: usize) -> Self {
+        self.output_limit = Some(bytes);
+        self
     }
 
-    pub fn get_registers_as_floats(&self) -> [f64; NUM_REGISTERS] {
-        let mut regs = [0.0; NUM_REGISTERS];
-        for i in 0..NUM_REGISTERS {
-            regs[i] = i2f(self.regs[i]);
-        }
-        regs
+    /// Writes the program output (and the debugger's interface) to `writer` instead of stdout.
+    pub fn with_output(mut self, writer: impl Write + Send + 'static) -> Self {
+        self.output = Box::new(writer);
+        self
+    }
+
+    /// Lets `op_span()` tell where in the source the current instruction was written, given the span
+    /// of each entry of the code (e.g. from `parser::parse_program_with_spans`).
+    pub fn with_spans(mut self, spans: Vec<SourceSpan>) -> Self {
+        self.spans = Some(spans);
+        self
+    }
+
+    /// Single-steps the program: before running each instruction it's printed along with its address,
+    /// and it only runs once Enter is pressed. Unlike `debugger()` there are no commands.
+    pub fn step_by_step(self) -> Self {
+        self.step_by_step_with_input(std::io::BufReader::new(std::io::stdin()))
+    }
+
+    /// Same as `step_by_step`, but waiting for lines from `input` instead of stdin.
+    /// Once `input` runs out of lines the program runs on without waiting.
+    pub fn step_by_step_with_input(mut self, input: impl BufRead + Send + 'static) -> Self {
+        self.step_input = Some(Box::new(input));
+        self
+    }
+
+    /// Stops execution with `VmError::StepLimitExceeded` once more than `max` instructions were executed.
+    /// There's no limit by default.
+    pub fn with_step_limit(mut self, max: u64) -> Self {
+        self.step_limit = Some(max);
+        self
+    }
+
+    /// Makes `ADD`, `SUB`, `MUL`, `INC`, `DEC` and their literal variants stop execution with
+    /// `VmError::IntegerOverflow` when the result doesn't fit in an i64. By default they wrap around.
+    pub fn with_checked_arithmetic(mut self) -> Self {
+        self.checked_arithmetic = true;
+        self
+    }
+
+    /// Calls `tracer` with the address and opcode of each instruction right before it runs, e.g. to
+    /// record coverage. Without a tracer nothing is called.
+    pub fn with_tracer(mut self, tracer: impl FnMut(usize, OpCode) + Send + 'static) -> Self {
+        self.tracer = Some(Box::new(tracer));
+        self
+    }
+
+    /// Seeds the generator behind `RAND` and `RANDF`, so that the same seed gives the same sequence.
+    /// Since the generator can't start from zero, a seed of 0 means `DEFAULT_SEED`.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = if seed == 0 { DEFAULT_SEED } else { seed };
+        self.seed = self.rng;
+        self
+    }
+
+    /// Brings the VM back to how it was before running anything, so that the same program can be run
+    /// again without loading it anew. The initial registers and the data given to `with_registers` and
+    /// `with_data` are put back, the random generator starts over from its seed, and the step count,
+    /// the opcode counts and any captured output are cleared. Everything set up through the other
+    /// builders (e.g. syscalls, the output or the step limit) is kept.
+    pub fn reset(&mut self) {
+        self.regs.fill(0);
+        self.regs[..self.initial_regs.len()].copy_from_slice(&self.initial_regs);
+        self.stack.fill(0);
+        self.stack[..self.initial_data.len()].copy_from_slice(&self.initial_data);
+        self.call_stack.fill(0);
+        self.pc = 0;
+        self.sp = self.initial_data.len();
+        self.bp = 0;
+        self.csp = 0;
+        self.cmp = 0;
+        self.captured_output.clear();
+        self.steps = 0;
+        self.rng = self.seed;
+        self.op_pc = 0;
+        self.halted = false;
+        self.result = None;
+        self.op_counts.fill(0);
+    }
+
+    pub fn get_registers(&self) -> &[i64] {
+        &self.regs
+    }
+
+    pub fn get_registers_mut(&mut self) -> &mut [i64] {
+        &mut self.regs
+    }
+
+    pub fn get_registers_as_floats(&self) -> Vec<f64> {
+        self.regs.iter().map(|&val| i2f(val)).collect()
+    }
+
+    /// How many times each opcode was executed, indexed by the opcode's discriminant.
+    pub fn get_op_counts(&self) -> &[u64] {
+        &self.op_counts
+    }
+
+    /// How many times each opcode that ran at least once was executed, for profiling a run.
+    pub fn profile(&self) -> HashMap<OpCode, u64> {
+        self.op_counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(op, count)| (OpCode::from_le_bytes([op as u8]).unwrap(), *count))
+            .collect()
+    }
+
+    /// The value the program designated as its result by halting with `RESULT`, if it did.
+    pub fn get_result(&self) -> Option<i64> {
+        self.result
     }
 
     pub fn get_cmp(&self) -> i8 {
         self.cmp
     }
 
-    fn consume_op(&mut self) -> OpCode {
-        match self.code[self.pc] {
+    pub fn snapshot(&self) -> VmSnapshot {
+        VmSnapshot {
+            regs: self.regs.clone(),
+            stack: self.stack[..self.sp].to_vec(),
+            call_stack: self.call_stack[..self.csp].to_vec(),
+            pc: self.pc,
+            sp: self.sp,
+            bp: self.bp,
+            csp: self.csp,
+            cmp: self.cmp,
+            halted: self.halted,
+            result: self.result,
+            rng: self.rng,
+        }
+    }
+
+    /// Brings the VM back to the state it was in when `snap` was taken from it.
+    pub fn restore(&mut self, snap: &VmSnapshot) {
+        self.regs.clone_from(&snap.regs);
+        self.stack[..snap.stack.len()].copy_from_slice(&snap.stack);
+        self.call_stack[..snap.call_stack.len()].copy_from_slice(&snap.call_stack);
+        self.pc = snap.pc;
+        self.sp = snap.sp;
+        self.bp = snap.bp;
+        self.csp = snap.csp;
+        self.cmp = snap.cmp;
+        self.halted = snap.halted;
+        self.result = snap.result;
+        self.rng = snap.rng;
+    }
+
+    /// Where the instruction executed last was written, if the VM was given spans. After an error
+    /// this points at the instruction that failed.
+    pub fn op_span(&self) -> Option<SourceSpan> {
+        self.spans.as_ref().and_then(|spans| spans.get(self.op_pc)).cloned()
+    }
+
+    /// Address of the next instruction to be executed.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn sp(&self) -> usize {
+        self.sp
+    }
+
+    /// Base of the current stack frame, i.e. where the locals reserved by the last `ENTER` start.
+    pub fn bp(&self) -> usize {
+        self.bp
+    }
+
+    /// The values currently on the stack, from the bottom up to (but excluding) `sp`.
+    pub fn stack(&self) -> &[i64] {
+        &self.stack[..self.sp]
+    }
+
+    /// Same as `stack`, but allowing the values to be changed (e.g. by a syscall).
+    pub fn stack_mut(&mut self) -> &mut [i64] {
+        &mut self.stack[..self.sp]
+    }
+
+    /// Makes `SYSCALL id` run `syscall`, replacing whatever was registered under `id` before.
+    pub fn register_syscall(&mut self, id: i64, syscall: impl FnMut(&mut VM) + Send + 'static) {
+        self.syscalls.insert(id, Box::new(syscall));
+    }
+
+    fn current_code(&self) -> Result<Code, VmError> {
+        if self.pc >= self.code.len() {
+            return Err(VmError::PcOutOfBounds(self.pc));
+        }
+        Ok(self.code[self.pc])
+    }
+
+    fn unexpected_code(&self, expected: &'static str) -> VmError {
+        VmError::UnexpectedCode {
+            expected,
+            found: self.code[self.pc],
+            addr: self.pc,
+        }
+    }
+
+    /// The address right after the `count` whole instructions starting at `addr`.
+    fn skip_instructions(&self, addr: usize, count: usize) -> Result<usize, VmError> {
+        let mut addr = addr;
+        for _ in 0..count {
+            match self.code.get(addr) {
+                Some(Code::Op(op)) => addr += 1 + OP_ARG_TYPES[*op as usize].num_args(),
+                Some(found) => {
+                    return Err(VmError::UnexpectedCode {
+                        expected: "an opcode",
+                        found: *found,
+                        addr,
+                    })
+                }
+                None => return Err(VmError::PcOutOfBounds(addr)),
+            }
+        }
+        // the arguments of the last skipped instruction must be there as well
+        if addr > self.code.len() {
+            return Err(VmError::PcOutOfBounds(addr));
+        }
+        Ok(addr)
+    }
+
+    /// Advances the xorshift64 generator and returns its new state.
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    /// Applies one of the `INT_*` operations, either wrapping around or failing on overflow.
+    fn int_arith(&self, op: OpCode, (checked, wrapping): IntArith, lhs: i64, rhs: i64) -> Result<i64, VmError> {
+        if self.checked_arithmetic {
+            checked(lhs, rhs).ok_or(VmError::IntegerOverflow(op))
+        } else {
+            Ok(wrapping(lhs, rhs))
+        }
+    }
+
+    /// Whether `cmp` has what the conditional jump or move `op` checks for (e.g. LT for `JLT` and `CMOVLT`).
+    fn condition_holds(&self, op: OpCode) -> bool {
+        match op {
+            OpCode::JEQ | OpCode::CMOVEQ => self.cmp == 0,
+            OpCode::JLT | OpCode::CMOVLT => self.cmp == -1,
+            OpCode::JLE | OpCode::CMOVLE => self.cmp <= 0,
+            OpCode::JGT | OpCode::CMOVGT => self.cmp == 1,
+            OpCode::JGE | OpCode::CMOVGE => self.cmp == 0 || self.cmp == 1,
+            OpCode::JNE | OpCode::CMOVNE => self.cmp != 0,
+            OpCode::JUN => self.cmp == CMP_UNORDERED,
+            _ => unreachable!("{} isn't conditional", op),
+        }
+    }
+
+    /// Moves execution to `addr`, which must be the start of an instruction.
+    fn jump_to(&mut self, addr: usize) -> Result<(), VmError> {
+        if !self.boundaries.get(addr).copied().unwrap_or(false) {
+            return Err(VmError::InvalidJump(addr));
+        }
+        self.pc = addr;
+        Ok(())
+    }
+
+    /// Checks every instruction of the program up front, including ones on branches that never run:
+    /// each opcode must be followed by the operands it expects, registers must be in range and
+    /// addresses must land on an instruction. Reports the first problem found, as `step` would.
+    pub fn validate(&self) -> Result<(), VmError> {
+        let mut addr = 0;
+        while addr < self.code.len() {
+            let op = match self.code[addr] {
+                Code::Op(op) => op,
+                found => {
+                    return Err(VmError::UnexpectedCode {
+                        expected: "an opcode",
+                        found,
+                        addr,
+                    })
+                }
+            };
+            let expected = expected_operands(OP_ARG_TYPES[op as usize]);
+            for (offset, expected) in expected.iter().enumerate() {
+                let arg_addr = addr + 1 + offset;
+                let found = match self.code.get(arg_addr) {
+                    Some(found) => *found,
+                    None => return Err(VmError::PcOutOfBounds(arg_addr)),
+                };
+                let fits = match (found, *expected) {
+                    (Code::Reg(reg), OperandKind::Reg) => {
+                        if reg as usize >= self.config.num_registers {
+                            return Err(VmError::RegisterOutOfBounds(reg as i64));
+                        }
+                        true
+                    }
+                    (Code::Addr(target), OperandKind::Addr) => {
+                        if !self.boundaries.get(target).copied().unwrap_or(false) {
+                            return Err(VmError::InvalidJump(target));
+                        }
+                        true
+                    }
+                    (Code::Int(_), OperandKind::Int) | (Code::Real(_), OperandKind::Real) => true,
+                    _ => false,
+                };
+                if !fits {
+                    return Err(VmError::UnexpectedCode {
+                        expected: expected.description(),
+                        found,
+                        addr: arg_addr,
+                    });
+                }
+            }
+            addr += 1 + expected.len();
+        }
+        Ok(())
+    }
+
+    fn consume_op(&mut self) -> Result<OpCode, VmError> {
+        match self.current_code()? {
             Code::Op(op) => {
                 self.pc += 1;
-                op
+                Ok(op)
             }
-            _ => panic!("Expected OpCode but got {}", self.code[self.pc]),
+            _ => Err(self.unexpected_code("an opcode")),
         }
     }
 
-    fn consume_reg(&mut self) -> usize {
-        match self.code[self.pc] {
+    /// The next operand of the instruction being executed, if it was decoded ahead of time.
+    fn decoded_operand(&mut self) -> Option<u64> {
+        let idx = self.next_operand?;
+        self.next_operand = Some(idx + 1);
+        self.pc += 1;
+        Some(self.operands[idx])
+    }
+
+    fn consume_reg(&mut self) -> Result<usize, VmError> {
+        if let Some(reg) = self.decoded_operand() {
+            return Ok(reg as usize);
+        }
+        match self.current_code()? {
             Code::Reg(reg) => {
-                if reg as usize >= NUM_REGISTERS {
-                    panic!("Register index out of bounds: {}", reg);
+                if reg as usize >= self.config.num_registers {
+                    return Err(VmError::RegisterOutOfBounds(reg as i64));
                 }
 
                 self.pc += 1;
-                reg as usize
+                Ok(reg as usize)
             }
-            _ => panic!("Expected a register, but got {} at {}", self.code[self.pc], self.pc),
+            _ => Err(self.unexpected_code(OperandKind::Reg.description())),
         }
     }
 
-    fn consume_int(&mut self) -> i64 {
-        match self.code[self.pc] {
+    fn consume_int(&mut self) -> Result<i64, VmError> {
+        if let Some(val) = self.decoded_operand() {
+            return Ok(val as i64);
+        }
+        match self.current_code()? {
             Code::Int(val) => {
                 self.pc += 1;
-                val
+                Ok(val)
             }
-            _ => panic!("Expected an integer, but got {} at {}", self.code[self.pc], self.pc),
+            _ => Err(self.unexpected_code(OperandKind::Int.description())),
         }
     }
 
-    fn consume_addr(&mut self) -> usize {
-        match self.code[self.pc] {
+    fn consume_addr(&mut self) -> Result<usize, VmError> {
+        if let Some(addr) = self.decoded_operand() {
+            return Ok(addr as usize);
+        }
+        match self.current_code()? {
             Code::Addr(addr) => {
                 self.pc += 1;
-                addr
+                Ok(addr)
             }
-            _ => panic!("Expected an address, but got {} at {}", self.code[self.pc], self.pc),
+            _ => Err(self.unexpected_code(OperandKind::Addr.description())),
         }
     }
 
-    fn consume_real(&mut self) -> f64 {
-        match self.code[self.pc] {
+    fn consume_real(&mut self) -> Result<f64, VmError> {
+        if let Some(bits) = self.decoded_operand() {
+            return Ok(f64::from_bits(bits));
+        }
+        match self.current_code()? {
             Code::Real(val) => {
                 self.pc += 1;
-                val
+                Ok(val)
             }
-            _ => panic!("Expected a real, but got {} at {}", self.code[self.pc], self.pc),
+            _ => Err(self.unexpected_code(OperandKind::Real.description())),
+        }
+    }
+
+    /// How debug output refers to `reg`: by its `.reg` alias if it has one, or as `rN` otherwise.
+    fn reg_name(&self, reg: usize) -> String {
+        match self.symbols.register_name(reg as u8) {
+            Some(name) => name.to_string(),
+            None => format!("r{}", reg),
+        }
+    }
+
+    fn render_code(&self, start: usize, end: usize) -> String {
+        self.code[start..end.min(self.code.len())]
+            .iter()
+            .map(|code| match code {
+                // the bold opcode would reset the error color halfway through the message
+                Code::Op(op) => op.to_string(),
+                _ => code.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// The index of the stack cell `offset` away from `sp`, which like for LOAD and STORE has to be
+    /// one that's in use (i.e. below `sp`).
+    fn sp_relative(&self, op: OpCode, offset: i64) -> Result<usize, VmError> {
+        self.stack_relative(op, self.sp, offset)
+    }
+
+    /// Same as `sp_relative`, but for the cell `offset` away from `base`.
+    fn stack_relative(&self, op: OpCode, base: usize, offset: i64) -> Result<usize, VmError> {
+        let idx = (base as i64).saturating_add(offset);
+        if idx < 0 || idx as usize >= self.sp {
+            return Err(VmError::StackIndexOutOfBounds(op, idx));
+        }
+        Ok(idx as usize)
+    }
+
+    fn overflow_context(&self) -> OverflowContext {
+        // by the time an overflow is detected the instruction was fully consumed,
+        // so it spans from `op_pc` up to the current `pc`
+        let instruction = self.render_code(self.op_pc, self.pc);
+        let stack_top = self.sp.saturating_sub(OVERFLOW_CONTEXT_ENTRIES);
+        let call_stack_top = self.csp.saturating_sub(OVERFLOW_CONTEXT_ENTRIES);
+
+        OverflowContext {
+            pc: self.op_pc,
+            instruction,
+            stack_top: self.stack[stack_top..self.sp].to_vec(),
+            call_stack_top: self.call_stack[call_stack_top..self.csp].to_vec(),
         }
     }
 
-    fn step(&mut self) -> Result<StepResult, String> {
-        // At one point I changed this to instead of continuously checking and trying to
-        // propagate errors if `code` was incorrectly built (e.g. SET is not actually followed
-        // by an integer and register, respectively). However, both parsing and deserialization
-        // are well tested so I simplified things to just panic b/c it should never happen for
-        // regular usage anyway.
+    fn stack_overflow_error(&self) -> VmError {
+        VmError::StackOverflow(self.overflow_context())
+    }
+
+    fn step(&mut self) -> Result<StepResult, VmError> {
+        // Parsing and deserialization are well tested so `code` being incorrectly built (e.g. SET
+        // not actually being followed by an integer and a register) should never happen for
+        // regular usage. Still, `code` can be built by hand through the library, so instead of
+        // panicking we report it as a `VmError::UnexpectedCode`.
+
+        if let Some(limit) = self.step_limit {
+            if self.steps >= limit {
+                return Err(VmError::StepLimitExceeded(limit));
+            }
+        }
+        self.steps += 1;
+        self.op_pc = self.pc;
 
         let mut res = StepResult {
             continue_running: true,
+            yielded: false,
             output: None,
         };
 
-        match self.consume_op() {
+        // decoded instructions skip going through the code (and checking it) operand by operand
+        let op = match self.decoded.get(self.pc).copied().flatten() {
+            Some(instruction) => {
+                self.operands = instruction.args;
+                self.next_operand = Some(0);
+                self.pc += 1;
+                instruction.op
+            }
+            None => {
+                self.next_operand = None;
+                self.consume_op()?
+            }
+        };
+        self.op_counts[op as usize] += 1;
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer(self.op_pc, op);
+        }
+
+        match op {
             OpCode::HALT => {
+                self.halted = true;
                 res.continue_running = false;
                 Ok(res)
             }
             OpCode::SET => {
-                let val = self.consume_int();
-                let reg = self.consume_reg();
+                let val = self.consume_int()?;
+                let reg = self.consume_reg()?;
                 self.regs[reg] = val;
                 Ok(res)
             }
             OpCode::SETF => {
-                let val = self.consume_real();
-                let reg = self.consume_reg();
+                let val = self.consume_real()?;
+                let reg = self.consume_reg()?;
                 let val = f2i(val);
                 self.regs[reg] = val;
                 Ok(res)
             }
             OpCode::MOV => {
-                let reg0 = self.consume_reg();
-                let reg1 = self.consume_reg();
+                let reg0 = self.consume_reg()?;
+                let reg1 = self.consume_reg()?;
                 self.regs[reg1] = self.regs[reg0];
                 Ok(res)
             }
             OpCode::PUSH => {
-                let reg = self.consume_reg();
-                if self.sp >= STACK_SIZE {
-                    return Err(err!("Stack overflow"));
+                let reg = self.consume_reg()?;
+                if self.sp >= self.config.stack_size {
+                    return Err(self.stack_overflow_error());
                 }
                 self.stack[self.sp] = self.regs[reg];
                 self.sp += 1;
                 Ok(res)
             }
             OpCode::PUSHL => {
-                let val = self.consume_int();
-                if self.sp >= STACK_SIZE {
-                    return Err(err!("Stack overflow"));
+                let val = self.consume_int()?;
+                if self.sp >= self.config.stack_size {
+                    return Err(self.stack_overflow_error());
                 }
                 self.stack[self.sp] = val;
                 self.sp += 1;
                 Ok(res)
             }
             OpCode::POP => {
-                let reg = self.consume_reg();
+                let reg = self.consume_reg()?;
                 if self.sp == 0 {
-                    return Err(err!("Stack underflow"));
+                    return Err(VmError::StackUnderflow);
                 }
                 self.sp -= 1;
                 self.regs[reg] = self.stack[self.sp];
                 Ok(res)
             }
             OpCode::PUSHRF => {
-                let frame_size = self.consume_int();
-                // validate that the value is actually between 1 and NUM_REGISTERS-1
-                if frame_size < 1 || frame_size as usize >= NUM_REGISTERS {
-                    return Err(err!("PUSHRF received a register frame size of {} out of bounds", frame_size));
+                let frame_size = self.consume_int()?;
+                // validate that the value is actually between 1 and num_registers-1
+                if frame_size < 1 || frame_size as usize >= self.config.num_registers {
+                    return Err(VmError::FrameSizeOutOfBounds(OpCode::PUSHRF, frame_size));
                 }
                 let frame_size = frame_size as usize;
                 // validate we indeed have "frame_size" free spaces on stack
-                if self.sp + frame_size >= STACK_SIZE {
-                    return Err(err!("PUSHRF {}: stack overflow", frame_size));
+                if self.sp + frame_size >= self.config.stack_size {
+                    return Err(VmError::FrameStackOverflow(OpCode::PUSHRF, frame_size, self.overflow_context()));
                 }
                 // push the first `frame_size` registers from lowest to highest
                 for reg in 0..frame_size {
@@ -198,15 +1008,15 @@ impl VM {
                 Ok(res)
             }
             OpCode::POPRF => {
-                let frame_size = self.consume_int();
-                // validate that the value is actually between 1 and NUM_REGISTERS-1
-                if frame_size < 1 || frame_size as usize >= NUM_REGISTERS {
-                    return Err(err!("POPRF received a register frame size of {} out of bounds", frame_size));
+                let frame_size = self.consume_int()?;
+                // validate that the value is actually between 1 and num_registers-1
+                if frame_size < 1 || frame_size as usize >= self.config.num_registers {
+                    return Err(VmError::FrameSizeOutOfBounds(OpCode::POPRF, frame_size));
                 }
                 let frame_size = frame_size as usize;
                 // validate we indeed have "frame_size" filled spaces on stack
                 if self.sp < frame_size {
-                    return Err(err!("POPRF {}: stack underflow", frame_size));
+                    return Err(VmError::FrameStackUnderflow(OpCode::POPRF, frame_size));
                 }
                 // pop the first `frame_size` registers from highest to lowest (opposite of PUSHRF)
                 for reg in (0..frame_size).rev() {
@@ -216,84 +1026,84 @@ impl VM {
                 Ok(res)
             }
             OpCode::ADD => {
-                let reg0 = self.consume_reg();
-                let reg1 = self.consume_reg();
-                self.regs[reg1] += self.regs[reg0];
+                let reg0 = self.consume_reg()?;
+                let reg1 = self.consume_reg()?;
+                self.regs[reg1] = self.int_arith(OpCode::ADD, INT_ADD, self.regs[reg1], self.regs[reg0])?;
                 Ok(res)
             }
             OpCode::ADDL => {
-                let val = self.consume_int();
-                let reg = self.consume_reg();
-                self.regs[reg] += val;
+                let val = self.consume_int()?;
+                let reg = self.consume_reg()?;
+                self.regs[reg] = self.int_arith(OpCode::ADDL, INT_ADD, self.regs[reg], val)?;
                 Ok(res)
             }
             OpCode::SUB => {
-                let reg0 = self.consume_reg();
-                let reg1 = self.consume_reg();
-                self.regs[reg1] -= self.regs[reg0];
+                let reg0 = self.consume_reg()?;
+                let reg1 = self.consume_reg()?;
+                self.regs[reg1] = self.int_arith(OpCode::SUB, INT_SUB, self.regs[reg1], self.regs[reg0])?;
                 Ok(res)
             }
             OpCode::SUBL => {
-                let val = self.consume_int();
-                let reg = self.consume_reg();
-                self.regs[reg] -= val;
+                let val = self.consume_int()?;
+                let reg = self.consume_reg()?;
+                self.regs[reg] = self.int_arith(OpCode::SUBL, INT_SUB, self.regs[reg], val)?;
                 Ok(res)
             }
             OpCode::SUB2L => {
-                let val = self.consume_int();
-                let reg = self.consume_reg();
-                self.regs[reg] = val - self.regs[reg];
+                let val = self.consume_int()?;
+                let reg = self.consume_reg()?;
+                self.regs[reg] = self.int_arith(OpCode::SUB2L, INT_SUB, val, self.regs[reg])?;
                 Ok(res)
             }
             OpCode::MUL => {
-                let reg0 = self.consume_reg();
-                let reg1 = self.consume_reg();
-                self.regs[reg1] *= self.regs[reg0];
+                let reg0 = self.consume_reg()?;
+                let reg1 = self.consume_reg()?;
+                self.regs[reg1] = self.int_arith(OpCode::MUL, INT_MUL, self.regs[reg1], self.regs[reg0])?;
                 Ok(res)
             }
             OpCode::MULL => {
-                let val = self.consume_int();
-                let reg = self.consume_reg();
-                self.regs[reg] *= val;
+                let val = self.consume_int()?;
+                let reg = self.consume_reg()?;
+                self.regs[reg] = self.int_arith(OpCode::MULL, INT_MUL, self.regs[reg], val)?;
                 Ok(res)
             }
             OpCode::DIV => {
-                let reg0 = self.consume_reg();
-                let reg1 = self.consume_reg();
-                self.regs[reg1] /= self.regs[reg0];
+                let reg0 = self.consume_reg()?;
+                let reg1 = self.consume_reg()?;
+                self.regs[reg1] = int_div(OpCode::DIV, self.regs[reg1], self.regs[reg0])?;
                 Ok(res)
             }
             OpCode::DIVL => {
-                let val = self.consume_int();
-                let reg = self.consume_reg();
-                self.regs[reg] /= val;
+                let val = self.consume_int()?;
+                let reg = self.consume_reg()?;
+                self.regs[reg] = int_div(OpCode::DIVL, self.regs[reg], val)?;
                 Ok(res)
             }
             OpCode::DIV2L => {
-                let val = self.consume_int();
-                let reg = self.consume_reg();
-                self.regs[reg] = val / self.regs[reg];
+                let val = self.consume_int()?;
+                let reg = self.consume_reg()?;
+                self.regs[reg] = int_div(OpCode::DIV2L, val, self.regs[reg])?;
                 Ok(res)
             }
             OpCode::MOD => {
-                let reg0 = self.consume_reg();
-                let reg1 = self.consume_reg();
-                self.regs[reg1] %= self.regs[reg0];
+                let reg0 = self.consume_reg()?;
+                let reg1 = self.consume_reg()?;
+                self.regs[reg1] = int_rem(OpCode::MOD, self.regs[reg1], self.regs[reg0])?;
                 Ok(res)
             }
             OpCode::INC => {
-                let reg = self.consume_reg();
-                self.regs[reg] += 1;
+                let reg = self.consume_reg()?;
+                self.regs[reg] = self.int_arith(OpCode::INC, INT_ADD, self.regs[reg], 1)?;
                 Ok(res)
             }
             OpCode::DEC => {
-                let reg = self.consume_reg();
-                self.regs[reg] -= 1;
+                let reg = self.consume_reg()?;
+                self.regs[reg] = self.int_arith(OpCode::DEC, INT_SUB, self.regs[reg], 1)?;
                 Ok(res)
             }
             OpCode::ADDF => {
-                let reg0 = self.consume_reg();
-                let reg1 = self.consume_reg();
+                let reg0 = self.consume_reg()?;
+                let reg1 = self.consume_reg()?;
 
                 let val1 = i2f(self.regs[reg0]);
                 let val2 = i2f(self.regs[reg1]);
@@ -304,8 +1114,8 @@ impl VM {
                 Ok(res)
             }
             OpCode::ADDFL => {
-                let val = self.consume_real();
-                let reg = self.consume_reg();
+                let val = self.consume_real()?;
+                let reg = self.consume_reg()?;
 
                 let val1 = val;
                 let val2 = i2f(self.regs[reg]);
@@ -316,8 +1126,8 @@ impl VM {
                 Ok(res)
             }
             OpCode::SUBF => {
-                let reg0 = self.consume_reg();
-                let reg1 = self.consume_reg();
+                let reg0 = self.consume_reg()?;
+                let reg1 = self.consume_reg()?;
 
                 let val1 = i2f(self.regs[reg0]);
                 let val2 = i2f(self.regs[reg1]);
@@ -328,8 +1138,8 @@ impl VM {
                 Ok(res)
             }
             OpCode::SUBFL => {
-                let val = self.consume_real();
-                let reg = self.consume_reg();
+                let val = self.consume_real()?;
+                let reg = self.consume_reg()?;
 
                 let val1 = val;
                 let val2 = i2f(self.regs[reg]);
@@ -340,8 +1150,8 @@ impl VM {
                 Ok(res)
             }
             OpCode::SUBF2L => {
-                let val = self.consume_real();
-                let reg = self.consume_reg();
+                let val = self.consume_real()?;
+                let reg = self.consume_reg()?;
 
                 let val1 = val;
                 let val2 = i2f(self.regs[reg]);
@@ -352,8 +1162,8 @@ impl VM {
                 Ok(res)
             }
             OpCode::MULF => {
-                let reg0 = self.consume_reg();
-                let reg1 = self.consume_reg();
+                let reg0 = self.consume_reg()?;
+                let reg1 = self.consume_reg()?;
 
                 let val1 = i2f(self.regs[reg0]);
                 let val2 = i2f(self.regs[reg1]);
@@ -364,8 +1174,8 @@ impl VM {
                 Ok(res)
             }
             OpCode::MULFL => {
-                let val = self.consume_real();
-                let reg = self.consume_reg();
+                let val = self.consume_real()?;
+                let reg = self.consume_reg()?;
 
                 let val1 = val;
                 let val2 = i2f(self.regs[reg]);
@@ -376,8 +1186,8 @@ impl VM {
                 Ok(res)
             }
             OpCode::DIVF => {
-                let reg0 = self.consume_reg();
-                let reg1 = self.consume_reg();
+                let reg0 = self.consume_reg()?;
+                let reg1 = self.consume_reg()?;
 
                 let val1 = i2f(self.regs[reg0]);
                 let val2 = i2f(self.regs[reg1]);
@@ -388,8 +1198,8 @@ impl VM {
                 Ok(res)
             }
             OpCode::DIVFL => {
-                let val = self.consume_real();
-                let reg = self.consume_reg();
+                let val = self.consume_real()?;
+                let reg = self.consume_reg()?;
 
                 let val1 = val;
                 let val2 = i2f(self.regs[reg]);
@@ -400,8 +1210,8 @@ impl VM {
                 Ok(res)
             }
             OpCode::DIVF2L => {
-                let val = self.consume_real();
-                let reg = self.consume_reg();
+                let val = self.consume_real()?;
+                let reg = self.consume_reg()?;
 
                 let val1 = val;
                 let val2 = i2f(self.regs[reg]);
@@ -412,8 +1222,8 @@ impl VM {
                 Ok(res)
             }
             OpCode::POW => {
-                let reg0 = self.consume_reg();
-                let reg1 = self.consume_reg();
+                let reg0 = self.consume_reg()?;
+                let reg1 = self.consume_reg()?;
 
                 let val1 = i2f(self.regs[reg0]);
                 let val2 = i2f(self.regs[reg1]);
@@ -424,8 +1234,8 @@ impl VM {
                 Ok(res)
             }
             OpCode::POW2 => {
-                let reg0 = self.consume_reg();
-                let reg1 = self.consume_reg();
+                let reg0 = self.consume_reg()?;
+                let reg1 = self.consume_reg()?;
 
                 let val1 = i2f(self.regs[reg0]);
                 let val2 = i2f(self.regs[reg1]);
@@ -436,8 +1246,8 @@ impl VM {
                 Ok(res)
             }
             OpCode::POWL => {
-                let val = self.consume_real();
-                let reg0 = self.consume_reg();
+                let val = self.consume_real()?;
+                let reg0 = self.consume_reg()?;
 
                 let val1 = val;
                 let val2 = i2f(self.regs[reg0]);
@@ -448,8 +1258,8 @@ impl VM {
                 Ok(res)
             }
             OpCode::POW2L => {
-                let val = self.consume_real();
-                let reg0 = self.consume_reg();
+                let val = self.consume_real()?;
+                let reg0 = self.consume_reg()?;
 
                 let val1 = val;
                 let val2 = i2f(self.regs[reg0]);
@@ -460,30 +1270,30 @@ impl VM {
                 Ok(res)
             }
             OpCode::CEIL => {
-                let reg = self.consume_reg();
+                let reg = self.consume_reg()?;
 
                 let val = i2f(self.regs[reg]);
-                if val > std::i64::MAX as f64 || val < std::i64::MIN as f64 {
-                    return Err(err!("CEIL overflow"));
+                if val > i64::MAX as f64 || val < i64::MIN as f64 {
+                    return Err(VmError::ConversionOverflow(OpCode::CEIL));
                 }
 
                 self.regs[reg] = val.ceil() as i64;
                 Ok(res)
             }
             OpCode::FLOR => {
-                let reg = self.consume_reg();
+                let reg = self.consume_reg()?;
 
                 let val = i2f(self.regs[reg]);
-                if val > std::i64::MAX as f64 || val < std::i64::MIN as f64 {
-                    return Err(err!("FLOR overflow"));
+                if val > i64::MAX as f64 || val < i64::MIN as f64 {
+                    return Err(VmError::ConversionOverflow(OpCode::FLOR));
                 }
 
                 self.regs[reg] = val.floor() as i64;
                 Ok(res)
             }
             OpCode::CMP => {
-                let reg0 = self.consume_reg();
-                let reg1 = self.consume_reg();
+                let reg0 = self.consume_reg()?;
+                let reg1 = self.consume_reg()?;
                 self.cmp = match self.regs[reg1].cmp(&self.regs[reg0]) {
                     std::cmp::Ordering::Less => -1,
                     std::cmp::Ordering::Equal => 0,
@@ -492,8 +1302,8 @@ impl VM {
                 Ok(res)
             }
             OpCode::CMPL => {
-                let val = self.consume_int();
-                let reg = self.consume_reg();
+                let val = self.consume_int()?;
+                let reg = self.consume_reg()?;
                 // Set the flag to -1, 0, or 1 depending on the comparison result.
                 self.cmp = match self.regs[reg].cmp(&val) {
                     std::cmp::Ordering::Less => -1,
@@ -502,139 +1312,774 @@ impl VM {
                 };
                 Ok(res)
             }
-            OpCode::JMP => {
-                let addr = self.consume_addr();
-                self.pc = addr;
-                Ok(res)
-            }
-            OpCode::JEQ => {
-                let addr = self.consume_addr();
-                if self.cmp == 0 {
-                    self.pc = addr;
-                }
-                Ok(res)
-            }
-            OpCode::JLT => {
-                let addr = self.consume_addr();
-                if self.cmp == -1 {
-                    self.pc = addr;
-                }
-                Ok(res)
-            }
-            OpCode::JLE => {
-                let addr = self.consume_addr();
-                if self.cmp <= 0 {
-                    self.pc = addr;
-                }
+            OpCode::CMPF => {
+                let reg0 = self.consume_reg()?;
+                let reg1 = self.consume_reg()?;
+                self.cmp = match i2f(self.regs[reg1]).partial_cmp(&i2f(self.regs[reg0])) {
+                    Some(std::cmp::Ordering::Less) => -1,
+                    Some(std::cmp::Ordering::Equal) => 0,
+                    Some(std::cmp::Ordering::Greater) => 1,
+                    None => CMP_UNORDERED,
+                };
                 Ok(res)
             }
-            OpCode::JGT => {
-                let addr = self.consume_addr();
-                if self.cmp == 1 {
-                    self.pc = addr;
-                }
+            OpCode::JMP => {
+                let addr = self.consume_addr()?;
+                self.jump_to(addr)?;
                 Ok(res)
             }
-            OpCode::JGE => {
-                let addr = self.consume_addr();
-                if self.cmp >= 0 {
-                    self.pc = addr;
+            OpCode::JEQ | OpCode::JLT | OpCode::JLE | OpCode::JGT | OpCode::JGE | OpCode::JUN | OpCode::JNE => {
+                let addr = self.consume_addr()?;
+                if self.condition_holds(op) {
+                    self.jump_to(addr)?;
                 }
                 Ok(res)
             }
-            OpCode::JNE => {
-                let addr = self.consume_addr();
-                if self.cmp != 0 {
-                    self.pc = addr;
+            OpCode::CMOVEQ | OpCode::CMOVLT | OpCode::CMOVLE | OpCode::CMOVGT | OpCode::CMOVGE | OpCode::CMOVNE => {
+                let reg0 = self.consume_reg()?;
+                let reg1 = self.consume_reg()?;
+                if self.condition_holds(op) {
+                    self.regs[reg1] = self.regs[reg0];
                 }
                 Ok(res)
             }
             OpCode::CALL => {
-                let addr = self.consume_addr();
-                if self.csp >= CALL_STACK_SIZE {
-                    return Err(err!("Call stack overflow"));
+                let addr = self.consume_addr()?;
+                if self.csp >= self.config.call_stack_size {
+                    return Err(VmError::CallStackOverflow(self.overflow_context()));
                 }
-                self.call_stack[self.csp] = self.pc;
+                let return_addr = self.pc;
+                self.jump_to(addr)?;
+                self.call_stack[self.csp] = return_addr;
                 self.csp += 1;
-                self.pc = addr;
                 Ok(res)
             }
             OpCode::RET => {
                 if self.csp == 0 {
-                    return Err(err!("Call stack underflow"));
+                    return Err(VmError::CallStackUnderflow);
                 }
+                self.jump_to(self.call_stack[self.csp - 1])?;
                 self.csp -= 1;
-                self.pc = self.call_stack[self.csp];
                 Ok(res)
             }
             OpCode::DBGREG => {
-                let reg = self.consume_reg();
-                res.output = Some(dbg!("r{} = {}", reg, self.regs[reg]));
+                let reg = self.consume_reg()?;
+                res.output = Some(dbg!("{} = {}", self.reg_name(reg), self.regs[reg]));
                 Ok(res)
             }
             OpCode::DBGREGF => {
-                let reg = self.consume_reg();
+                let reg = self.consume_reg()?;
                 let val = i2f(self.regs[reg]);
-                res.output = Some(dbg!("r{} = {}", reg, val));
+                res.output = Some(dbg!("{} = {}", self.reg_name(reg), val));
                 Ok(res)
             }
             OpCode::DBGREGS => {
-                res.output = Some(dbg!("regs = {:?}", self.regs));
+                if self.symbols.registers.is_empty() {
+                    res.output = Some(dbg!("regs = {:?}", self.regs));
+                } else {
+                    // with aliases around a bare list would be hard to match up with them
+                    let regs = (0..self.regs.len())
+                        .map(|reg| format!("{} = {}", self.reg_name(reg), self.regs[reg]))
+                        .collect::<Vec<_>>();
+                    res.output = Some(dbg!("regs = [{}]", regs.join(", ")));
+                }
                 Ok(res)
             }
-        }
-    }
-
-    pub fn run(&mut self) -> Result<String, String> {
-        let mut captured_output = String::new();
-
-        loop {
-            match self.step() {
-                Ok(res) => {
-                    if let Some(output) = res.output {
-                        if !self.capture_output {
-                            println!("{}", output);
-                        } else {
-                            captured_output.push_str(&output);
-                            captured_output.push('\n');
-                        }
-                    }
-                    if !res.continue_running {
-                        return Ok(captured_output);
-                    }
+            OpCode::MOVI => {
+                let reg0 = self.consume_reg()?;
+                let reg1 = self.consume_reg()?;
+                let idx = self.regs[reg0];
+                if idx < 0 || idx as usize >= self.config.num_registers {
+                    return Err(VmError::RegisterOutOfBounds(idx));
                 }
-                Err(msg) => {
-                    return Err(msg);
+                self.regs[reg1] = self.regs[idx as usize];
+                Ok(res)
+            }
+            OpCode::MOVIS => {
+                let reg0 = self.consume_reg()?;
+                let reg1 = self.consume_reg()?;
+                let idx = self.regs[reg0];
+                if idx < 0 || idx as usize >= self.config.num_registers {
+                    return Err(VmError::RegisterOutOfBounds(idx));
                 }
+                self.regs[idx as usize] = self.regs[reg1];
+                Ok(res)
             }
-        }
-    }
-
-    pub fn debugger(&mut self) -> Result<String, String> {
-        let mut wait_for_input = true;
-        let mut allowed_to_run = false;
-        let mut breakpoints: Vec<usize> = Vec::new();
-
-        let (displayable_code, addr2idx, idx2addr) = displayable_code(&self.code);
-
-        loop {
-            // check if current PC is a breakpoint
-            if breakpoints.contains(&self.pc) {
-                if allowed_to_run {
-                    // we just hit a breakpoint, so we need to stop
-                    // if allowed_to_run was false, it means we already had hit this breakpoint
-                    // and the user is just running inspectioning commands
-                    println!("Breakpoint hit at address {}", self.pc);
+            OpCode::JEMPTY => {
+                let addr = self.consume_addr()?;
+                if self.sp == 0 {
+                    self.jump_to(addr)?;
                 }
-                wait_for_input = true;
+                Ok(res)
+            }
+            OpCode::JNEMPTY => {
+                let addr = self.consume_addr()?;
+                if self.sp != 0 {
+                    self.jump_to(addr)?;
+                }
+                Ok(res)
+            }
+            OpCode::CLAMP8 => {
+                let reg = self.consume_reg()?;
+                self.regs[reg] = self.regs[reg].clamp(u8::MIN as i64, u8::MAX as i64);
+                Ok(res)
+            }
+            OpCode::CLAMP8S => {
+                let reg = self.consume_reg()?;
+                self.regs[reg] = self.regs[reg].clamp(i8::MIN as i64, i8::MAX as i64);
+                Ok(res)
+            }
+            OpCode::TAILCALL => {
+                // the callee takes over the current frame: whatever return address is on top
+                // of the call stack is where its RET will go, so nothing needs to be pushed
+                let addr = self.consume_addr()?;
+                self.jump_to(addr)?;
+                Ok(res)
+            }
+            OpCode::LOAD => {
+                let reg0 = self.consume_reg()?;
+                let reg1 = self.consume_reg()?;
+                let idx = self.regs[reg0];
+                // only the cells currently in use (i.e. below `sp`) can be addressed
+                if idx < 0 || idx as usize >= self.sp {
+                    return Err(VmError::StackIndexOutOfBounds(OpCode::LOAD, idx));
+                }
+                self.regs[reg1] = self.stack[idx as usize];
+                Ok(res)
+            }
+            OpCode::STORE => {
+                let reg0 = self.consume_reg()?;
+                let reg1 = self.consume_reg()?;
+                let idx = self.regs[reg0];
+                if idx < 0 || idx as usize >= self.sp {
+                    return Err(VmError::StackIndexOutOfBounds(OpCode::STORE, idx));
+                }
+                self.stack[idx as usize] = self.regs[reg1];
+                Ok(res)
+            }
+            OpCode::CHECKSUM => {
+                let reg = self.consume_reg()?;
+                // XOR doesn't depend on the order values were pushed in and an empty stack folds to 0
+                self.regs[reg] = self.stack[..self.sp].iter().fold(0, |acc, val| acc ^ val);
+                Ok(res)
+            }
+            OpCode::YIELD => {
+                res.yielded = true;
+                Ok(res)
+            }
+            OpCode::ENSURE => {
+                let lo = self.consume_int()?;
+                let hi = self.consume_int()?;
+                let reg = self.consume_reg()?;
+                let val = self.regs[reg];
+                if val < lo || val > hi {
+                    return Err(VmError::EnsureFailed { reg, val, lo, hi });
+                }
+                Ok(res)
+            }
+            OpCode::DBGTABLE => {
+                let mut table = dbg!("{:>4} | {:>20} | {}", "reg", "int", "float");
+                for (reg, val) in self.regs.iter().enumerate() {
+                    let reg = self.reg_name(reg);
+                    table.push_str(&format!("\n{:>4} | {:>20} | {}", reg, val, i2f(*val)));
+                }
+                res.output = Some(table);
+                Ok(res)
+            }
+            OpCode::OPCOUNT => {
+                let op = self.consume_int()?;
+                let reg = self.consume_reg()?;
+                if op < 0 || op as usize >= self.op_counts.len() {
+                    return Err(VmError::InvalidOpCode(op));
+                }
+                self.regs[reg] = self.op_counts[op as usize] as i64;
+                Ok(res)
+            }
+            OpCode::CLROUT => {
+                // when writing the output out there's nothing buffered to clear
+                self.captured_output.clear();
+                Ok(res)
+            }
+            OpCode::SETBITS => {
+                // the same as SET, as registers always hold raw bits, but spelled out for floats
+                let bits = self.consume_int()?;
+                let reg = self.consume_reg()?;
+                self.regs[reg] = bits;
+                Ok(res)
+            }
+            OpCode::ISNAN => {
+                let reg0 = self.consume_reg()?;
+                let reg1 = self.consume_reg()?;
+                self.regs[reg1] = i2f(self.regs[reg0]).is_nan() as i64;
+                Ok(res)
+            }
+            OpCode::FIB => {
+                let reg = self.consume_reg()?;
+                let n = self.regs[reg];
+                if n < 0 {
+                    return Err(VmError::NegativeOperand(OpCode::FIB, n));
+                }
+                // `prev` and `curr` are the (i - 1)-th and i-th numbers, stopping right at the n-th one
+                let (mut prev, mut curr) = (1i64, 0i64);
+                for _ in 0..n {
+                    let next = prev.checked_add(curr).ok_or(VmError::IntegerOverflow(OpCode::FIB))?;
+                    (prev, curr) = (curr, next);
+                }
+                self.regs[reg] = curr;
+                Ok(res)
+            }
+            OpCode::SKIPN => {
+                let count = self.consume_int()?;
+                if count < 0 {
+                    return Err(VmError::NegativeOperand(OpCode::SKIPN, count));
+                }
+                if self.cmp == 0 {
+                    self.pc = self.skip_instructions(self.pc, count as usize)?;
+                }
+                Ok(res)
+            }
+            OpCode::PRINTB => {
+                let base = self.consume_int()?;
+                let reg = self.consume_reg()?;
+                if !(2..=36).contains(&base) {
+                    return Err(VmError::InvalidBase(base));
+                }
+                let val = self.regs[reg];
+                let mut magnitude = val.unsigned_abs();
+                let mut digits = Vec::new();
+                loop {
+                    digits.push(std::char::from_digit((magnitude % base as u64) as u32, base as u32).unwrap());
+                    magnitude /= base as u64;
+                    if magnitude == 0 {
+                        break;
+                    }
+                }
+                if val < 0 {
+                    digits.push('-');
+                }
+                res.output = Some(digits.iter().rev().collect());
+                Ok(res)
+            }
+            OpCode::POPN => {
+                let count = self.consume_int()?;
+                // unlike POPRF, every register can be filled
+                if count < 1 || count as usize > self.config.num_registers {
+                    return Err(VmError::FrameSizeOutOfBounds(OpCode::POPN, count));
+                }
+                let count = count as usize;
+                if self.sp < count {
+                    return Err(VmError::FrameStackUnderflow(OpCode::POPN, count));
+                }
+                // the top of the stack goes to r0, so values pushed in order end up reversed
+                for reg in 0..count {
+                    self.sp -= 1;
+                    self.regs[reg] = self.stack[self.sp];
+                }
+                Ok(res)
+            }
+            OpCode::ITOF => {
+                let reg = self.consume_reg()?;
+                self.regs[reg] = f2i(self.regs[reg] as f64);
+                Ok(res)
+            }
+            OpCode::FTOI => {
+                let reg = self.consume_reg()?;
+
+                let val = i2f(self.regs[reg]);
+                if val > i64::MAX as f64 || val < i64::MIN as f64 {
+                    return Err(VmError::ConversionOverflow(OpCode::FTOI));
+                }
+
+                self.regs[reg] = val.trunc() as i64;
+                Ok(res)
+            }
+            OpCode::GETLIMIT => {
+                let which = self.consume_int()?;
+                let reg = self.consume_reg()?;
+
+                self.regs[reg] = match which {
+                    0 => self.step_limit.map_or(-1, |limit| limit.min(i64::MAX as u64) as i64),
+                    1 => self.config.stack_size as i64,
+                    2 => self.config.call_stack_size as i64,
+                    3 => self.config.num_registers as i64,
+                    _ => return Err(VmError::UnknownLimit(which)),
+                };
+                Ok(res)
+            }
+            OpCode::RAND => {
+                let reg = self.consume_reg()?;
+                self.regs[reg] = self.next_random() as i64;
+                Ok(res)
+            }
+            OpCode::RANDF => {
+                let reg = self.consume_reg()?;
+
+                // the top 53 bits fill a double's mantissa exactly
+                let val = (self.next_random() >> 11) as f64 / (1u64 << 53) as f64;
+                self.regs[reg] = f2i(val);
+                Ok(res)
+            }
+            OpCode::XCHG => {
+                let reg0 = self.consume_reg()?;
+                let reg1 = self.consume_reg()?;
+                self.regs.swap(reg0, reg1);
+                Ok(res)
+            }
+            OpCode::DOT => {
+                let reg0 = self.consume_reg()?;
+                let reg1 = self.consume_reg()?;
+                let len = self.consume_int()?;
+                // both windows of registers must fit in the register file
+                let num_registers = self.config.num_registers as i64;
+                if len < 1 || reg0 as i64 + len > num_registers || reg1 as i64 + len > num_registers {
+                    return Err(VmError::FrameSizeOutOfBounds(OpCode::DOT, len));
+                }
+
+                let mut sum: i64 = 0;
+                for offset in 0..len as usize {
+                    let product =
+                        self.int_arith(OpCode::DOT, INT_MUL, self.regs[reg0 + offset], self.regs[reg1 + offset])?;
+                    sum = self.int_arith(OpCode::DOT, INT_ADD, sum, product)?;
+                }
+                self.regs[reg0] = sum;
+                Ok(res)
+            }
+            OpCode::SYSCALL => {
+                let id = self.consume_int()?;
+
+                // the syscall is taken out while it runs, since it gets the whole VM to itself
+                let mut syscall = self.syscalls.remove(&id).ok_or(VmError::UnknownSyscall(id))?;
+                syscall(self);
+                // unless it registered a replacement for itself while running
+                self.syscalls.entry(id).or_insert(syscall);
+                Ok(res)
+            }
+            OpCode::RESULT => {
+                let reg = self.consume_reg()?;
+                self.result = Some(self.regs[reg]);
+                self.halted = true;
+                res.continue_running = false;
+                Ok(res)
+            }
+            OpCode::REVERSE => {
+                let reg0 = self.consume_reg()?;
+                let reg1 = self.consume_reg()?;
+                let (start, count) = (self.regs[reg0], self.regs[reg1]);
+                if count < 0 {
+                    return Err(VmError::NegativeOperand(OpCode::REVERSE, count));
+                }
+                if count == 0 {
+                    return Ok(res);
+                }
+                // like LOAD and STORE, only the cells below `sp` can be addressed
+                if start < 0 {
+                    return Err(VmError::StackIndexOutOfBounds(OpCode::REVERSE, start));
+                }
+                let last = start.saturating_add(count - 1);
+                if last as usize >= self.sp {
+                    return Err(VmError::StackIndexOutOfBounds(OpCode::REVERSE, last));
+                }
+                self.stack[start as usize..=last as usize].reverse();
+                Ok(res)
+            }
+            OpCode::BSEARCH => {
+                let reg0 = self.consume_reg()?;
+                let reg1 = self.consume_reg()?;
+                let reg2 = self.consume_reg()?;
+                let (start, count, key) = (self.regs[reg0], self.regs[reg1], self.regs[reg2]);
+                if count < 0 {
+                    return Err(VmError::NegativeOperand(OpCode::BSEARCH, count));
+                }
+                // like LOAD and STORE, only the cells below `sp` can be addressed
+                if start < 0 || (count > 0 && start as usize >= self.sp) {
+                    return Err(VmError::StackIndexOutOfBounds(OpCode::BSEARCH, start));
+                }
+                let end = start.saturating_add(count);
+                if end as usize > self.sp {
+                    return Err(VmError::StackIndexOutOfBounds(OpCode::BSEARCH, end - 1));
+                }
+
+                match self.stack[start as usize..end as usize].binary_search(&key) {
+                    Ok(idx) => {
+                        self.regs[reg0] = start + idx as i64;
+                        self.cmp = 0;
+                    }
+                    Err(_) => {
+                        self.regs[reg0] = -1;
+                        self.cmp = -1;
+                    }
+                }
+                Ok(res)
+            }
+            OpCode::HAMMING => {
+                let reg0 = self.consume_reg()?;
+                let reg1 = self.consume_reg()?;
+                self.regs[reg1] = (self.regs[reg0] ^ self.regs[reg1]).count_ones() as i64;
+                Ok(res)
+            }
+            OpCode::BREAK => {
+                // only the debugger cares about it, and it stops before getting here
+                Ok(res)
+            }
+            OpCode::NOP => Ok(res),
+            OpCode::PUSHA => {
+                // the same as PUSHRF, but for every register
+                let num_registers = self.config.num_registers;
+                if self.sp + num_registers >= self.config.stack_size {
+                    return Err(VmError::FrameStackOverflow(OpCode::PUSHA, num_registers, self.overflow_context()));
+                }
+                self.stack[self.sp..self.sp + num_registers].copy_from_slice(&self.regs);
+                self.sp += num_registers;
+                Ok(res)
+            }
+            OpCode::POPA => {
+                let num_registers = self.config.num_registers;
+                if self.sp < num_registers {
+                    return Err(VmError::FrameStackUnderflow(OpCode::POPA, num_registers));
+                }
+                self.sp -= num_registers;
+                self.regs.copy_from_slice(&self.stack[self.sp..self.sp + num_registers]);
+                Ok(res)
+            }
+            OpCode::LOADSP => {
+                let offset = self.consume_int()?;
+                let reg = self.consume_reg()?;
+                let idx = self.sp_relative(OpCode::LOADSP, offset)?;
+                self.regs[reg] = self.stack[idx];
+                Ok(res)
+            }
+            OpCode::STORESP => {
+                let offset = self.consume_int()?;
+                let reg = self.consume_reg()?;
+                let idx = self.sp_relative(OpCode::STORESP, offset)?;
+                self.stack[idx] = self.regs[reg];
+                Ok(res)
+            }
+            OpCode::ENTER => {
+                let num_locals = self.consume_int()?;
+                if num_locals < 0 {
+                    return Err(VmError::NegativeOperand(OpCode::ENTER, num_locals));
+                }
+                let num_locals = num_locals as usize;
+                // the old bp plus the locals have to fit
+                if num_locals >= self.config.stack_size - self.sp {
+                    return Err(self.stack_overflow_error());
+                }
+                self.stack[self.sp] = self.bp as i64;
+                self.sp += 1;
+                self.bp = self.sp;
+                self.stack[self.sp..self.sp + num_locals].fill(0);
+                self.sp += num_locals;
+                Ok(res)
+            }
+            OpCode::LEAVE => {
+                // the old bp is right below the frame
+                if self.bp == 0 {
+                    return Err(VmError::StackUnderflow);
+                }
+                let old_sp = self.bp - 1;
+                let old_bp = self.stack[old_sp];
+                if old_bp < 0 || old_bp as usize > old_sp {
+                    return Err(VmError::StackIndexOutOfBounds(OpCode::LEAVE, old_bp));
+                }
+                self.sp = old_sp;
+                self.bp = old_bp as usize;
+                Ok(res)
+            }
+            OpCode::LOADBP => {
+                let offset = self.consume_int()?;
+                let reg = self.consume_reg()?;
+                let idx = self.stack_relative(OpCode::LOADBP, self.bp, offset)?;
+                self.regs[reg] = self.stack[idx];
+                Ok(res)
+            }
+            OpCode::STOREBP => {
+                let offset = self.consume_int()?;
+                let reg = self.consume_reg()?;
+                let idx = self.stack_relative(OpCode::STOREBP, self.bp, offset)?;
+                self.stack[idx] = self.regs[reg];
+                Ok(res)
+            }
+            OpCode::CLRA => {
+                self.regs.fill(0);
+                Ok(res)
+            }
+            OpCode::CLR => {
+                let reg = self.consume_reg()?;
+                self.regs[reg] = 0;
+                Ok(res)
+            }
+            OpCode::POPCNT => {
+                let reg = self.consume_reg()?;
+                self.regs[reg] = (self.regs[reg] as u64).count_ones() as i64;
+                Ok(res)
+            }
+            OpCode::CLZ => {
+                let reg = self.consume_reg()?;
+                self.regs[reg] = (self.regs[reg] as u64).leading_zeros() as i64;
+                Ok(res)
+            }
+            OpCode::CTZ => {
+                let reg = self.consume_reg()?;
+                self.regs[reg] = (self.regs[reg] as u64).trailing_zeros() as i64;
+                Ok(res)
+            }
+            OpCode::SPGET => {
+                let reg = self.consume_reg()?;
+                self.regs[reg] = self.sp as i64;
+                Ok(res)
+            }
+            OpCode::CSPGET => {
+                let reg = self.consume_reg()?;
+                self.regs[reg] = self.csp as i64;
+                Ok(res)
+            }
+            OpCode::DROP => {
+                let count = self.consume_int()?;
+                if count < 0 {
+                    return Err(VmError::NegativeOperand(OpCode::DROP, count));
+                }
+                let count = count as usize;
+                if self.sp < count {
+                    return Err(VmError::FrameStackUnderflow(OpCode::DROP, count));
+                }
+                self.sp -= count;
+                Ok(res)
+            }
+            OpCode::DBGSTACK => {
+                res.output = Some(dbg!("stack = {:?}", &self.stack[..self.sp]));
+                Ok(res)
+            }
+            OpCode::DBGSTACKN => {
+                let count = self.consume_int()?;
+                if count < 0 {
+                    return Err(VmError::NegativeOperand(OpCode::DBGSTACKN, count));
+                }
+                // the indices are shown too, since only part of the stack is
+                let start = self.sp.saturating_sub(count as usize);
+                res.output = Some(dbg!("stack[{}..{}] = {:?}", start, self.sp, &self.stack[start..self.sp]));
+                Ok(res)
+            }
+        }
+    }
+
+    fn wait_for_step(&mut self) -> Result<(), VmError> {
+        // show the instruction about to run, leaving anything malformed for `step` to report
+        let num_args = match self.code.get(self.pc) {
+            Some(Code::Op(op)) => OP_ARG_TYPES[*op as usize].num_args(),
+            _ => 0,
+        };
+        let line = format!("{:04} {}", self.pc, self.render_code(self.pc, self.pc + 1 + num_args));
+        let write_op = writeln!(self.output, "{}", line).and_then(|_| self.output.flush());
+        if write_op.is_err() {
+            return Err(VmError::OutputFailed(write_op.unwrap_err().to_string()));
+        }
+
+        let mut input = String::new();
+        let read_op = self.step_input.as_mut().unwrap().read_line(&mut input);
+        if read_op.is_err() {
+            // there's no one left to press Enter, so just carry on
+            self.step_input = None;
+        }
+        Ok(())
+    }
+
+    /// The single place program output leaves the VM. Whatever an instruction printed ends up as whole
+    /// lines, i.e. with exactly one trailing newline, and the writer is flushed once after them.
+    fn emit_output(&mut self, output: &str, kind: OutputKind) -> Result<(), VmError> {
+        let output = output.trim_end_matches('\n');
+        if kind == OutputKind::Debugger || !self.capture_output {
+            let prefix = if kind == OutputKind::Debugger {
+                "PROGRAM OUTPUT> "
+            } else {
+                ""
+            };
+            let write_op = writeln!(self.output, "{}{}", prefix, output).and_then(|_| self.output.flush());
+            if write_op.is_err() {
+                return Err(VmError::OutputFailed(write_op.unwrap_err().to_string()));
+            }
+        } else {
+            self.captured_output.push_str(output);
+            self.captured_output.push('\n');
+            if let Some(limit) = self.output_limit {
+                if self.captured_output.len() > limit {
+                    return Err(VmError::OutputLimitExceeded(limit));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the program until it halts or yields. Returns the captured output (empty unless
+    /// `capture_output` is set) or the `VmError` that stopped execution, whose `Display` is the
+    /// message shown by the CLI. Use `resume()` to tell whether the program halted or yielded.
+    pub fn run(&mut self) -> Result<String, VmError> {
+        match self.resume() {
+            RunResult::Halted(output) | RunResult::Yielded(output) => Ok(output),
+            RunResult::Error(err) => Err(err),
+        }
+    }
+
+    /// Runs the program until it halts, yields or fails. After a `YIELD` calling it again continues
+    /// right after it with all state preserved, while a halted program stays halted.
+    pub fn resume(&mut self) -> RunResult {
+        if self.halted {
+            return RunResult::Halted(std::mem::take(&mut self.captured_output));
+        }
+
+        loop {
+            match self.tick() {
+                Ok(VmStatus::Running(_)) => {}
+                Ok(VmStatus::Yielded(_)) => return RunResult::Yielded(std::mem::take(&mut self.captured_output)),
+                Ok(VmStatus::Halted(_)) => return RunResult::Halted(std::mem::take(&mut self.captured_output)),
+                Err(msg) => return RunResult::Error(msg),
+            }
+        }
+    }
+
+    /// Executes a single instruction, for embedders that want to drive the VM themselves. The output
+    /// of the instruction is emitted as usual (written out or captured) and also returned. Ticking a
+    /// halted program does nothing and reports it as halted.
+    pub fn tick(&mut self) -> Result<VmStatus, VmError> {
+        if self.halted {
+            return Ok(VmStatus::Halted(None));
+        }
+
+        if self.step_input.is_some() {
+            self.wait_for_step()?;
+        }
+        let res = self.step()?;
+        if let Some(output) = &res.output {
+            self.emit_output(output, OutputKind::Run)?;
+        }
+        if !res.continue_running {
+            Ok(VmStatus::Halted(res.output))
+        } else if res.yielded {
+            Ok(VmStatus::Yielded(res.output))
+        } else {
+            Ok(VmStatus::Running(res.output))
+        }
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Executes up to `n` instructions (stopping early on a `YIELD`), returning whether the program
+    /// is still running afterwards. Output captured along the way is kept and returned by the next `run()` call.
+    pub fn run_n(&mut self, n: usize) -> Result<bool, VmError> {
+        if self.halted {
+            return Ok(false);
+        }
+
+        for _ in 0..n {
+            match self.tick()? {
+                VmStatus::Running(_) => {}
+                VmStatus::Yielded(_) => return Ok(true),
+                VmStatus::Halted(_) => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Resolves what was given to the debugger's `bp` command, which is either a numeric address or,
+    /// when there are debug symbols, a label (with sublabels written as `label.sublabel`).
+    pub fn breakpoint_addr(&self, target: &str) -> Result<usize, String> {
+        if let Ok(addr) = target.parse::<usize>() {
+            return Ok(addr);
+        }
+        if let Some(addr) = self.symbols.label_addr(target) {
+            return Ok(addr);
+        }
+        if self.symbols.labels.is_empty() {
+            return Err(format!("Unknown label {} (the program has no debug symbols)", target));
+        }
+        Err(format!("Unknown label {}", target))
+    }
+
+    /// A register given to the debugger, either as `rN` or by its `.reg` alias.
+    fn debugger_reg(&self, token: &str) -> Option<usize> {
+        let alias = self.symbols.registers.iter().find(|(name, _)| name == token);
+        let reg = match alias {
+            Some((_, reg)) => *reg as usize,
+            None => token.strip_prefix('r').and_then(|idx| idx.parse::<usize>().ok())?,
+        };
+        if reg >= self.regs.len() {
+            return None;
+        }
+        Some(reg)
+    }
+
+    /// Prints the instructions in `idxs` for the debugger, with their labels and source lines if known
+    /// and a marker on the current one.
+    fn print_code(&mut self, displayable_code: &[String], idx2addr: &HashMap<usize, usize>, idxs: Range<usize>) {
+        for idx in idxs {
+            let addr = idx2addr[&idx];
+            for label in self.symbols.labels_at(addr) {
+                writeln!(self.output, "       {}:", label).unwrap();
+            }
+
+            if addr == self.pc {
+                write!(self.output, "⇨ ").unwrap();
+            } else {
+                write!(self.output, "  ").unwrap();
+            }
+            match self.symbols.line_at(addr) {
+                Some(src_line) => {
+                    writeln!(self.output, "{:04} {} (line {})", addr, displayable_code[idx], src_line).unwrap()
+                }
+                None => writeln!(self.output, "{:04} {}", addr, displayable_code[idx]).unwrap(),
+            }
+        }
+    }
+
+    pub fn debugger(&mut self) -> Result<String, VmError> {
+        self.debugger_with_input(&mut std::io::stdin().lock())
+    }
+
+    fn debugger_with_input(&mut self, commands: &mut impl std::io::BufRead) -> Result<String, VmError> {
+        let mut wait_for_input = true;
+        let mut allowed_to_run = false;
+        let mut breakpoints: Vec<usize> = Vec::new();
+        // watched registers along with the last value seen in them
+        let mut watches: Vec<(usize, i64)> = Vec::new();
+        // the state before each of the last executed instructions, and whether it printed something
+        let mut history: std::collections::VecDeque<(VmSnapshot, bool)> = std::collections::VecDeque::new();
+
+        let listing = displayable_code(&self.code);
+        if listing.is_err() {
+            // the listing needs whole instructions, and `validate` reports what's wrong with them as a VmError
+            return Err(self.validate().expect_err("code that can't be listed is malformed"));
+        }
+        let (displayable_code, addr2idx, idx2addr) = listing.unwrap();
+
+        loop {
+            // check if current PC is a breakpoint
+            if breakpoints.contains(&self.pc) {
+                if allowed_to_run {
+                    // we just hit a breakpoint, so we need to stop
+                    // if allowed_to_run was false, it means we already had hit this breakpoint
+                    // and the user is just running inspectioning commands
+                    writeln!(self.output, "Breakpoint hit at address {}", self.pc).unwrap();
+                }
+                wait_for_input = true;
+            } else if self.code.get(self.pc) == Some(&Code::Op(OpCode::BREAK)) {
+                // a breakpoint written in the program itself
+                if allowed_to_run {
+                    writeln!(self.output, "BREAK hit at address {}", self.pc).unwrap();
+                }
+                wait_for_input = true;
             }
 
             if wait_for_input {
-                print!("> ");
-                std::io::stdout().flush().unwrap();
+                write!(self.output, "> ").unwrap();
+                self.output.flush().unwrap();
                 let input = {
                     let mut input = String::new();
-                    std::io::stdin().read_line(&mut input).unwrap();
+                    if commands.read_line(&mut input).unwrap() == 0 {
+                        // nothing else will ever be typed in
+                        return Ok("".to_string());
+                    }
                     input
                 };
 
@@ -651,7 +2096,7 @@ impl VM {
                     "r" | "regs" => {
                         allowed_to_run = false;
 
-                        println!("regs = {:?}", self.regs);
+                        writeln!(self.output, "regs = {:?}", self.regs).unwrap();
                     }
                     "st" | "stack" => {
                         allowed_to_run = false;
@@ -660,24 +2105,24 @@ impl VM {
                         let num_entries = {
                             let num_entries = tokens.next();
                             if num_entries.is_none() {
-                                println!("Expected a number of entries to print");
+                                writeln!(self.output, "Expected a number of entries to print").unwrap();
                                 continue;
                             }
                             let num_entries = num_entries.unwrap();
                             let num_entries = num_entries.parse::<usize>();
                             if num_entries.is_err() {
-                                println!("Expected a valid number of entries to print");
+                                writeln!(self.output, "Expected a valid number of entries to print").unwrap();
                                 continue;
                             }
                             num_entries.unwrap()
                         };
 
                         // print the stack with the top of stack first (i.e. in reverse order)
-                        print!("SP = {}, Stack = [", self.sp);
+                        write!(self.output, "SP = {}, Stack = [", self.sp).unwrap();
                         let num_entries = std::cmp::min(num_entries, self.sp);
 
                         if num_entries == 0 {
-                            println!("]");
+                            writeln!(self.output, "]").unwrap();
                             continue;
                         }
 
@@ -686,13 +2131,19 @@ impl VM {
                             if i == num_entries - 1 {
                                 if num_entries == self.sp {
                                     // showing all of stack, so represent this by a closed bracked
-                                    println!("{}]", self.stack[idx]);
+                                    writeln!(self.output, "{}]", self.stack[idx]).unwrap();
                                 } else {
                                     // there is stack remaining, so represent this directly
-                                    println!("{}, ...<{} hidden>]", self.stack[idx], self.sp - num_entries);
+                                    writeln!(
+                                        self.output,
+                                        "{}, ...<{} hidden>]",
+                                        self.stack[idx],
+                                        self.sp - num_entries
+                                    )
+                                    .unwrap();
                                 }
                             } else {
-                                print!("{}, ", self.stack[idx]);
+                                write!(self.output, "{}, ", self.stack[idx]).unwrap();
                             }
                         }
                     }
@@ -703,6 +2154,29 @@ impl VM {
                         allowed_to_run = true;
                         wait_for_input = false;
                     }
+                    "bs" | "back" => {
+                        allowed_to_run = false;
+
+                        let last = history.pop_back();
+                        if last.is_none() {
+                            writeln!(self.output, "Cannot step back further").unwrap();
+                            continue;
+                        }
+                        let (snapshot, printed) = last.unwrap();
+                        self.restore(&snapshot);
+                        // the instruction stepped back over is counted again once it runs again
+                        self.steps -= 1;
+                        if let Some(Code::Op(op)) = self.code.get(self.pc) {
+                            self.op_counts[*op as usize] -= 1;
+                        }
+                        for (reg, last_val) in watches.iter_mut() {
+                            *last_val = self.regs[*reg];
+                        }
+                        writeln!(self.output, "Stepped back to address {}", self.pc).unwrap();
+                        if printed {
+                            writeln!(self.output, "The output of that instruction was already printed").unwrap();
+                        }
+                    }
                     "x" | "exit" => {
                         return Ok("".to_string());
                     }
@@ -712,88 +2186,207 @@ impl VM {
                         let addr = {
                             let addr = tokens.next();
                             if addr.is_none() {
-                                println!("Expected an address");
+                                writeln!(self.output, "Expected an address").unwrap();
                                 continue;
                             }
-                            let addr = addr.unwrap();
-                            let addr = addr.parse::<usize>();
+                            let addr = self.breakpoint_addr(addr.unwrap());
                             if addr.is_err() {
-                                println!("Expected a valid address");
+                                writeln!(self.output, "{}", addr.unwrap_err()).unwrap();
                                 continue;
                             }
                             addr.unwrap()
                         };
 
                         if breakpoints.contains(&addr) {
-                            println!("Breakpoint at {} was removed", addr);
+                            writeln!(self.output, "Breakpoint at {} was removed", addr).unwrap();
                             breakpoints.retain(|&x| x != addr);
                         } else {
-                            println!("Breakpoint set at address {}", addr);
+                            writeln!(self.output, "Breakpoint set at address {}", addr).unwrap();
                             breakpoints.push(addr);
                         }
                     }
-                    "c" | "code" => {
+                    "w" | "watch" => {
                         allowed_to_run = false;
 
-                        let window_size = {
-                            let window_size = tokens.next();
-                            if window_size.is_none() {
-                                println!("Expected a window size");
+                        let reg = {
+                            let reg = tokens.next();
+                            if reg.is_none() {
+                                writeln!(self.output, "Expected a register").unwrap();
                                 continue;
                             }
-                            let window_size = window_size.unwrap();
-                            let window_size = window_size.parse::<usize>();
-                            if window_size.is_err() {
-                                println!("Expected a valid window size");
+                            let reg = self.debugger_reg(reg.unwrap());
+                            if reg.is_none() {
+                                writeln!(self.output, "Expected a valid register").unwrap();
                                 continue;
                             }
-                            window_size.unwrap()
+                            reg.unwrap()
                         };
 
-                        let current_idx = addr2idx[&self.pc];
-                        let start_idx = if current_idx >= window_size {
-                            current_idx - window_size
-                        } else {
-                            0
-                        };
-                        let end_idx = if current_idx + window_size >= displayable_code.len() {
-                            displayable_code.len()
+                        if watches.iter().any(|(watched, _)| *watched == reg) {
+                            writeln!(self.output, "Watchpoint on {} was removed", self.reg_name(reg)).unwrap();
+                            watches.retain(|(watched, _)| *watched != reg);
                         } else {
-                            current_idx + window_size
-                        };
-
-                        for (idx, line) in displayable_code[start_idx..end_idx].iter().enumerate() {
-                            if current_idx == start_idx + idx {
-                                print!("⇨ ");
+                            writeln!(self.output, "Watchpoint set on {}", self.reg_name(reg)).unwrap();
+                            watches.push((reg, self.regs[reg]));
+                        }
+                    }
+                    "set" | "setf" => {
+                        allowed_to_run = false;
+
+                        let reg = {
+                            let reg = tokens.next();
+                            if reg.is_none() {
+                                writeln!(self.output, "Expected a register").unwrap();
+                                continue;
+                            }
+                            let reg = self.debugger_reg(reg.unwrap());
+                            if reg.is_none() {
+                                writeln!(self.output, "Expected a valid register").unwrap();
+                                continue;
+                            }
+                            reg.unwrap()
+                        };
+
+                        let val = {
+                            let val = tokens.next();
+                            if val.is_none() {
+                                writeln!(self.output, "Expected a value").unwrap();
+                                continue;
+                            }
+                            let val = val.unwrap();
+                            let parsed = if cmd == "set" {
+                                parse_int_literal(val).ok()
                             } else {
-                                print!("  ");
+                                val.parse::<f64>().ok().map(f2i)
+                            };
+                            if parsed.is_none() {
+                                writeln!(self.output, "Expected a valid value but got {}", val).unwrap();
+                                continue;
+                            }
+                            parsed.unwrap()
+                        };
+
+                        self.regs[reg] = val;
+                        // the user changed it, so watchpoints shouldn't report it as the program's doing
+                        for (_, last_val) in watches.iter_mut().filter(|(watched, _)| *watched == reg) {
+                            *last_val = val;
+                        }
+                        if cmd == "set" {
+                            writeln!(self.output, "{} = {}", self.reg_name(reg), val).unwrap();
+                        } else {
+                            writeln!(self.output, "{} = {}", self.reg_name(reg), i2f(val)).unwrap();
+                        }
+                    }
+                    "bt" | "backtrace" => {
+                        allowed_to_run = false;
+
+                        // the current instruction, then where each active call will return to
+                        let frames = std::iter::once(self.pc).chain(self.call_stack[..self.csp].iter().rev().copied());
+                        for (frame, addr) in frames.enumerate() {
+                            match addr2idx.get(&addr) {
+                                Some(idx) => match self.symbols.line_at(addr) {
+                                    Some(src_line) => writeln!(
+                                        self.output,
+                                        "#{} {:04} {} (line {})",
+                                        frame, addr, displayable_code[*idx], src_line
+                                    )
+                                    .unwrap(),
+                                    None => writeln!(self.output, "#{} {:04} {}", frame, addr, displayable_code[*idx])
+                                        .unwrap(),
+                                },
+                                None => {
+                                    writeln!(self.output, "#{} {:04} <not the start of an instruction>", frame, addr)
+                                        .unwrap()
+                                }
+                            }
+                        }
+                        if self.csp == 0 {
+                            writeln!(self.output, "No active calls").unwrap();
+                        }
+                    }
+                    "c" | "code" => {
+                        allowed_to_run = false;
+
+                        let window_size = {
+                            let window_size = tokens.next();
+                            if window_size.is_none() {
+                                writeln!(self.output, "Expected a window size").unwrap();
+                                continue;
+                            }
+                            let window_size = window_size.unwrap();
+                            let window_size = window_size.parse::<usize>();
+                            if window_size.is_err() {
+                                writeln!(self.output, "Expected a valid window size").unwrap();
+                                continue;
                             }
-                            println!("{:04} {}", idx2addr[&(start_idx + idx)], line);
+                            window_size.unwrap()
+                        };
+
+                        let current_idx = addr2idx[&self.pc];
+                        let start_idx = current_idx.saturating_sub(window_size);
+                        let end_idx = if current_idx + window_size >= displayable_code.len() {
+                            displayable_code.len()
+                        } else {
+                            current_idx + window_size
+                        };
+
+                        self.print_code(&displayable_code, &idx2addr, start_idx..end_idx);
+                    }
+                    "l" | "list" => {
+                        allowed_to_run = false;
+
+                        // a window around the current instruction, from the top if it's close enough to it
+                        let current_idx = addr2idx.get(&self.pc).copied().unwrap_or(0);
+                        let end_idx = (current_idx.saturating_sub(DEBUGGER_LIST_LEN / 2) + DEBUGGER_LIST_LEN)
+                            .min(displayable_code.len());
+                        let start_idx = end_idx.saturating_sub(DEBUGGER_LIST_LEN);
+                        if start_idx > 0 {
+                            writeln!(self.output, "({} more)", start_idx).unwrap();
+                        }
+                        self.print_code(&displayable_code, &idx2addr, start_idx..end_idx);
+                        if end_idx < displayable_code.len() {
+                            writeln!(self.output, "({} more)", displayable_code.len() - end_idx).unwrap();
                         }
                     }
                     _ => {
                         allowed_to_run = false;
 
-                        println!("Unknown command: {}", cmd);
+                        writeln!(self.output, "Unknown command: {}", cmd).unwrap();
                         continue;
                     }
                 }
             }
 
             if allowed_to_run {
+                if history.len() == DEBUGGER_HISTORY_LEN {
+                    history.pop_front();
+                }
+                history.push_back((self.snapshot(), false));
+
                 match self.step() {
                     Ok(res) => {
+                        history.back_mut().unwrap().1 = res.output.is_some();
                         if let Some(output) = res.output {
-                            println!("PROGRAM OUTPUT> {}", output);
+                            self.emit_output(&output, OutputKind::Debugger)?;
                         }
                         if !res.continue_running {
-                            println!("<PROGRAM HALTED>");
+                            writeln!(self.output, "<PROGRAM HALTED>").unwrap();
                             return Ok("".to_string());
                         }
+                        // a change stops `play` just like a breakpoint would
+                        for (reg, last_val) in watches.iter_mut() {
+                            let val = self.regs[*reg];
+                            if val != *last_val {
+                                let name = self.reg_name(*reg);
+                                writeln!(self.output, "Watchpoint: {} changed {} -> {}", name, last_val, val).unwrap();
+                                *last_val = val;
+                                wait_for_input = true;
+                            }
+                        }
                     }
                     Err(msg) => {
                         // TODO: Maybe try something smart here for debugging purposes?
-                        println!("PROGRAM ERROR> {}", msg);
+                        writeln!(self.output, "PROGRAM ERROR> {}", msg).unwrap();
                         return Err(msg);
                     }
                 }
@@ -847,6 +2440,35 @@ mod tests {
         assert_eq!(vm.regs[1], 42);
     }
 
+    #[test]
+    fn test_movi_movis() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(3), Code::Reg(0),
+            Code::Op(OpCode::SET), Code::Int(42), Code::Reg(3),
+            Code::Op(OpCode::MOVI), Code::Reg(0), Code::Reg(1),
+            Code::Op(OpCode::SET), Code::Int(5), Code::Reg(0),
+            Code::Op(OpCode::MOVIS), Code::Reg(0), Code::Reg(1),
+            Code::Op(OpCode::HALT)
+        ];
+        let mut vm = VM::new(code);
+        assert!(vm.run().is_ok());
+        assert_eq!(vm.regs[1], 42);
+        assert_eq!(vm.regs[5], 42);
+    }
+
+    #[test]
+    fn test_movi_out_of_bounds() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(NUM_REGISTERS as i64), Code::Reg(0),
+            Code::Op(OpCode::MOVI), Code::Reg(0), Code::Reg(1),
+            Code::Op(OpCode::HALT)
+        ];
+        let mut vm = VM::new(code);
+        assert_eq!(vm.run(), Err(VmError::RegisterOutOfBounds(NUM_REGISTERS as i64)));
+    }
+
     #[test]
     fn test_dbgreg() {
         #[rustfmt::skip]
@@ -861,6 +2483,44 @@ mod tests {
         assert_eq!(res.unwrap(), dbg!("r0 = 42\n"));
     }
 
+    /// A writer that keeps what's written accessible after being moved into the VM.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_dbgreg_with_output() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(42), Code::Reg(0),
+            Code::Op(OpCode::DBGREG), Code::Reg(0),
+            Code::Op(OpCode::SET), Code::Int(-1), Code::Reg(1),
+            Code::Op(OpCode::DBGREG), Code::Reg(1),
+            Code::Op(OpCode::HALT)
+        ];
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::new(code).with_output(buffer.clone());
+        let res = vm.run();
+        assert!(res.is_ok());
+        assert_eq!(res.unwrap(), "");
+        assert_eq!(buffer.contents(), format!("{}\n{}\n", dbg!("r0 = 42"), dbg!("r1 = -1")));
+    }
+
     #[test]
     fn test_dbgregs() {
         #[rustfmt::skip]
@@ -886,68 +2546,1764 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
+    fn test_stack_underflow() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::POP), Code::Reg(0),
+            Code::Op(OpCode::HALT)
+        ];
+        let mut vm = VM::new(code);
+        assert_eq!(vm.run(), Err(VmError::StackUnderflow));
+    }
+
+    #[test]
+    fn test_call_stack_underflow() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::RET)
+        ];
+        let mut vm = VM::new(code);
+        assert_eq!(vm.run(), Err(VmError::CallStackUnderflow));
+    }
+
+    #[test]
+    fn test_frame_size_out_of_bounds() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::PUSHRF), Code::Int(0),
+            Code::Op(OpCode::HALT)
+        ];
+        let mut vm = VM::new(code);
+        assert_eq!(vm.run(), Err(VmError::FrameSizeOutOfBounds(OpCode::PUSHRF, 0)));
+    }
+
+    #[test]
+    fn test_fails_on_running_past_the_end() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(42), Code::Reg(0)
+        ];
+        let mut vm = VM::new(code);
+        assert_eq!(vm.run(), Err(VmError::PcOutOfBounds(3)));
+    }
+
+    #[test]
+    fn test_error_display_keeps_cli_message() {
+        assert_eq!(VmError::StackUnderflow.to_string(), "Stack underflow".to_string());
+        assert_eq!(
+            VmError::FrameStackUnderflow(OpCode::POPRF, 4).to_string(),
+            "POPRF 4: stack underflow".to_string()
+        );
+    }
+
+    #[test]
     fn test_fails_on_int_as_opcode() {
         #[rustfmt::skip]
         let code = vec![
             Code::Int(42)
         ];
         let mut vm = VM::new(code);
-        let _ = vm.run();
+        let expected_err = VmError::UnexpectedCode {
+            expected: "an opcode",
+            found: Code::Int(42),
+            addr: 0,
+        };
+        assert_eq!(vm.run(), Err(expected_err));
     }
 
     #[test]
-    #[should_panic]
     fn test_fails_on_int_as_reg() {
         #[rustfmt::skip]
         let code = vec![
             Code::Op(OpCode::SET), Code::Int(42), Code::Int(0)
         ];
         let mut vm = VM::new(code);
-        let _ = vm.run();
+        let expected_err = VmError::UnexpectedCode {
+            expected: "a register",
+            found: Code::Int(0),
+            addr: 2,
+        };
+        assert_eq!(vm.run(), Err(expected_err));
     }
 
     #[test]
-    #[should_panic]
     fn test_fails_on_reg_as_opcode() {
         #[rustfmt::skip]
         let code = vec![
             Code::Reg(0)
         ];
         let mut vm = VM::new(code);
-        let _ = vm.run();
+        let expected_err = VmError::UnexpectedCode {
+            expected: "an opcode",
+            found: Code::Reg(0),
+            addr: 0,
+        };
+        assert_eq!(vm.run(), Err(expected_err));
     }
 
     #[test]
-    #[should_panic]
     fn test_fails_on_reg_as_int() {
         #[rustfmt::skip]
         let code = vec![
             Code::Op(OpCode::SET), Code::Reg(0), Code::Reg(0)
         ];
         let mut vm = VM::new(code);
-        let _ = vm.run();
+        let expected_err = VmError::UnexpectedCode {
+            expected: "an integer",
+            found: Code::Reg(0),
+            addr: 1,
+        };
+        assert_eq!(vm.run(), Err(expected_err));
     }
 
     #[test]
-    #[should_panic]
     fn test_fails_on_op_as_int() {
         #[rustfmt::skip]
         let code = vec![
             Code::Op(OpCode::SET), Code::Op(OpCode::HALT), Code::Reg(0)
         ];
         let mut vm = VM::new(code);
-        let _ = vm.run();
+        let expected_err = VmError::UnexpectedCode {
+            expected: "an integer",
+            found: Code::Op(OpCode::HALT),
+            addr: 1,
+        };
+        assert_eq!(vm.run(), Err(expected_err));
     }
 
     #[test]
-    #[should_panic]
     fn test_fails_on_op_as_reg() {
         #[rustfmt::skip]
         let code = vec![
             Code::Op(OpCode::SET), Code::Reg(0), Code::Op(OpCode::HALT)
         ];
         let mut vm = VM::new(code);
-        let _ = vm.run();
+        assert!(matches!(vm.run(), Err(VmError::UnexpectedCode { .. })));
+    }
+
+    #[test]
+    fn test_jnempty_drains_stack() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::PUSHL), Code::Int(1),
+            Code::Op(OpCode::PUSHL), Code::Int(2),
+            Code::Op(OpCode::PUSHL), Code::Int(3),
+            Code::Op(OpCode::POP), Code::Reg(0),        // 6: loop start
+            Code::Op(OpCode::ADD), Code::Reg(0), Code::Reg(1),
+            Code::Op(OpCode::INC), Code::Reg(2),
+            Code::Op(OpCode::JNEMPTY), Code::Addr(6),
+            Code::Op(OpCode::HALT)
+        ];
+        let mut vm = VM::new(code);
+        assert!(vm.run().is_ok());
+        assert_eq!(vm.sp, 0);
+        assert_eq!(vm.regs[1], 6);
+        assert_eq!(vm.regs[2], 3);
+    }
+
+    #[test]
+    fn test_jempty() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::JEMPTY), Code::Addr(5),
+            Code::Op(OpCode::SET), Code::Int(1), Code::Reg(0),
+            Code::Op(OpCode::PUSHL), Code::Int(7),      // 5
+            Code::Op(OpCode::JEMPTY), Code::Addr(12),
+            Code::Op(OpCode::SET), Code::Int(2), Code::Reg(1),
+            Code::Op(OpCode::HALT)                      // 12
+        ];
+        let mut vm = VM::new(code);
+        assert!(vm.run().is_ok());
+        assert_eq!(vm.regs[0], 0);
+        assert_eq!(vm.regs[1], 2);
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        #[rustfmt::skip]
+        let programs = vec![
+            vec![
+                Code::Op(OpCode::SET), Code::Int(42), Code::Reg(0),
+                Code::Op(OpCode::DIV), Code::Reg(1), Code::Reg(0),
+                Code::Op(OpCode::HALT)
+            ],
+            vec![
+                Code::Op(OpCode::SET), Code::Int(42), Code::Reg(0),
+                Code::Op(OpCode::DIVL), Code::Int(0), Code::Reg(0),
+                Code::Op(OpCode::HALT)
+            ],
+            vec![
+                Code::Op(OpCode::DIV2L), Code::Int(42), Code::Reg(0),
+                Code::Op(OpCode::HALT)
+            ],
+            vec![
+                Code::Op(OpCode::SET), Code::Int(42), Code::Reg(0),
+                Code::Op(OpCode::MOD), Code::Reg(1), Code::Reg(0),
+                Code::Op(OpCode::HALT)
+            ],
+        ];
+        for code in programs {
+            let mut vm = VM::new(code);
+            assert_eq!(vm.run(), Err(VmError::DivisionByZero));
+        }
+    }
+
+    #[test]
+    fn test_division_overflow() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(i64::MIN), Code::Reg(0),
+            Code::Op(OpCode::DIVL), Code::Int(-1), Code::Reg(0),
+            Code::Op(OpCode::HALT)
+        ];
+        let mut vm = VM::new(code);
+        assert_eq!(vm.run(), Err(VmError::IntegerOverflow(OpCode::DIVL)));
+
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(i64::MIN), Code::Reg(0),
+            Code::Op(OpCode::SET), Code::Int(-1), Code::Reg(1),
+            Code::Op(OpCode::MOD), Code::Reg(1), Code::Reg(0),
+            Code::Op(OpCode::HALT)
+        ];
+        let mut vm = VM::new(code);
+        assert_eq!(vm.run(), Err(VmError::IntegerOverflow(OpCode::MOD)));
+    }
+
+    #[test]
+    fn test_clamp8() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(-5), Code::Reg(0),
+            Code::Op(OpCode::SET), Code::Int(100), Code::Reg(1),
+            Code::Op(OpCode::SET), Code::Int(300), Code::Reg(2),
+            Code::Op(OpCode::CLAMP8), Code::Reg(0),
+            Code::Op(OpCode::CLAMP8), Code::Reg(1),
+            Code::Op(OpCode::CLAMP8), Code::Reg(2),
+            Code::Op(OpCode::HALT)
+        ];
+        let mut vm = VM::new(code);
+        assert!(vm.run().is_ok());
+        assert_eq!(vm.regs[..3], [0, 100, 255]);
+    }
+
+    #[test]
+    fn test_clamp8s() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(-300), Code::Reg(0),
+            Code::Op(OpCode::SET), Code::Int(-5), Code::Reg(1),
+            Code::Op(OpCode::SET), Code::Int(300), Code::Reg(2),
+            Code::Op(OpCode::CLAMP8S), Code::Reg(0),
+            Code::Op(OpCode::CLAMP8S), Code::Reg(1),
+            Code::Op(OpCode::CLAMP8S), Code::Reg(2),
+            Code::Op(OpCode::HALT)
+        ];
+        let mut vm = VM::new(code);
+        assert!(vm.run().is_ok());
+        assert_eq!(vm.regs[..3], [-128, -5, 127]);
+    }
+
+    #[test]
+    fn test_with_config() {
+        let config = VmConfig {
+            num_registers: 4,
+            stack_size: 2,
+            call_stack_size: 1,
+        };
+
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(42), Code::Reg(3),
+            Code::Op(OpCode::SET), Code::Int(42), Code::Reg(5),
+            Code::Op(OpCode::HALT)
+        ];
+        let mut vm = VM::with_config(code, config);
+        assert_eq!(vm.run(), Err(VmError::RegisterOutOfBounds(5)));
+        assert_eq!(vm.get_registers(), [0, 0, 0, 42]);
+
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::PUSHL), Code::Int(1),
+            Code::Op(OpCode::PUSHL), Code::Int(2),
+            Code::Op(OpCode::PUSHL), Code::Int(3),
+            Code::Op(OpCode::HALT)
+        ];
+        let mut vm = VM::with_config(code, config);
+        assert!(matches!(vm.run(), Err(VmError::StackOverflow(_))));
+    }
+
+    #[test]
+    fn test_load_store() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(1), Code::Reg(0),
+            Code::Op(OpCode::LOAD), Code::Reg(0), Code::Reg(1),
+            Code::Op(OpCode::SET), Code::Int(7), Code::Reg(2),
+            Code::Op(OpCode::STORE), Code::Reg(0), Code::Reg(2),
+            Code::Op(OpCode::HALT)
+        ];
+        let mut vm = VM::new(code).with_data(&[5, 6]).unwrap();
+        assert!(vm.run().is_ok());
+        assert_eq!(vm.regs[1], 6);
+        assert_eq!(vm.stack[..vm.sp], [5, 7]);
+    }
+
+    #[test]
+    fn test_load_out_of_bounds() {
+        // only the cells below `sp` can be read, even though the stack is larger
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(2), Code::Reg(0),
+            Code::Op(OpCode::LOAD), Code::Reg(0), Code::Reg(1),
+            Code::Op(OpCode::HALT)
+        ];
+        let mut vm = VM::new(code).with_data(&[5, 6]).unwrap();
+        assert_eq!(vm.run(), Err(VmError::StackIndexOutOfBounds(OpCode::LOAD, 2)));
+    }
+
+    #[test]
+    fn test_data_larger_than_stack() {
+        let config = VmConfig {
+            stack_size: 2,
+            ..Default::default()
+        };
+        let vm = VM::with_config(vec![Code::Op(OpCode::HALT)], config).with_data(&[1, 2, 3]);
+        assert!(matches!(vm, Err(VmError::StackOverflow(_))));
+    }
+
+    #[test]
+    fn test_stack_overflow_diagnostics() {
+        // a loop that keeps pushing without ever popping
+        let config = VmConfig {
+            stack_size: 8,
+            ..Default::default()
+        };
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(0), Code::Reg(0),
+            Code::Op(OpCode::INC), Code::Reg(0),        // 3: loop start
+            Code::Op(OpCode::PUSH), Code::Reg(0),       // 5
+            Code::Op(OpCode::JMP), Code::Addr(3),
+        ];
+        let mut vm = VM::with_config(code, config);
+        let err = vm.run().unwrap_err();
+        assert_eq!(
+            err,
+            VmError::StackOverflow(OverflowContext {
+                pc: 5,
+                instruction: "PUSH r0".to_string(),
+                stack_top: vec![5, 6, 7, 8],
+                call_stack_top: vec![],
+            })
+        );
+
+        let msg = err.to_string();
+        assert!(msg.contains("at pc 5"));
+        assert!(msg.contains("PUSH r0"));
+    }
+
+    #[test]
+    fn test_call_stack_overflow_diagnostics() {
+        // runaway recursion
+        let config = VmConfig {
+            call_stack_size: 3,
+            ..Default::default()
+        };
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::CALL), Code::Addr(0),
+        ];
+        let mut vm = VM::with_config(code, config);
+        let err = vm.run().unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("at pc 0 (CALL addr(0)), stack top: [], call stack top: [2, 2, 2]"));
+    }
+
+    #[test]
+    fn test_checksum() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::CHECKSUM), Code::Reg(0),
+            Code::Op(OpCode::PUSHL), Code::Int(0b1100),
+            Code::Op(OpCode::PUSHL), Code::Int(0b1010),
+            Code::Op(OpCode::PUSHL), Code::Int(-1),
+            Code::Op(OpCode::CHECKSUM), Code::Reg(1),
+            Code::Op(OpCode::HALT)
+        ];
+        let mut vm = VM::new(code);
+        assert!(vm.run().is_ok());
+        assert_eq!(vm.regs[0], 0);
+        assert_eq!(vm.regs[1], !0b0110);
+        assert_eq!(vm.sp, 3);
+    }
+
+    #[test]
+    fn test_run_resumes_after_yield() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(1), Code::Reg(0),
+            Code::Op(OpCode::DBGREG), Code::Reg(0),
+            Code::Op(OpCode::YIELD),
+            Code::Op(OpCode::SET), Code::Int(2), Code::Reg(0),
+            Code::Op(OpCode::DBGREG), Code::Reg(0),
+            Code::Op(OpCode::HALT)
+        ];
+        let mut vm = VM::new(code).capture_output();
+        assert_eq!(vm.run(), Ok(dbg!("r0 = 1") + "\n"));
+        assert!(!vm.is_halted());
+        assert_eq!(vm.run(), Ok(dbg!("r0 = 2") + "\n"));
+        assert!(vm.is_halted());
+
+        // once halted, it stays halted
+        assert_eq!(vm.resume(), RunResult::Halted(String::new()));
+        assert_eq!(vm.run_n(10), Ok(false));
+    }
+
+    #[test]
+    fn test_ensure() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(10), Code::Reg(0),
+            Code::Op(OpCode::ENSURE), Code::Int(0), Code::Int(10), Code::Reg(0),
+            Code::Op(OpCode::INC), Code::Reg(0),
+            Code::Op(OpCode::ENSURE), Code::Int(0), Code::Int(10), Code::Reg(0),
+            Code::Op(OpCode::HALT)
+        ];
+        let mut vm = VM::new(code);
+        let err = vm.run().unwrap_err();
+        assert_eq!(
+            err,
+            VmError::EnsureFailed {
+                reg: 0,
+                val: 11,
+                lo: 0,
+                hi: 10
+            }
+        );
+        assert_eq!(err.to_string(), "ENSURE failed: r0 = 11 is outside [0, 10]".to_string());
+        assert_eq!(vm.pc, 13);
+    }
+
+    #[test]
+    fn test_dbgtable() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(-42), Code::Reg(1),
+            Code::Op(OpCode::SETF), Code::Real(1.5), Code::Reg(12),
+            Code::Op(OpCode::DBGTABLE),
+            Code::Op(OpCode::HALT)
+        ];
+        let mut vm = VM::new(code).capture_output();
+        let res = vm.run();
+        assert!(res.is_ok());
+
+        let res = res.unwrap();
+        let lines: Vec<&str> = res.lines().collect();
+        assert_eq!(lines.len(), 1 + NUM_REGISTERS);
+        assert_eq!(lines[0], dbg!(" reg |                  int | float"));
+        assert_eq!(lines[1], "  r0 |                    0 | 0");
+        assert_eq!(lines[2], format!("  r1 |                  -42 | {}", i2f(-42)));
+        assert_eq!(lines[13], format!(" r12 | {:>20} | 1.5", f2i(1.5)));
+    }
+
+    #[test]
+    fn test_opcount() {
+        // loops 5 times, jumping back to the start at the end of every iteration
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(5), Code::Reg(0),
+            Code::Op(OpCode::CMPL), Code::Int(0), Code::Reg(0), // 3: loop start
+            Code::Op(OpCode::JEQ), Code::Addr(12),
+            Code::Op(OpCode::DEC), Code::Reg(0),
+            Code::Op(OpCode::JMP), Code::Addr(3),
+            Code::Op(OpCode::OPCOUNT), Code::Int(OpCode::JMP as i64), Code::Reg(1),
+            Code::Op(OpCode::OPCOUNT), Code::Int(OpCode::DEC as i64), Code::Reg(2),
+            Code::Op(OpCode::HALT)
+        ];
+        let mut vm = VM::new(code);
+        assert!(vm.run().is_ok());
+        assert_eq!(vm.regs[1], 5);
+        assert_eq!(vm.regs[2], 5);
+        assert_eq!(vm.get_op_counts()[OpCode::OPCOUNT as usize], 2);
+        assert_eq!(vm.get_op_counts()[OpCode::HALT as usize], 1);
+    }
+
+    #[test]
+    fn test_opcount_invalid_opcode() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::OPCOUNT), Code::Int(-1), Code::Reg(0),
+        ];
+        let mut vm = VM::new(code);
+        assert_eq!(vm.run(), Err(VmError::InvalidOpCode(-1)));
+    }
+
+    #[test]
+    fn test_clrout() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(1), Code::Reg(0),
+            Code::Op(OpCode::DBGREG), Code::Reg(0),
+            Code::Op(OpCode::CLROUT),
+            Code::Op(OpCode::SET), Code::Int(2), Code::Reg(0),
+            Code::Op(OpCode::DBGREG), Code::Reg(0),
+            Code::Op(OpCode::HALT)
+        ];
+        let mut vm = VM::new(code.clone()).capture_output();
+        assert_eq!(vm.run(), Ok(dbg!("r0 = 2") + "\n"));
+
+        // without capturing, everything was already written out
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::new(code).with_output(buffer.clone());
+        assert_eq!(vm.run(), Ok(String::new()));
+        assert_eq!(buffer.contents(), dbg!("r0 = 1") + "\n" + &dbg!("r0 = 2") + "\n");
+    }
+
+    #[test]
+    fn test_tick() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::PUSHL), Code::Int(7),
+            Code::Op(OpCode::POP), Code::Reg(0),
+            Code::Op(OpCode::DBGREG), Code::Reg(0),
+            Code::Op(OpCode::HALT),
+        ];
+        let mut vm = VM::new(code).capture_output();
+
+        assert_eq!(vm.tick(), Ok(VmStatus::Running(None)));
+        assert_eq!((vm.pc(), vm.sp(), vm.stack()), (2, 1, &[7][..]));
+        assert_eq!(vm.tick(), Ok(VmStatus::Running(None)));
+        assert_eq!((vm.pc(), vm.sp(), vm.stack()), (4, 0, &[][..]));
+        assert_eq!(vm.tick(), Ok(VmStatus::Running(Some(dbg!("r0 = 7")))));
+        assert_eq!(vm.tick(), Ok(VmStatus::Halted(None)));
+        assert!(vm.is_halted());
+
+        // once halted, ticking doesn't run anything else
+        assert_eq!(vm.tick(), Ok(VmStatus::Halted(None)));
+        assert_eq!(vm.pc(), 7);
+        assert_eq!(vm.run(), Ok(dbg!("r0 = 7") + "\n"));
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(3), Code::Reg(0),
+            Code::Op(OpCode::PUSH), Code::Reg(0),
+            Code::Op(OpCode::CALL), Code::Addr(9),
+            Code::Op(OpCode::HALT),
+            Code::Op(OpCode::HALT),
+            Code::Op(OpCode::POP), Code::Reg(1),
+            Code::Op(OpCode::CMPL), Code::Int(0), Code::Reg(1),
+            Code::Op(OpCode::RET),
+        ];
+        let mut vm = VM::new(code);
+        vm.tick().unwrap();
+        let snap = vm.snapshot();
+
+        assert_eq!(vm.run(), Ok(String::new()));
+        assert!(vm.is_halted());
+        assert_eq!(vm.get_registers()[1], 3);
+
+        vm.restore(&snap);
+        assert!(!vm.is_halted());
+        assert_eq!(vm.snapshot(), snap);
+        assert_eq!(vm.get_registers()[..2], [3, 0]);
+        assert_eq!((vm.pc(), vm.sp(), vm.get_cmp()), (3, 0, 0));
+
+        // running again from the snapshot gets to the same place
+        assert_eq!(vm.run(), Ok(String::new()));
+        assert_eq!(vm.get_registers()[1], 3);
+        // only the cells in use are copied, however large the stacks are
+        vm.restore(&snap);
+        vm.tick().unwrap();
+        vm.tick().unwrap();
+        let snap = vm.snapshot();
+        assert_eq!((snap.stack, snap.call_stack), (vec![3], vec![7]));
+    }
+
+    #[test]
+    fn test_setbits_isnan() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SETBITS), Code::Int(0x7FF8_0000_0000_002A), Code::Reg(0),
+            Code::Op(OpCode::ISNAN), Code::Reg(0), Code::Reg(1),
+            Code::Op(OpCode::SETBITS), Code::Int(1), Code::Reg(2),
+            Code::Op(OpCode::ISNAN), Code::Reg(2), Code::Reg(3),
+            Code::Op(OpCode::HALT),
+        ];
+        let mut vm = VM::new(code);
+        assert_eq!(vm.run(), Ok(String::new()));
+        let regs = vm.get_registers();
+        assert!(i2f(regs[0]).is_nan());
+        assert_eq!(regs[0] & 0xFFFF, 0x2A); // the payload is kept
+        assert_eq!(regs[1], 1);
+        assert_eq!(i2f(regs[2]), f64::from_bits(1)); // the smallest subnormal
+        assert_eq!(regs[3], 0);
+    }
+
+    #[test]
+    fn test_debugger_step_back() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(1), Code::Reg(0),
+            Code::Op(OpCode::DBGREG), Code::Reg(0),
+            Code::Op(OpCode::INC), Code::Reg(0),
+            Code::Op(OpCode::HALT),
+        ];
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::new(code).with_output(buffer.clone());
+
+        let mut commands = "back\ns\ns\ns\nbs\nbs\nbs\nbs\ns\nx\n".as_bytes();
+        assert_eq!(vm.debugger_with_input(&mut commands), Ok(String::new()));
+        assert_eq!(vm.regs[0], 1);
+        assert_eq!(vm.pc, 3);
+        // only the last SET counts, the instructions stepped back over don't
+        assert_eq!(vm.steps, 1);
+        assert_eq!(vm.op_counts.iter().sum::<u64>(), 1);
+        assert_eq!(vm.op_counts[OpCode::SET as usize], 1);
+
+        let output = buffer.contents();
+        let printed = format!("PROGRAM OUTPUT> {}", dbg!("r0 = 1"));
+        let expected = [
+            "> Cannot step back further",
+            &format!("> > {}", printed),
+            "> > Stepped back to address 5",
+            "> Stepped back to address 3",
+            "The output of that instruction was already printed",
+            "> Stepped back to address 0",
+            "> Cannot step back further",
+            "> > ",
+        ]
+        .join("\n");
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_debugger_malformed_code() {
+        let mut vm = VM::new(vec![Code::Op(OpCode::SET)]).with_output(SharedBuffer::default());
+        let mut commands = "s\nx\n".as_bytes();
+        assert_eq!(vm.debugger_with_input(&mut commands), Err(VmError::PcOutOfBounds(1)));
+
+        let mut vm = VM::new(vec![Code::Int(1)]).with_output(SharedBuffer::default());
+        let mut commands = "s\nx\n".as_bytes();
+        assert!(matches!(
+            vm.debugger_with_input(&mut commands),
+            Err(VmError::UnexpectedCode { addr: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn test_fib() {
+        let fib = |n: i64| {
+            #[rustfmt::skip]
+            let code = vec![
+                Code::Op(OpCode::SET), Code::Int(n), Code::Reg(0),
+                Code::Op(OpCode::FIB), Code::Reg(0),
+                Code::Op(OpCode::HALT),
+            ];
+            let mut vm = VM::new(code);
+            vm.run().map(|_| vm.regs[0])
+        };
+        assert_eq!(fib(0), Ok(0));
+        assert_eq!(fib(1), Ok(1));
+        assert_eq!(fib(2), Ok(1));
+        assert_eq!(fib(20), Ok(6765));
+        // the largest one that fits in an i64
+        assert_eq!(fib(92), Ok(7540113804746346429));
+        assert_eq!(fib(93), Err(VmError::IntegerOverflow(OpCode::FIB)));
+        assert_eq!(fib(1000), Err(VmError::IntegerOverflow(OpCode::FIB)));
+        assert_eq!(fib(-1), Err(VmError::NegativeOperand(OpCode::FIB, -1)));
+    }
+
+    #[test]
+    fn test_step_by_step() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(4), Code::Reg(0),
+            Code::Op(OpCode::DBGREG), Code::Reg(0),
+            Code::Op(OpCode::HALT),
+        ];
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::new(code)
+            .with_output(buffer.clone())
+            .step_by_step_with_input("\n".as_bytes());
+        assert_eq!(vm.run(), Ok(String::new()));
+        assert!(vm.is_halted());
+        assert_eq!(
+            buffer.contents(),
+            format!("0000 SET 4i r0\n0003 DBGREG r0\n{}\n0005 HALT\n", dbg!("r0 = 4"))
+        );
+    }
+
+    #[test]
+    fn test_debugger_watch() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(1), Code::Reg(0),
+            Code::Op(OpCode::INC), Code::Reg(1),
+            Code::Op(OpCode::SET), Code::Int(1), Code::Reg(0),
+            Code::Op(OpCode::SET), Code::Int(2), Code::Reg(0),
+            Code::Op(OpCode::INC), Code::Reg(1),
+            Code::Op(OpCode::HALT),
+        ];
+        let buffer = SharedBuffer::default();
+        // watchpoints name registers by their alias when they have one
+        let symbols = DebugSymbols {
+            registers: vec![("count".to_string(), 0)],
+            ..DebugSymbols::default()
+        };
+        let mut vm = VM::new(code).with_output(buffer.clone()).with_symbols(symbols);
+
+        let mut commands = "w\nw r99\nwatch r0\np\np\nw count\np\n".as_bytes();
+        assert_eq!(vm.debugger_with_input(&mut commands), Ok(String::new()));
+        assert_eq!(vm.regs[..2], [2, 2]);
+
+        let expected = [
+            "> Expected a register",
+            "> Expected a valid register",
+            "> Watchpoint set on count",
+            "> Watchpoint: count changed 0 -> 1",
+            "> Watchpoint: count changed 1 -> 2",
+            "> Watchpoint on count was removed",
+            "> <PROGRAM HALTED>\n",
+        ]
+        .join("\n");
+        assert_eq!(buffer.contents(), expected);
+    }
+
+    #[test]
+    fn test_debugger_list() {
+        let mut code = [Code::Op(OpCode::INC), Code::Reg(0)].repeat(DEBUGGER_LIST_LEN + 2);
+        code.push(Code::Op(OpCode::HALT));
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::new(code).with_output(buffer.clone());
+
+        let mut commands = "s\nl\nx\n".as_bytes();
+        assert_eq!(vm.debugger_with_input(&mut commands), Ok(String::new()));
+
+        let output = buffer.contents();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), DEBUGGER_LIST_LEN + 2);
+        let inc = Code::Op(OpCode::INC);
+        assert_eq!(lines[0], format!("> >   0000 {} r0", inc));
+        assert_eq!(lines[1], format!("⇨ 0002 {} r0", inc));
+        assert_eq!(lines[DEBUGGER_LIST_LEN], "(3 more)");
+        assert_eq!(lines[DEBUGGER_LIST_LEN + 1], "> ");
+
+        // past the first instructions, the listing follows the current one
+        let target = DEBUGGER_LIST_LEN + 10;
+        let mut code = vec![Code::Op(OpCode::JMP), Code::Addr(2 + 2 * target)];
+        code.extend([Code::Op(OpCode::INC), Code::Reg(0)].repeat(2 * DEBUGGER_LIST_LEN));
+        code.push(Code::Op(OpCode::HALT));
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::new(code).with_output(buffer.clone());
+
+        let mut commands = "s\nl\nx\n".as_bytes();
+        assert_eq!(vm.debugger_with_input(&mut commands), Ok(String::new()));
+
+        let output = buffer.contents();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), DEBUGGER_LIST_LEN + 3);
+        assert_eq!(lines[0], "> > (43 more)");
+        assert_eq!(lines[DEBUGGER_LIST_LEN / 2 + 1], format!("⇨ {:04} {} r0", 2 + 2 * target, inc));
+        assert_eq!(lines[DEBUGGER_LIST_LEN + 1], "(23 more)");
+    }
+
+    #[test]
+    fn test_skipn() {
+        let run_with = |cmp_with: i64| {
+            #[rustfmt::skip]
+            let code = vec![
+                Code::Op(OpCode::CMPL), Code::Int(cmp_with), Code::Reg(0),
+                Code::Op(OpCode::SKIPN), Code::Int(2),
+                Code::Op(OpCode::ENSURE), Code::Int(1), Code::Int(1), Code::Reg(0),
+                Code::Op(OpCode::YIELD),
+                Code::Op(OpCode::INC), Code::Reg(1),
+                Code::Op(OpCode::HALT),
+            ];
+            let mut vm = VM::new(code);
+            vm.run().map(|_| (vm.pc, vm.regs[1]))
+        };
+        // r0 is 0, so comparing it with 0 skips both the 4-word ENSURE and the 1-word YIELD
+        assert_eq!(run_with(0), Ok((13, 1)));
+        // otherwise ENSURE runs and fails
+        assert!(matches!(run_with(5), Err(VmError::EnsureFailed { .. })));
+
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SKIPN), Code::Int(3),
+            Code::Op(OpCode::INC), Code::Reg(1),
+            Code::Op(OpCode::HALT),
+        ];
+        let mut vm = VM::new(code);
+        assert_eq!(vm.run(), Err(VmError::PcOutOfBounds(5)));
+
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SKIPN), Code::Int(-1),
+            Code::Op(OpCode::HALT),
+        ];
+        let mut vm = VM::new(code);
+        assert_eq!(vm.run(), Err(VmError::NegativeOperand(OpCode::SKIPN, -1)));
+    }
+
+    #[test]
+    fn test_debugger_set() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::DBGREG), Code::Reg(0),
+            Code::Op(OpCode::HALT),
+        ];
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::new(code).with_output(buffer.clone());
+
+        let mut commands = "set r0 0x10\nsetf r1 2.5\nset r16 1\nset r0 nope\nset r0 'A'\np\n".as_bytes();
+        assert_eq!(vm.debugger_with_input(&mut commands), Ok(String::new()));
+        assert_eq!(vm.regs[1], f2i(2.5));
+
+        let expected = [
+            "> r0 = 16",
+            "> r1 = 2.5",
+            "> Expected a valid register",
+            "> Expected a valid value but got nope",
+            "> r0 = 65",
+            &format!("> PROGRAM OUTPUT> {}", dbg!("r0 = 65")),
+            "<PROGRAM HALTED>\n",
+        ]
+        .join("\n");
+        assert_eq!(buffer.contents(), expected);
+    }
+
+    #[test]
+    fn test_debugger_backtrace() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::CALL), Code::Addr(3),
+            Code::Op(OpCode::HALT),
+            Code::Op(OpCode::CALL), Code::Addr(6),
+            Code::Op(OpCode::RET),
+            Code::Op(OpCode::RET),
+        ];
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::new(code).with_output(buffer.clone());
+        // a bogus return address, as if the call stack had been tampered with
+        vm.call_stack[2] = 4;
+
+        let mut commands = "bt\ns\ns\nbt\nx\n".as_bytes();
+        assert_eq!(vm.debugger_with_input(&mut commands), Ok(String::new()));
+        vm.csp = 3;
+        let mut commands = "bt\nx\n".as_bytes();
+        assert_eq!(vm.debugger_with_input(&mut commands), Ok(String::new()));
+
+        let call = Code::Op(OpCode::CALL);
+        let ret = Code::Op(OpCode::RET);
+        let halt = Code::Op(OpCode::HALT);
+        let expected = [
+            format!("> #0 0000 {} addr(3)", call),
+            "No active calls".to_string(),
+            format!("> > > #0 0006 {}", ret),
+            format!("#1 0005 {}", ret),
+            format!("#2 0002 {}", halt),
+            format!("> > #0 0006 {}", ret),
+            "#1 0004 <not the start of an instruction>".to_string(),
+            format!("#2 0005 {}", ret),
+            format!("#3 0002 {}", halt),
+            "> ".to_string(),
+        ]
+        .join("\n");
+        assert_eq!(buffer.contents(), expected);
+    }
+
+    #[test]
+    fn test_printb() {
+        let print_in = |val: i64, base: i64| {
+            #[rustfmt::skip]
+            let code = vec![
+                Code::Op(OpCode::SET), Code::Int(val), Code::Reg(0),
+                Code::Op(OpCode::PRINTB), Code::Int(base), Code::Reg(0),
+                Code::Op(OpCode::HALT),
+            ];
+            VM::new(code).capture_output().run()
+        };
+        assert_eq!(print_in(255, 16), Ok("ff\n".to_string()));
+        assert_eq!(print_in(255, 2), Ok("11111111\n".to_string()));
+        assert_eq!(print_in(0, 10), Ok("0\n".to_string()));
+        assert_eq!(print_in(-35, 36), Ok("-z\n".to_string()));
+        assert_eq!(print_in(i64::MIN, 16), Ok("-8000000000000000\n".to_string()));
+        assert_eq!(print_in(1, 1), Err(VmError::InvalidBase(1)));
+        assert_eq!(print_in(1, 37), Err(VmError::InvalidBase(37)));
+    }
+
+    #[test]
+    fn test_invalid_jumps() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::JMP), Code::Addr(100),
+            Code::Op(OpCode::HALT),
+        ];
+        let mut vm = VM::new(code);
+        assert_eq!(vm.run(), Err(VmError::InvalidJump(100)));
+        assert_eq!(VmError::InvalidJump(100).to_string(), "Jump to invalid address 100".to_string());
+
+        // landing on the integer of a SET would run it as an opcode
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(0), Code::Reg(0),
+            Code::Op(OpCode::CALL), Code::Addr(1),
+            Code::Op(OpCode::HALT),
+        ];
+        let mut vm = VM::new(code);
+        assert_eq!(vm.run(), Err(VmError::InvalidJump(1)));
+        assert_eq!(vm.csp, 0);
+
+        // return addresses are checked as well
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::RET),
+            Code::Op(OpCode::HALT),
+        ];
+        let mut vm = VM::new(code);
+        vm.call_stack[0] = 2;
+        vm.csp = 1;
+        assert_eq!(vm.run(), Err(VmError::InvalidJump(2)));
+    }
+
+    #[test]
+    fn test_popn() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::PUSHL), Code::Int(10),
+            Code::Op(OpCode::PUSHL), Code::Int(20),
+            Code::Op(OpCode::PUSHL), Code::Int(30),
+            Code::Op(OpCode::PUSHL), Code::Int(40),
+            Code::Op(OpCode::PUSHL), Code::Int(50),
+            Code::Op(OpCode::POPN), Code::Int(5),
+            Code::Op(OpCode::HALT),
+        ];
+        let mut vm = VM::new(code);
+        assert_eq!(vm.run(), Ok(String::new()));
+        assert_eq!(vm.regs[..6], [50, 40, 30, 20, 10, 0]);
+        assert_eq!(vm.sp, 0);
+
+        let popn = |count: i64| {
+            #[rustfmt::skip]
+            let code = vec![
+                Code::Op(OpCode::PUSHL), Code::Int(1),
+                Code::Op(OpCode::POPN), Code::Int(count),
+                Code::Op(OpCode::HALT),
+            ];
+            VM::new(code).run()
+        };
+        assert_eq!(popn(2), Err(VmError::FrameStackUnderflow(OpCode::POPN, 2)));
+        assert_eq!(popn(0), Err(VmError::FrameSizeOutOfBounds(OpCode::POPN, 0)));
+        assert_eq!(popn(17), Err(VmError::FrameSizeOutOfBounds(OpCode::POPN, 17)));
+    }
+
+    #[test]
+    fn test_validate() {
+        // the bad register sits on a branch that is always jumped over
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::JMP), Code::Addr(5),
+            Code::Op(OpCode::SET), Code::Int(1), Code::Reg(99),
+            Code::Op(OpCode::HALT),
+        ];
+        let mut vm = VM::new(code);
+        assert_eq!(vm.validate(), Err(VmError::RegisterOutOfBounds(99)));
+        assert_eq!(vm.run(), Ok(String::new()));
+
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::JMP), Code::Addr(5),
+            Code::Op(OpCode::ADD), Code::Int(1), Code::Reg(0),
+            Code::Op(OpCode::HALT),
+        ];
+        let vm = VM::new(code);
+        assert_eq!(
+            vm.validate(),
+            Err(VmError::UnexpectedCode {
+                expected: "a register",
+                found: Code::Int(1),
+                addr: 3,
+            })
+        );
+
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(1), Code::Reg(0),
+            Code::Op(OpCode::JMP), Code::Addr(4),
+            Code::Op(OpCode::HALT),
+        ];
+        assert_eq!(VM::new(code).validate(), Err(VmError::InvalidJump(4)));
+
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(1), Code::Reg(0),
+            Code::Op(OpCode::JMP), Code::Addr(5),
+            Code::Op(OpCode::HALT),
+            Code::Op(OpCode::SET), Code::Int(1),
+        ];
+        assert_eq!(VM::new(code).validate(), Err(VmError::PcOutOfBounds(8)));
+
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SETF), Code::Real(1.5), Code::Reg(0),
+            Code::Op(OpCode::JMP), Code::Addr(5),
+            Code::Op(OpCode::HALT),
+        ];
+        assert_eq!(VM::new(code).validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_itof_ftoi() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(5), Code::Reg(0),
+            Code::Op(OpCode::ITOF), Code::Reg(0),
+            Code::Op(OpCode::SETF), Code::Real(3.9), Code::Reg(1),
+            Code::Op(OpCode::FTOI), Code::Reg(1),
+            Code::Op(OpCode::SETF), Code::Real(-3.9), Code::Reg(2),
+            Code::Op(OpCode::FTOI), Code::Reg(2),
+            Code::Op(OpCode::HALT),
+        ];
+        let mut vm = VM::new(code);
+        assert_eq!(vm.run(), Ok(String::new()));
+        assert_eq!(i2f(vm.regs[0]), 5.0);
+        assert_eq!(vm.regs[1], 3);
+        assert_eq!(vm.regs[2], -3);
+
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SETF), Code::Real(1e30), Code::Reg(0),
+            Code::Op(OpCode::FTOI), Code::Reg(0),
+            Code::Op(OpCode::HALT),
+        ];
+        let mut vm = VM::new(code);
+        assert_eq!(vm.run(), Err(VmError::ConversionOverflow(OpCode::FTOI)));
+    }
+
+    #[test]
+    fn test_getlimit() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::GETLIMIT), Code::Int(0), Code::Reg(0),
+            Code::Op(OpCode::GETLIMIT), Code::Int(1), Code::Reg(1),
+            Code::Op(OpCode::GETLIMIT), Code::Int(2), Code::Reg(2),
+            Code::Op(OpCode::GETLIMIT), Code::Int(3), Code::Reg(3),
+            Code::Op(OpCode::HALT),
+        ];
+        let config = VmConfig {
+            num_registers: 8,
+            stack_size: 64,
+            call_stack_size: 4,
+        };
+        let mut vm = VM::with_config(code.clone(), config).with_step_limit(100);
+        assert_eq!(vm.run(), Ok(String::new()));
+        assert_eq!(vm.regs[..4], [100, 64, 4, 8]);
+
+        let mut vm = VM::new(code);
+        assert_eq!(vm.run(), Ok(String::new()));
+        assert_eq!(vm.regs[0], -1);
+
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::GETLIMIT), Code::Int(4), Code::Reg(0),
+            Code::Op(OpCode::HALT),
+        ];
+        assert_eq!(VM::new(code).run(), Err(VmError::UnknownLimit(4)));
+    }
+
+    #[test]
+    fn test_rand() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::RAND), Code::Reg(0),
+            Code::Op(OpCode::RAND), Code::Reg(1),
+            Code::Op(OpCode::RANDF), Code::Reg(2),
+            Code::Op(OpCode::RANDF), Code::Reg(3),
+            Code::Op(OpCode::HALT),
+        ];
+        let run_with = |vm: VM| {
+            let mut vm = vm;
+            assert_eq!(vm.run(), Ok(String::new()));
+            vm.regs[..4].to_vec()
+        };
+
+        let first = run_with(VM::new(code.clone()).with_seed(42));
+        assert_eq!(first, run_with(VM::new(code.clone()).with_seed(42)));
+        assert_ne!(first, run_with(VM::new(code.clone()).with_seed(43)));
+        assert_ne!(first[0], first[1]);
+        for val in &first[2..] {
+            assert!((0.0..1.0).contains(&i2f(*val)));
+        }
+
+        // without a seed the sequence is still the same every time
+        assert_eq!(run_with(VM::new(code.clone())), run_with(VM::new(code.clone()).with_seed(0)));
+    }
+
+    #[test]
+    fn test_xchg() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(1), Code::Reg(0),
+            Code::Op(OpCode::SET), Code::Int(2), Code::Reg(1),
+            Code::Op(OpCode::XCHG), Code::Reg(0), Code::Reg(1),
+            Code::Op(OpCode::XCHG), Code::Reg(1), Code::Reg(1),
+            Code::Op(OpCode::HALT),
+        ];
+        let mut vm = VM::new(code);
+        assert_eq!(vm.run(), Ok(String::new()));
+        assert_eq!(vm.regs[..2], [2, 1]);
+    }
+
+    #[test]
+    fn test_dot() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(1), Code::Reg(0),
+            Code::Op(OpCode::SET), Code::Int(2), Code::Reg(1),
+            Code::Op(OpCode::SET), Code::Int(3), Code::Reg(2),
+            Code::Op(OpCode::SET), Code::Int(4), Code::Reg(8),
+            Code::Op(OpCode::SET), Code::Int(-5), Code::Reg(9),
+            Code::Op(OpCode::SET), Code::Int(6), Code::Reg(10),
+            Code::Op(OpCode::DOT), Code::Reg(0), Code::Reg(8), Code::Int(3),
+            Code::Op(OpCode::HALT),
+        ];
+        let mut vm = VM::new(code);
+        assert_eq!(vm.run(), Ok(String::new()));
+        assert_eq!(vm.regs[..3], [12, 2, 3]);
+        assert_eq!(vm.regs[8..11], [4, -5, 6]);
+
+        let dot = |reg1: u8, len: i64| {
+            #[rustfmt::skip]
+            let code = vec![
+                Code::Op(OpCode::SET), Code::Int(i64::MAX), Code::Reg(0),
+                Code::Op(OpCode::SET), Code::Int(2), Code::Reg(1),
+                Code::Op(OpCode::DOT), Code::Reg(0), Code::Reg(reg1), Code::Int(len),
+                Code::Op(OpCode::HALT),
+            ];
+            VM::new(code).run()
+        };
+        assert_eq!(dot(1, 0), Err(VmError::FrameSizeOutOfBounds(OpCode::DOT, 0)));
+        assert_eq!(dot(14, 3), Err(VmError::FrameSizeOutOfBounds(OpCode::DOT, 3)));
+    }
+
+    #[test]
+    fn test_syscall() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(21), Code::Reg(0),
+            Code::Op(OpCode::SYSCALL), Code::Int(7),
+            Code::Op(OpCode::PUSHL), Code::Int(5),
+            Code::Op(OpCode::SYSCALL), Code::Int(8),
+            Code::Op(OpCode::SYSCALL), Code::Int(7),
+            Code::Op(OpCode::HALT),
+        ];
+        let mut vm = VM::new(code.clone());
+        vm.register_syscall(7, |vm| vm.get_registers_mut()[0] *= 2);
+        vm.register_syscall(8, |vm| vm.stack_mut()[0] += 1);
+        assert_eq!(vm.run(), Ok(String::new()));
+        assert_eq!(vm.regs[0], 84);
+        assert_eq!(vm.stack(), [6]);
+
+        let mut vm = VM::new(code);
+        vm.register_syscall(7, |vm| vm.get_registers_mut()[0] *= 2);
+        assert_eq!(vm.run(), Err(VmError::UnknownSyscall(8)));
+        assert_eq!(vm.regs[0], 42);
+    }
+
+    #[test]
+    fn test_result() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(7), Code::Reg(3),
+            Code::Op(OpCode::RESULT), Code::Reg(3),
+            Code::Op(OpCode::SET), Code::Int(8), Code::Reg(3),
+            Code::Op(OpCode::HALT),
+        ];
+        let mut vm = VM::new(code);
+        assert_eq!(vm.get_result(), None);
+        assert_eq!(vm.run(), Ok(String::new()));
+        assert!(vm.is_halted());
+        assert_eq!(vm.get_result(), Some(7));
+        assert_eq!(vm.regs[3], 7);
+
+        let mut vm = VM::new(vec![Code::Op(OpCode::HALT)]);
+        assert_eq!(vm.run(), Ok(String::new()));
+        assert_eq!(vm.get_result(), None);
+    }
+
+    #[test]
+    fn test_reverse() {
+        let reverse = |start: i64, count: i64| {
+            #[rustfmt::skip]
+            let code = vec![
+                Code::Op(OpCode::PUSHL), Code::Int(1),
+                Code::Op(OpCode::PUSHL), Code::Int(2),
+                Code::Op(OpCode::PUSHL), Code::Int(3),
+                Code::Op(OpCode::PUSHL), Code::Int(4),
+                Code::Op(OpCode::PUSHL), Code::Int(5),
+                Code::Op(OpCode::SET), Code::Int(start), Code::Reg(0),
+                Code::Op(OpCode::SET), Code::Int(count), Code::Reg(1),
+                Code::Op(OpCode::REVERSE), Code::Reg(0), Code::Reg(1),
+                Code::Op(OpCode::HALT),
+            ];
+            let mut vm = VM::new(code);
+            vm.run().map(|_| vm.stack().to_vec())
+        };
+        assert_eq!(reverse(0, 5), Ok(vec![5, 4, 3, 2, 1]));
+        assert_eq!(reverse(1, 2), Ok(vec![1, 3, 2, 4, 5]));
+        assert_eq!(reverse(4, 0), Ok(vec![1, 2, 3, 4, 5]));
+        assert_eq!(reverse(3, 3), Err(VmError::StackIndexOutOfBounds(OpCode::REVERSE, 5)));
+        assert_eq!(reverse(-1, 2), Err(VmError::StackIndexOutOfBounds(OpCode::REVERSE, -1)));
+        assert_eq!(reverse(0, -1), Err(VmError::NegativeOperand(OpCode::REVERSE, -1)));
+    }
+
+    #[test]
+    fn test_tracer() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(2), Code::Reg(0),
+            Code::Op(OpCode::SUBL), Code::Int(1), Code::Reg(0),
+            Code::Op(OpCode::CMPL), Code::Int(0), Code::Reg(0),
+            Code::Op(OpCode::JGT), Code::Addr(3),
+            Code::Op(OpCode::HALT),
+        ];
+        let trace = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = trace.clone();
+        let mut vm = VM::new(code).with_tracer(move |addr, op| recorded.lock().unwrap().push((addr, op)));
+        assert_eq!(vm.run(), Ok(String::new()));
+
+        #[rustfmt::skip]
+        assert_eq!(*trace.lock().unwrap(), [
+            (0, OpCode::SET),
+            (3, OpCode::SUBL), (6, OpCode::CMPL), (9, OpCode::JGT),
+            (3, OpCode::SUBL), (6, OpCode::CMPL), (9, OpCode::JGT),
+            (11, OpCode::HALT),
+        ]);
+    }
+
+    #[test]
+    fn test_bsearch() {
+        let bsearch = |start: i64, count: i64, key: i64| {
+            #[rustfmt::skip]
+            let code = vec![
+                Code::Op(OpCode::PUSHL), Code::Int(99),
+                Code::Op(OpCode::PUSHL), Code::Int(-4),
+                Code::Op(OpCode::PUSHL), Code::Int(1),
+                Code::Op(OpCode::PUSHL), Code::Int(8),
+                Code::Op(OpCode::PUSHL), Code::Int(15),
+                Code::Op(OpCode::PUSHL), Code::Int(42),
+                Code::Op(OpCode::SET), Code::Int(start), Code::Reg(0),
+                Code::Op(OpCode::SET), Code::Int(count), Code::Reg(1),
+                Code::Op(OpCode::SET), Code::Int(key), Code::Reg(2),
+                Code::Op(OpCode::CMPL), Code::Int(1), Code::Reg(1),
+                Code::Op(OpCode::BSEARCH), Code::Reg(0), Code::Reg(1), Code::Reg(2),
+                Code::Op(OpCode::HALT),
+            ];
+            let mut vm = VM::new(code);
+            vm.run().map(|_| (vm.regs[0], vm.cmp))
+        };
+        // the sorted table is the 5 cells after the first one
+        assert_eq!(bsearch(1, 5, -4), Ok((1, 0)));
+        assert_eq!(bsearch(1, 5, 8), Ok((3, 0)));
+        assert_eq!(bsearch(1, 5, 42), Ok((5, 0)));
+        assert_eq!(bsearch(1, 5, 7), Ok((-1, -1)));
+        assert_eq!(bsearch(1, 5, 99), Ok((-1, -1)));
+        assert_eq!(bsearch(1, 0, 8), Ok((-1, -1)));
+        assert_eq!(bsearch(1, 6, 8), Err(VmError::StackIndexOutOfBounds(OpCode::BSEARCH, 6)));
+        assert_eq!(bsearch(-1, 2, 8), Err(VmError::StackIndexOutOfBounds(OpCode::BSEARCH, -1)));
+        assert_eq!(bsearch(1, -1, 8), Err(VmError::NegativeOperand(OpCode::BSEARCH, -1)));
+    }
+
+    #[test]
+    fn test_decoded_dispatch_matches_undecoded() {
+        let run = |code: Vec<Code>, decode: bool| {
+            let mut vm = VM::new(code);
+            if !decode {
+                vm.decoded = vec![None; vm.decoded.len()];
+            }
+            let result = vm.run();
+            (result, vm.regs.clone(), vm.pc, vm.profile())
+        };
+
+        let code = crate::parser::parse_file("tests/recursive_fibonacci.uvm".to_string()).unwrap();
+        let fast = run(code.clone(), true);
+        assert_eq!(fast, run(code, false));
+        assert!(fast.0.is_ok());
+        assert_eq!(fast.1[0], 6765);
+
+        // malformed instructions aren't decoded and still fail the same way
+        let code = vec![Code::Op(OpCode::INC), Code::Int(3), Code::Op(OpCode::HALT)];
+        let slow = run(code.clone(), false);
+        assert!(slow.0.is_err());
+        assert_eq!(run(code, true), slow);
+    }
+
+    #[test]
+    fn test_hamming() {
+        let hamming = |a: i64, b: i64| {
+            #[rustfmt::skip]
+            let code = vec![
+                Code::Op(OpCode::SET), Code::Int(a), Code::Reg(0),
+                Code::Op(OpCode::SET), Code::Int(b), Code::Reg(1),
+                Code::Op(OpCode::HAMMING), Code::Reg(0), Code::Reg(1),
+                Code::Op(OpCode::HALT),
+            ];
+            let mut vm = VM::new(code);
+            vm.run().map(|_| (vm.regs[0], vm.regs[1]))
+        };
+        assert_eq!(hamming(12345, 12345), Ok((12345, 0)));
+        assert_eq!(hamming(0b1010, 0b1000), Ok((0b1010, 1)));
+        assert_eq!(hamming(0, i64::MIN), Ok((0, 1)));
+        assert_eq!(hamming(0, -1), Ok((0, 64)));
+    }
+
+    #[test]
+    fn test_break() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::INC), Code::Reg(0),
+            Code::Op(OpCode::BREAK),
+            Code::Op(OpCode::DBGREG), Code::Reg(0),
+            Code::Op(OpCode::INC), Code::Reg(0),
+            Code::Op(OpCode::HALT),
+        ];
+
+        let mut vm = VM::new(code.clone()).capture_output();
+        assert_eq!(vm.run(), Ok(dbg!("r0 = 1") + "\n"));
+        assert_eq!(vm.regs[0], 2);
+
+        // under the debugger it stops right before the BREAK, and playing again goes through it
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::new(code).with_output(buffer.clone());
+        let mut commands = "p\np\n".as_bytes();
+        assert_eq!(vm.debugger_with_input(&mut commands), Ok(String::new()));
+        assert_eq!(vm.regs[0], 2);
+
+        let expected = [
+            "> BREAK hit at address 2",
+            &format!("> PROGRAM OUTPUT> {}", dbg!("r0 = 1")),
+            "<PROGRAM HALTED>\n",
+        ]
+        .join("\n");
+        assert_eq!(buffer.contents(), expected);
+    }
+
+    #[test]
+    fn test_output_limit() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::INC), Code::Reg(0),
+            Code::Op(OpCode::DBGREG), Code::Reg(0),
+            Code::Op(OpCode::JMP), Code::Addr(0),
+        ];
+        let mut vm = VM::new(code.clone()).capture_output().with_output_limit(100);
+        assert_eq!(vm.run(), Err(VmError::OutputLimitExceeded(100)));
+        assert_eq!(VmError::OutputLimitExceeded(100).to_string(), "Output limit exceeded");
+
+        // output that fits is returned as usual
+        let mut code = code;
+        code.truncate(4);
+        code.push(Code::Op(OpCode::HALT));
+        let mut vm = VM::new(code).capture_output().with_output_limit(100);
+        assert_eq!(vm.run(), Ok(dbg!("r0 = 1") + "\n"));
+    }
+
+    #[test]
+    fn test_pusha_popa() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(1), Code::Reg(0),
+            Code::Op(OpCode::SET), Code::Int(-2), Code::Reg(7),
+            Code::Op(OpCode::SET), Code::Int(3), Code::Reg(15),
+            Code::Op(OpCode::PUSHA),
+            Code::Op(OpCode::SET), Code::Int(9), Code::Reg(0),
+            Code::Op(OpCode::SET), Code::Int(9), Code::Reg(7),
+            Code::Op(OpCode::SET), Code::Int(9), Code::Reg(8),
+            Code::Op(OpCode::SET), Code::Int(9), Code::Reg(15),
+            Code::Op(OpCode::POPA),
+            Code::Op(OpCode::HALT),
+        ];
+        let mut vm = VM::new(code);
+        let expected_regs = {
+            let mut regs = vec![0; vm.regs.len()];
+            regs[0] = 1;
+            regs[7] = -2;
+            regs[15] = 3;
+            regs
+        };
+        assert_eq!(vm.run(), Ok(String::new()));
+        assert_eq!(vm.regs, expected_regs);
+        assert_eq!(vm.sp, 0);
+
+        let mut vm = VM::new(vec![Code::Op(OpCode::POPA)]);
+        let num_registers = vm.regs.len();
+        assert_eq!(vm.run(), Err(VmError::FrameStackUnderflow(OpCode::POPA, num_registers)));
+
+        let config = VmConfig {
+            stack_size: 8,
+            ..VmConfig::default()
+        };
+        let mut vm = VM::with_config(vec![Code::Op(OpCode::PUSHA)], config);
+        assert!(matches!(vm.run(), Err(VmError::FrameStackOverflow(OpCode::PUSHA, _, _))));
+    }
+
+    #[test]
+    fn test_loadsp_storesp() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::PUSHL), Code::Int(10),
+            Code::Op(OpCode::PUSHL), Code::Int(20),
+            Code::Op(OpCode::PUSHL), Code::Int(30),
+            Code::Op(OpCode::LOADSP), Code::Int(-1), Code::Reg(0),
+            Code::Op(OpCode::LOADSP), Code::Int(-3), Code::Reg(1),
+            Code::Op(OpCode::SET), Code::Int(25), Code::Reg(2),
+            Code::Op(OpCode::STORESP), Code::Int(-2), Code::Reg(2),
+            Code::Op(OpCode::HALT),
+        ];
+        let mut vm = VM::new(code);
+        assert_eq!(vm.run(), Ok(String::new()));
+        assert_eq!(vm.regs[..2], [30, 10]);
+        assert_eq!(vm.stack[..vm.sp], [10, 25, 30]);
+
+        let access = |op: OpCode, offset: i64| {
+            #[rustfmt::skip]
+            let code = vec![
+                Code::Op(OpCode::PUSHL), Code::Int(10),
+                Code::Op(op), Code::Int(offset), Code::Reg(0),
+                Code::Op(OpCode::HALT),
+            ];
+            VM::new(code).run()
+        };
+        assert_eq!(access(OpCode::LOADSP, -2), Err(VmError::StackIndexOutOfBounds(OpCode::LOADSP, -1)));
+        assert_eq!(access(OpCode::LOADSP, 0), Err(VmError::StackIndexOutOfBounds(OpCode::LOADSP, 1)));
+        assert_eq!(access(OpCode::STORESP, 5), Err(VmError::StackIndexOutOfBounds(OpCode::STORESP, 6)));
+        assert_eq!(access(OpCode::STORESP, -1), Ok(String::new()));
+    }
+
+    #[test]
+    fn test_enter_leave_errors() {
+        let run = |code: Vec<Code>| VM::new(code).run();
+
+        assert_eq!(run(vec![Code::Op(OpCode::LEAVE)]), Err(VmError::StackUnderflow));
+        assert_eq!(
+            run(vec![Code::Op(OpCode::ENTER), Code::Int(-1)]),
+            Err(VmError::NegativeOperand(OpCode::ENTER, -1))
+        );
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::ENTER), Code::Int(1),
+            Code::Op(OpCode::LOADBP), Code::Int(1), Code::Reg(0),
+        ];
+        assert_eq!(run(code), Err(VmError::StackIndexOutOfBounds(OpCode::LOADBP, 2)));
+
+        let config = VmConfig {
+            stack_size: 4,
+            ..VmConfig::default()
+        };
+        let mut vm = VM::with_config(vec![Code::Op(OpCode::ENTER), Code::Int(4)], config);
+        assert!(matches!(vm.run(), Err(VmError::StackOverflow(_))));
+
+        // a corrupted saved bp fails LEAVE without touching the frame
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(9), Code::Reg(0),
+            Code::Op(OpCode::ENTER), Code::Int(1),
+            Code::Op(OpCode::STOREBP), Code::Int(-1), Code::Reg(0),
+            Code::Op(OpCode::LEAVE),
+        ];
+        let mut vm = VM::new(code);
+        assert_eq!(vm.run(), Err(VmError::StackIndexOutOfBounds(OpCode::LEAVE, 9)));
+        assert_eq!((vm.sp(), vm.bp), (2, 1));
+    }
+
+    #[test]
+    fn test_reset() {
+        // changes the data, a register and the generator, so a second run only matches if all come back
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::SET), Code::Int(0), Code::Reg(1),
+            Code::Op(OpCode::LOAD), Code::Reg(1), Code::Reg(2),
+            Code::Op(OpCode::ADD), Code::Reg(0), Code::Reg(2),
+            Code::Op(OpCode::STORE), Code::Reg(1), Code::Reg(2),
+            Code::Op(OpCode::SET), Code::Int(9), Code::Reg(0),
+            Code::Op(OpCode::RAND), Code::Reg(3),
+            Code::Op(OpCode::DBGREG), Code::Reg(2),
+            Code::Op(OpCode::HALT)
+        ];
+        let mut vm = VM::new(code)
+            .with_registers(&[5])
+            .unwrap()
+            .with_data(&[10])
+            .unwrap()
+            .with_seed(7)
+            .capture_output();
+        let first_output = vm.run().unwrap();
+        let first_regs = vm.regs.clone();
+        assert_eq!(first_regs[2], 15);
+        assert_eq!(vm.stack[..vm.sp], [15]);
+
+        vm.reset();
+        assert_eq!(vm.pc, 0);
+        assert_eq!(vm.steps, 0);
+        assert_eq!(vm.regs[0], 5);
+        assert_eq!(vm.stack[..vm.sp], [10]);
+
+        assert_eq!(vm.run().unwrap(), first_output);
+        assert_eq!(vm.regs, first_regs);
+        assert_eq!(vm.stack[..vm.sp], [15]);
+    }
+
+    #[test]
+    fn test_wrapping_arithmetic() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::ADDL), Code::Int(1), Code::Reg(0),
+            Code::Op(OpCode::DEC), Code::Reg(1),
+            Code::Op(OpCode::MULL), Code::Int(2), Code::Reg(2),
+            Code::Op(OpCode::DOT), Code::Reg(3), Code::Reg(3), Code::Int(1),
+            Code::Op(OpCode::HALT)
+        ];
+        let mut vm = VM::new(code)
+            .with_registers(&[i64::MAX, i64::MIN, i64::MAX, i64::MAX])
+            .unwrap();
+        assert!(vm.run().is_ok());
+        assert_eq!(vm.regs[..4], [i64::MIN, i64::MAX, -2, 1]);
+    }
+
+    #[test]
+    fn test_checked_arithmetic() {
+        let overflows = [
+            (OpCode::ADD, vec![Code::Op(OpCode::ADD), Code::Reg(1), Code::Reg(0)]),
+            (OpCode::ADDL, vec![Code::Op(OpCode::ADDL), Code::Int(1), Code::Reg(0)]),
+            (OpCode::SUBL, vec![Code::Op(OpCode::SUBL), Code::Int(-1), Code::Reg(0)]),
+            (OpCode::SUB2L, vec![Code::Op(OpCode::SUB2L), Code::Int(-2), Code::Reg(0)]),
+            (OpCode::MULL, vec![Code::Op(OpCode::MULL), Code::Int(2), Code::Reg(0)]),
+            (OpCode::INC, vec![Code::Op(OpCode::INC), Code::Reg(0)]),
+            (OpCode::DOT, vec![Code::Op(OpCode::DOT), Code::Reg(0), Code::Reg(0), Code::Int(1)]),
+        ];
+        for (op, mut code) in overflows {
+            code.push(Code::Op(OpCode::HALT));
+            let mut vm = VM::new(code)
+                .with_registers(&[i64::MAX, 1])
+                .unwrap()
+                .with_checked_arithmetic();
+            let err = vm.run().unwrap_err();
+            assert_eq!(err, VmError::IntegerOverflow(op));
+            assert_eq!(err.to_string(), format!("Integer overflow in {}", op));
+        }
+
+        // results that fit are unaffected
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::ADDL), Code::Int(1), Code::Reg(0),
+            Code::Op(OpCode::DEC), Code::Reg(0),
+            Code::Op(OpCode::HALT)
+        ];
+        let mut vm = VM::new(code)
+            .with_registers(&[i64::MAX - 1])
+            .unwrap()
+            .with_checked_arithmetic();
+        assert!(vm.run().is_ok());
+        assert_eq!(vm.regs[0], i64::MAX - 1);
+    }
+
+    #[test]
+    fn test_cmpf_unordered() {
+        let jumps = [
+            (OpCode::JEQ, false),
+            (OpCode::JNE, true),
+            (OpCode::JLT, false),
+            (OpCode::JLE, false),
+            (OpCode::JGT, false),
+            (OpCode::JGE, false),
+            (OpCode::JUN, true),
+        ];
+        for (jump, taken) in jumps {
+            #[rustfmt::skip]
+            let code = vec![
+                Code::Op(OpCode::CMPF), Code::Reg(0), Code::Reg(1),
+                Code::Op(jump), Code::Addr(8),
+                Code::Op(OpCode::SET), Code::Int(1), Code::Reg(2),
+                Code::Op(OpCode::HALT)
+            ];
+            let mut vm = VM::new(code).with_registers(&[f2i(f64::NAN), f2i(1.0)]).unwrap();
+            assert!(vm.run().is_ok());
+            assert_eq!(vm.get_cmp(), CMP_UNORDERED);
+            assert_eq!(vm.regs[2] == 0, taken, "{}", jump);
+        }
+
+        // ordered floats compare like CMP does for integers
+        for (lhs, rhs, cmp) in [(1.5, 2.0, -1), (2.0, 2.0, 0), (-0.0, -1.0, 1)] {
+            #[rustfmt::skip]
+            let code = vec![
+                Code::Op(OpCode::CMPF), Code::Reg(0), Code::Reg(1),
+                Code::Op(OpCode::HALT)
+            ];
+            let mut vm = VM::new(code).with_registers(&[f2i(rhs), f2i(lhs)]).unwrap();
+            assert!(vm.run().is_ok());
+            assert_eq!(vm.get_cmp(), cmp);
+        }
+    }
+
+    #[test]
+    fn test_captured_output_lines() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::DBGREG), Code::Reg(0),
+            Code::Op(OpCode::DBGREGS),
+            Code::Op(OpCode::DBGREG), Code::Reg(1),
+            Code::Op(OpCode::DBGREGS),
+            Code::Op(OpCode::HALT)
+        ];
+        let config = VmConfig {
+            num_registers: 2,
+            ..Default::default()
+        };
+        let mut vm = VM::with_config(code, config).capture_output();
+        let output = vm.run().unwrap();
+        assert_eq!(output.matches('\n').count(), 4);
+        assert!(output.ends_with("]\n"));
+        assert!(!output.contains("\n\n"));
+    }
+
+    #[test]
+    fn test_clr() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::CLR), Code::Reg(1),
+            Code::Op(OpCode::YIELD),
+            Code::Op(OpCode::CLRA),
+            Code::Op(OpCode::HALT)
+        ];
+        let mut vm = VM::new(code).with_registers(&[1, 2, 3]).unwrap();
+        assert!(matches!(vm.resume(), RunResult::Yielded(_)));
+        assert_eq!(vm.regs[..3], [1, 0, 3]);
+        assert!(matches!(vm.resume(), RunResult::Halted(_)));
+        assert!(vm.regs.iter().all(|&reg| reg == 0));
+    }
+
+    #[test]
+    fn test_bit_counts() {
+        // zero, a single bit and all ones (i.e. -1)
+        for (val, popcnt, clz, ctz) in [(0, 0, 64, 64), (1 << 4, 1, 59, 4), (-1, 64, 0, 0), (i64::MIN, 1, 0, 63)] {
+            #[rustfmt::skip]
+            let code = vec![
+                Code::Op(OpCode::POPCNT), Code::Reg(0),
+                Code::Op(OpCode::CLZ), Code::Reg(1),
+                Code::Op(OpCode::CTZ), Code::Reg(2),
+                Code::Op(OpCode::HALT)
+            ];
+            let mut vm = VM::new(code).with_registers(&[val, val, val]).unwrap();
+            assert!(vm.run().is_ok());
+            assert_eq!(vm.regs[..3], [popcnt, clz, ctz], "{}", val);
+        }
+    }
+
+    #[test]
+    fn test_spget_and_cspget() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::PUSH), Code::Reg(0),   // 0
+            Code::Op(OpCode::PUSH), Code::Reg(0),   // 2
+            Code::Op(OpCode::PUSH), Code::Reg(0),   // 4
+            Code::Op(OpCode::SPGET), Code::Reg(1),  // 6
+            Code::Op(OpCode::CSPGET), Code::Reg(2), // 8
+            Code::Op(OpCode::CALL), Code::Addr(13), // 10
+            Code::Op(OpCode::HALT),                 // 12
+            Code::Op(OpCode::CSPGET), Code::Reg(3), // 13
+            Code::Op(OpCode::RET)
+        ];
+        let mut vm = VM::new(code).with_data(&[7, 8]).unwrap();
+        assert!(vm.run().is_ok());
+        // the data counts as stack cells in use too
+        assert_eq!(vm.regs[1..4], [5, 0, 1]);
+    }
+
+    #[test]
+    fn test_drop() {
+        let mut code = [Code::Op(OpCode::PUSH), Code::Reg(0)].repeat(5);
+        code.extend([Code::Op(OpCode::DROP), Code::Int(3), Code::Op(OpCode::HALT)]);
+        let mut vm = VM::new(code.clone()).with_registers(&[4]).unwrap();
+        assert!(vm.run().is_ok());
+        assert_eq!(vm.stack[..vm.sp], [4, 4]);
+
+        code[11] = Code::Int(6);
+        let mut vm = VM::new(code.clone());
+        assert_eq!(vm.run(), Err(VmError::FrameStackUnderflow(OpCode::DROP, 6)));
+
+        code[11] = Code::Int(-1);
+        let mut vm = VM::new(code);
+        assert_eq!(vm.run(), Err(VmError::NegativeOperand(OpCode::DROP, -1)));
+    }
+
+    #[test]
+    fn test_dbgstack() {
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::DBGSTACK),
+            Code::Op(OpCode::PUSHL), Code::Int(3),
+            Code::Op(OpCode::PUSHL), Code::Int(-1),
+            Code::Op(OpCode::PUSHL), Code::Int(8),
+            Code::Op(OpCode::DBGSTACK),
+            Code::Op(OpCode::DBGSTACKN), Code::Int(2),
+            Code::Op(OpCode::DBGSTACKN), Code::Int(5),
+            Code::Op(OpCode::DBGSTACKN), Code::Int(-2),
+        ];
+        let mut vm = VM::new(code).capture_output();
+        assert_eq!(vm.run(), Err(VmError::NegativeOperand(OpCode::DBGSTACKN, -2)));
+
+        // what was printed before the error is still kept
+        let expected = [
+            dbg!("stack = []"),
+            dbg!("stack = [3, -1, 8]"),
+            dbg!("stack[1..3] = [-1, 8]"),
+            dbg!("stack[0..3] = [3, -1, 8]"),
+        ];
+        assert_eq!(vm.captured_output, expected.join("\n") + "\n");
+    }
+
+    #[test]
+    fn test_cmov() {
+        // r2 = max(r0, r1) without branching: start with r1 and take r0 if r1 < r0
+        #[rustfmt::skip]
+        let code = vec![
+            Code::Op(OpCode::MOV), Code::Reg(1), Code::Reg(2),
+            Code::Op(OpCode::CMP), Code::Reg(0), Code::Reg(1),
+            Code::Op(OpCode::CMOVLT), Code::Reg(0), Code::Reg(2),
+            Code::Op(OpCode::HALT)
+        ];
+        for (r0, r1, max) in [(7, 3, 7), (3, 7, 7), (5, 5, 5)] {
+            let mut vm = VM::new(code.clone()).with_registers(&[r0, r1]).unwrap();
+            assert!(vm.run().is_ok());
+            assert_eq!(vm.regs[2], max);
+        }
+
+        // each condition moves exactly when the matching jump would jump
+        let moves = [
+            (OpCode::CMOVEQ, [false, true, false]),
+            (OpCode::CMOVNE, [true, false, true]),
+            (OpCode::CMOVLT, [true, false, false]),
+            (OpCode::CMOVLE, [true, true, false]),
+            (OpCode::CMOVGT, [false, false, true]),
+            (OpCode::CMOVGE, [false, true, true]),
+        ];
+        for (cmov, moved) in moves {
+            for (val, moved) in [1, 2, 3].into_iter().zip(moved) {
+                #[rustfmt::skip]
+                let code = vec![
+                    Code::Op(OpCode::CMPL), Code::Int(2), Code::Reg(0),
+                    Code::Op(cmov), Code::Reg(1), Code::Reg(2),
+                    Code::Op(OpCode::HALT)
+                ];
+                let mut vm = VM::new(code).with_registers(&[val, 9]).unwrap();
+                assert!(vm.run().is_ok());
+                assert_eq!(vm.regs[2] == 9, moved, "{} with {}", cmov, val);
+            }
+        }
     }
 }
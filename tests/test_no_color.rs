@@ -0,0 +1,18 @@
+// Kept apart from the other tests since whether colors are used is only checked once per process.
+
+#[test]
+fn no_color_turns_off_escape_codes() {
+    std::env::set_var("NO_COLOR", "1");
+
+    let messages = [
+        uvm::err!("failed"),
+        uvm::dbg!("r0 = 1"),
+        uvm::warn!("careful"),
+        uvm::info!("fyi"),
+    ];
+    assert_eq!(messages, ["[ERROR] failed", "[DEBUG] r0 = 1", "[WARN] careful", "[INFO] fyi"]);
+
+    let mut vm = uvm::vm::VM::new(uvm::load_source("SET 7 r0\nDBGREG r0\nHALT").unwrap()).capture_output();
+    let output = vm.run().unwrap();
+    assert_eq!(output, "[DEBUG] r0 = 7\n");
+}
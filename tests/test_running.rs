@@ -1,19 +1,16 @@
-use uvm;
+// See the note in lib.rs.
+#![allow(clippy::unnecessary_unwrap)]
 
 #[test]
 fn test_basic_arithmetics() {
     let code = uvm::parser::parse_file("tests/basic_arithmetic.uvm".to_string());
-    if !code.is_ok() {
-        println!("{}", code.unwrap_err());
-        assert!(false);
-        return;
+    if code.is_err() {
+        panic!("{}", code.unwrap_err());
     }
     let mut vm = uvm::vm::VM::new(code.unwrap());
     let result = vm.run();
-    if !result.is_ok() {
-        println!("{}", result.unwrap_err());
-        assert!(false);
-        return;
+    if result.is_err() {
+        panic!("{}", result.unwrap_err());
     }
     assert_eq!(vm.get_registers()[1..14], [22, 10, 50, 5, 3, 11, 9, -11, -30, 8, -8, 4, 9]);
 }
@@ -21,17 +18,57 @@ fn test_basic_arithmetics() {
 #[test]
 fn test_basic_loop() {
     let code = uvm::parser::parse_file("tests/basic_loop.uvm".to_string());
-    if !code.is_ok() {
-        println!("{}", code.unwrap_err());
-        assert!(false);
-        return;
+    if code.is_err() {
+        panic!("{}", code.unwrap_err());
     }
     let mut vm = uvm::vm::VM::new(code.unwrap());
     let result = vm.run();
-    if !result.is_ok() {
-        println!("{}", result.unwrap_err());
-        assert!(false);
-        return;
+    if result.is_err() {
+        panic!("{}", result.unwrap_err());
+    }
+    assert_eq!(vm.get_registers()[0..2], [0, 1275]);
+}
+
+#[test]
+fn test_basic_loop_partial_run() {
+    let code = uvm::parser::parse_file("tests/basic_loop.uvm".to_string());
+    if code.is_err() {
+        panic!("{}", code.unwrap_err());
+    }
+    let mut vm = uvm::vm::VM::new(code.unwrap());
+
+    // 3 setup instructions followed by 10 iterations of the 5-instruction loop
+    let still_running = vm.run_n(3 + 5 * 10);
+    if still_running.is_err() {
+        panic!("{}", still_running.unwrap_err());
+    }
+    assert!(still_running.unwrap());
+    assert_eq!(vm.get_registers()[0..2], [40, 455]);
+
+    // asking for more steps than needed stops at HALT
+    let still_running = vm.run_n(1000);
+    if still_running.is_err() {
+        panic!("{}", still_running.unwrap_err());
+    }
+    assert!(!still_running.unwrap());
+    assert_eq!(vm.get_registers()[0..2], [0, 1275]);
+}
+
+#[test]
+fn test_basic_loop_step_limit() {
+    let code = uvm::parser::parse_file("tests/basic_loop.uvm".to_string());
+    if code.is_err() {
+        panic!("{}", code.unwrap_err());
+    }
+    let code = code.unwrap();
+
+    let mut vm = uvm::vm::VM::new(code.clone()).with_step_limit(10);
+    assert_eq!(vm.run(), Err(uvm::vm::VmError::StepLimitExceeded(10)));
+
+    let mut vm = uvm::vm::VM::new(code).with_step_limit(1000).capture_output();
+    let result = vm.run();
+    if result.is_err() {
+        panic!("{}", result.unwrap_err());
     }
     assert_eq!(vm.get_registers()[0..2], [0, 1275]);
 }
@@ -39,17 +76,13 @@ fn test_basic_loop() {
 #[test]
 fn test_cmp() {
     let code = uvm::parser::parse_file("tests/cmp_test.uvm".to_string());
-    if !code.is_ok() {
-        println!("{}", code.unwrap_err());
-        assert!(false);
-        return;
+    if code.is_err() {
+        panic!("{}", code.unwrap_err());
     }
     let mut vm = uvm::vm::VM::new(code.unwrap());
     let result = vm.run();
-    if !result.is_ok() {
-        println!("{}", result.unwrap_err());
-        assert!(false);
-        return;
+    if result.is_err() {
+        panic!("{}", result.unwrap_err());
     }
     assert_eq!(vm.get_cmp(), 1);
 }
@@ -57,17 +90,13 @@ fn test_cmp() {
 #[test]
 fn test_conditional_jumps() {
     let code = uvm::parser::parse_file("tests/conditional_jump_tests.uvm".to_string());
-    if !code.is_ok() {
-        println!("{}", code.unwrap_err());
-        assert!(false);
-        return;
+    if code.is_err() {
+        panic!("{}", code.unwrap_err());
     }
     let mut vm = uvm::vm::VM::new(code.unwrap());
     let result = vm.run();
-    if !result.is_ok() {
-        println!("{}", result.unwrap_err());
-        assert!(false);
-        return;
+    if result.is_err() {
+        panic!("{}", result.unwrap_err());
     }
     assert_eq!(vm.get_registers()[7], 1);
 }
@@ -75,35 +104,35 @@ fn test_conditional_jumps() {
 #[test]
 fn test_basic_stack() {
     let code = uvm::parser::parse_file("tests/basic_stack.uvm".to_string());
-    if !code.is_ok() {
-        println!("{}", code.unwrap_err());
-        assert!(false);
-        return;
+    if code.is_err() {
+        panic!("{}", code.unwrap_err());
     }
-    let mut vm = uvm::vm::VM::new(code.unwrap());
+    let mut vm = uvm::vm::VM::new(code.unwrap()).capture_output();
+
+    // stop right after both values were pushed to look at the stack
+    for _ in 0..4 {
+        assert!(matches!(vm.tick(), Ok(uvm::vm::VmStatus::Running(_))));
+    }
+    assert_eq!((vm.sp(), vm.stack()), (2, &[10, 20][..]));
+
     let result = vm.run();
-    if !result.is_ok() {
-        println!("{}", result.unwrap_err());
-        assert!(false);
-        return;
+    if result.is_err() {
+        panic!("{}", result.unwrap_err());
     }
     assert_eq!(vm.get_registers()[..3], [0, 20, 10]);
+    assert_eq!((vm.sp(), vm.stack()), (0, &[][..]));
 }
 
 #[test]
 fn test_rf_stack_ops() {
     let code = uvm::parser::parse_file("tests/rf_push_pop.uvm".to_string());
-    if !code.is_ok() {
-        println!("{}", code.unwrap_err());
-        assert!(false);
-        return;
+    if code.is_err() {
+        panic!("{}", code.unwrap_err());
     }
     let mut vm = uvm::vm::VM::new(code.unwrap());
     let result = vm.run();
-    if !result.is_ok() {
-        println!("{}", result.unwrap_err());
-        assert!(false);
-        return;
+    if result.is_err() {
+        panic!("{}", result.unwrap_err());
     }
     assert_eq!(vm.get_registers()[..10], [10, 11, 12, 13, 14, 15, 16, 17, 0, 0]);
 }
@@ -111,37 +140,373 @@ fn test_rf_stack_ops() {
 #[test]
 fn test_fibonacci_recursion() {
     let code = uvm::parser::parse_file("tests/recursive_fibonacci.uvm".to_string());
-    if !code.is_ok() {
-        println!("{}", code.unwrap_err());
-        assert!(false);
-        return;
+    if code.is_err() {
+        panic!("{}", code.unwrap_err());
     }
     let mut vm = uvm::vm::VM::new(code.unwrap());
     let result = vm.run();
-    if !result.is_ok() {
-        println!("{}", result.unwrap_err());
-        assert!(false);
-        return;
+    if result.is_err() {
+        panic!("{}", result.unwrap_err());
     }
     assert_eq!(vm.get_registers()[0], 6765);
 }
 
 #[test]
+#[allow(clippy::approx_constant)] // 3.14 is just a literal here, not meant as PI
 fn test_basic_float_arithmetic() {
     let code = uvm::parser::parse_file("tests/basic_float_arithmetic.uvm".to_string());
-    if !code.is_ok() {
-        println!("{}", code.unwrap_err());
-        assert!(false);
-        return;
+    if code.is_err() {
+        panic!("{}", code.unwrap_err());
     }
     let mut vm = uvm::vm::VM::new(code.unwrap());
     let result = vm.run();
-    if !result.is_ok() {
-        println!("{}", result.unwrap_err());
-        assert!(false);
-        return;
+    if result.is_err() {
+        panic!("{}", result.unwrap_err());
     }
     assert_eq!(vm.get_registers_as_floats()[..5], [3.14, 10.99, 4.396, -87.92, 88.02]);
     assert_eq!(vm.get_registers()[5..9], [89, 88, 1405, 25796]);
     assert_eq!(vm.get_registers_as_floats()[9..13], [11.2, -11.2, 7.0, 0.5]);
 }
+
+#[test]
+fn test_tail_call() {
+    let code = uvm::parser::parse_file("tests/tail_call_countdown.uvm".to_string());
+    if code.is_err() {
+        panic!("{}", code.unwrap_err());
+    }
+    let code = code.unwrap();
+    let config = uvm::vm::VmConfig {
+        call_stack_size: 16,
+        ..Default::default()
+    };
+
+    let mut vm = uvm::vm::VM::with_config(code.clone(), config);
+    let result = vm.run();
+    if result.is_err() {
+        panic!("{}", result.unwrap_err());
+    }
+    assert_eq!(vm.get_registers()[0..2], [0, 101]);
+
+    // the very same program using a regular CALL for the recursion overflows the call stack
+    let tail_call = uvm::asm::Code::Op(uvm::asm::OpCode::TAILCALL);
+    let code = code
+        .into_iter()
+        .map(|c| {
+            if c == tail_call {
+                uvm::asm::Code::Op(uvm::asm::OpCode::CALL)
+            } else {
+                c
+            }
+        })
+        .collect();
+    let mut vm = uvm::vm::VM::with_config(code, config);
+    assert!(matches!(vm.run(), Err(uvm::vm::VmError::CallStackOverflow(_))));
+}
+
+#[test]
+fn test_data_section() {
+    let program = uvm::parser::parse_program("tests/data_section.uvm".to_string());
+    if program.is_err() {
+        panic!("{}", program.unwrap_err());
+    }
+    let program = program.unwrap();
+    assert_eq!(program.data, [10, 20, 12, 0]);
+
+    let mut vm = match uvm::vm::VM::from_program(program) {
+        Ok(vm) => vm,
+        Err(err) => panic!("{}", err),
+    };
+    let result = vm.run();
+    if result.is_err() {
+        panic!("{}", result.unwrap_err());
+    }
+    assert_eq!(vm.get_registers()[0..5], [3, 42, 12, 3, 42]);
+}
+
+#[test]
+fn test_include() {
+    let code = uvm::parser::parse_file("tests/include_main.uvm".to_string());
+    if code.is_err() {
+        panic!("{}", code.unwrap_err());
+    }
+    let mut vm = uvm::vm::VM::new(code.unwrap());
+    let result = vm.run();
+    if result.is_err() {
+        panic!("{}", result.unwrap_err());
+    }
+    assert_eq!(vm.get_registers()[0..2], [7, 49]);
+}
+
+#[test]
+fn test_include_errors() {
+    let code = uvm::parser::parse_file("tests/include/cycle_a.uvm".to_string());
+    assert!(code.is_err());
+    assert!(code
+        .unwrap_err()
+        .contains("cycle_b.uvm.1: Including tests/include/cycle_a.uvm would recurse"));
+
+    let code = uvm::parser::parse_source(".include \"tests/include/broken.uvm\"");
+    assert!(code.is_err());
+    assert!(code
+        .unwrap_err()
+        .contains("tests/include/broken.uvm.4:1: Expected to find an OpCode but found NOPE"));
+}
+
+#[test]
+fn test_yield_round_robin() {
+    let code = uvm::parser::parse_file("tests/yield_counter.uvm".to_string());
+    if code.is_err() {
+        panic!("{}", code.unwrap_err());
+    }
+    let code = code.unwrap();
+
+    let mut vms = [uvm::vm::VM::new(code.clone()), uvm::vm::VM::new(code)];
+    for round in 1..=5 {
+        for vm in vms.iter_mut() {
+            assert_eq!(vm.resume(), uvm::vm::RunResult::Yielded(String::new()));
+            assert_eq!(vm.get_registers()[0], round);
+        }
+    }
+    for vm in vms.iter_mut() {
+        assert_eq!(vm.resume(), uvm::vm::RunResult::Halted(String::new()));
+        assert!(vm.is_halted());
+        assert_eq!(vm.get_registers()[0], 5);
+    }
+}
+
+#[test]
+fn test_include_depth_limit() {
+    // a chain of files each including the next one, nested deeper than the default limit
+    let dir = std::env::temp_dir().join(format!("uvm_include_depth_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let depth = uvm::parser::DEFAULT_MAX_INCLUDE_DEPTH + 8;
+    for idx in 0..depth {
+        let source = format!(".include \"chain{}.uvm\"\nINC r0\n", idx + 1);
+        std::fs::write(dir.join(format!("chain{}.uvm", idx)), source).unwrap();
+    }
+    std::fs::write(dir.join(format!("chain{}.uvm", depth)), "HALT\n").unwrap();
+
+    let path = |idx: usize| dir.join(format!("chain{}.uvm", idx)).to_string_lossy().to_string();
+    let code = uvm::parser::parse_file(path(0));
+    assert!(code.is_err());
+    let expected = format!(
+        "Include depth limit exceeded including {}",
+        path(uvm::parser::DEFAULT_MAX_INCLUDE_DEPTH + 1)
+    );
+    assert!(code.unwrap_err().contains(&expected));
+
+    // the limit is inclusive and can be raised
+    let code = uvm::parser::parse_file(path(depth - uvm::parser::DEFAULT_MAX_INCLUDE_DEPTH));
+    assert!(code.is_ok());
+    let program = uvm::parser::parse_program_with_include_depth(path(0), depth);
+    assert!(program.is_ok());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_breakpoint_labels() {
+    let program = uvm::parser::parse_program("tests/factorial.uvm".to_string());
+    if program.is_err() {
+        panic!("{}", program.unwrap_err());
+    }
+    let vm = match uvm::vm::VM::from_program(program.unwrap()) {
+        Ok(vm) => vm,
+        Err(err) => panic!("{}", err),
+    };
+
+    assert_eq!(vm.breakpoint_addr("factorial"), Ok(9));
+    assert_eq!(vm.breakpoint_addr("factorial.loop"), Ok(14));
+    assert_eq!(vm.breakpoint_addr("4"), Ok(4));
+    assert_eq!(vm.breakpoint_addr("loop"), Err("Unknown label loop".to_string()));
+
+    // without debug symbols only addresses can be used
+    let vm = uvm::vm::VM::new(uvm::parser::parse_file("tests/factorial.uvm".to_string()).unwrap());
+    assert_eq!(
+        vm.breakpoint_addr("factorial"),
+        Err("Unknown label factorial (the program has no debug symbols)".to_string())
+    );
+}
+
+#[test]
+fn test_register_aliases() {
+    let program = uvm::parser::parse_program("tests/reg_aliases.uvm".to_string());
+    if program.is_err() {
+        panic!("{}", program.unwrap_err());
+    }
+    let program = program.unwrap();
+    assert_eq!(program.symbols.registers, vec![("counter".to_string(), 0), ("total".to_string(), 1)]);
+
+    let mut vm = match uvm::vm::VM::from_program(program.clone()) {
+        Ok(vm) => vm.capture_output(),
+        Err(err) => panic!("{}", err),
+    };
+    let output = vm.run();
+    assert_eq!(output, Ok(uvm::dbg!("total = 55") + "\n" + &uvm::dbg!("r2 = 0") + "\n"));
+
+    // without the symbols registers go back to their plain names
+    let mut vm = uvm::vm::VM::new(program.code).capture_output();
+    assert_eq!(vm.run(), Ok(uvm::dbg!("r1 = 55") + "\n" + &uvm::dbg!("r2 = 0") + "\n"));
+
+    let code = uvm::parser::parse_source(".reg r3 r1\nHALT");
+    assert!(code.unwrap_err().contains(".reg alias r3 would shadow a register"));
+    let code = uvm::parser::parse_source(".reg x r1\n.reg x r2\nHALT");
+    assert!(code.unwrap_err().contains("Register alias x already defined"));
+    let code = uvm::parser::parse_source(".reg x 5\nHALT");
+    assert!(code
+        .unwrap_err()
+        .contains(".reg expected to find a register for x but got 5"));
+}
+
+#[test]
+fn test_source_spans() {
+    let source = "SET 1 r0\n\n  // comment\n  CMPL 0    r0 // trailing comment\nloop:\n\tJMP loop\n";
+    let parsed = uvm::parser::parse_string_with_spans(source);
+    if parsed.is_err() {
+        panic!("{}", parsed.unwrap_err());
+    }
+    let (program, spans) = parsed.unwrap();
+    assert_eq!(spans.len(), program.code.len());
+
+    let span = |line, column| uvm::asm::SourceSpan {
+        filename: "<source>".to_string(),
+        line,
+        column,
+    };
+    assert_eq!(spans[0..3], [span(1, 1), span(1, 5), span(1, 7)]);
+    assert_eq!(spans[3..6], [span(4, 3), span(4, 8), span(4, 13)]);
+    assert_eq!(spans[6..8], [span(6, 2), span(6, 6)]);
+
+    // the VM reports where the failing instruction was written
+    let (program, spans) = uvm::parser::parse_string_with_spans("SET 0 r1\nDIV r1 r0\nHALT").unwrap();
+    let mut vm = uvm::vm::VM::new(program.code).with_spans(spans);
+    assert_eq!(vm.run(), Err(uvm::vm::VmError::DivisionByZero));
+    assert_eq!(vm.op_span(), Some(span(2, 1)));
+
+    // code from a macro points to where it was used
+    let source = ".macro BUMP x\nINC x\n.endmacro\n  BUMP r0";
+    let (_, spans) = uvm::parser::parse_string_with_spans(source).unwrap();
+    assert_eq!(spans, [span(4, 3), span(4, 3)]);
+
+    // and code from an included file to that file
+    let (_, spans) = uvm::parser::parse_program_with_spans("tests/include_main.uvm".to_string()).unwrap();
+    assert_eq!(spans[2].filename, "tests/include_main.uvm");
+    assert_eq!(spans[7].filename, "tests/include/square.uvm");
+    assert_eq!((spans[7].line, spans[7].column), (4, 2));
+}
+
+#[test]
+fn test_errors_are_plain_text() {
+    // coloring errors is left to whoever displays them, like the CLI does with `err!`
+    let parse_err = uvm::load_source("SET 1.5 r0").unwrap_err();
+    assert!(!parse_err.contains('\x1b'), "{:?}", parse_err);
+    assert!(parse_err.starts_with("<source>.1:5: "), "{:?}", parse_err);
+
+    let load_err = uvm::load_binary(&[0, 1, 2]).unwrap_err();
+    assert!(!load_err.contains('\x1b'), "{:?}", load_err);
+
+    let mut vm = uvm::vm::VM::new(uvm::load_source("POP r0\nHALT").unwrap());
+    let run_err = vm.run().unwrap_err();
+    assert_eq!(run_err, uvm::vm::VmError::StackUnderflow);
+    assert_eq!(run_err.to_string(), "Stack underflow");
+
+    let shown = uvm::err!("{}", run_err);
+    assert!(shown.contains("[ERROR]") && shown.ends_with(" Stack underflow"), "{:?}", shown);
+}
+
+#[test]
+fn test_driving_vm_from_another_thread() {
+    fn assert_send<T: Send>() {}
+    assert_send::<uvm::vm::VM>();
+
+    let code = uvm::load_source("SET 1 r0\nSET 2 r1\nADD r0 r1\nHALT").unwrap();
+    let (command_tx, command_rx) = std::sync::mpsc::channel::<()>();
+    let (response_tx, response_rx) = std::sync::mpsc::channel();
+
+    let mut vm = uvm::vm::VM::new(code);
+    let worker = std::thread::spawn(move || {
+        for _ in command_rx {
+            let status = vm.tick();
+            response_tx.send((status, vm.get_registers()[1])).unwrap();
+        }
+    });
+
+    let mut r1_after_each_step = Vec::new();
+    loop {
+        command_tx.send(()).unwrap();
+        let (status, r1) = response_rx.recv().unwrap();
+        if status.unwrap() == uvm::vm::VmStatus::Halted(None) {
+            break;
+        }
+        r1_after_each_step.push(r1);
+    }
+    drop(command_tx);
+    worker.join().unwrap();
+
+    assert_eq!(r1_after_each_step, [0, 2, 3]);
+}
+
+#[test]
+fn test_profile() {
+    let code = uvm::parser::parse_file("tests/basic_loop.uvm".to_string()).unwrap();
+    let mut vm = uvm::vm::VM::new(code);
+    vm.run().unwrap();
+
+    let profile = vm.profile();
+    // the loop runs 50 times, plus the comparison that finally exits it
+    assert_eq!(profile[&uvm::asm::OpCode::CMPL], 51);
+    assert_eq!(profile[&uvm::asm::OpCode::JEQ], 51);
+    assert_eq!(profile[&uvm::asm::OpCode::JMP], 50);
+    assert_eq!(profile[&uvm::asm::OpCode::ADD], 100);
+    assert_eq!(profile[&uvm::asm::OpCode::HALT], 1);
+    // opcodes that never ran are left out
+    assert!(!profile.contains_key(&uvm::asm::OpCode::CALL));
+}
+
+#[test]
+fn test_check_file() {
+    assert!(uvm::check_file("tests/recursive_fibonacci.uvm".to_string()).is_ok());
+
+    let checked = uvm::check_file("tests/include/broken.uvm".to_string());
+    assert!(checked.is_err());
+    assert!(checked
+        .unwrap_err()
+        .to_string()
+        .contains("tests/include/broken.uvm.4:1: Expected to find an OpCode but found NOPE"));
+}
+
+#[test]
+fn test_parse_file_structured() {
+    let err = uvm::parser::parse_file_structured("tests/include/broken.uvm".to_string()).unwrap_err();
+    assert_eq!(err.filename, "tests/include/broken.uvm");
+    assert_eq!(err.line, Some(4));
+    assert_eq!(err.column, Some(1));
+    assert_eq!(err.message, "Expected to find an OpCode but found NOPE");
+
+    let err = uvm::parser::parse_file_structured("tests/does_not_exist.uvm".to_string()).unwrap_err();
+    assert_eq!(err.line, None);
+    assert!(err.message.starts_with("Failed to read the file"));
+}
+
+#[test]
+fn test_org_pads_with_nops() {
+    let code = uvm::parser::parse_source("JMP start\n.org 8\nstart:\nSET 42 r0\nHALT").unwrap();
+    let mut vm = uvm::vm::VM::new(code);
+    assert!(vm.run().is_ok());
+    assert_eq!(vm.get_registers()[0], 42);
+}
+
+#[test]
+fn test_frame_locals() {
+    let code = uvm::parser::parse_file("tests/frame_locals.uvm".to_string());
+    if code.is_err() {
+        panic!("{}", code.unwrap_err());
+    }
+    let mut vm = uvm::vm::VM::new(code.unwrap());
+    let result = vm.run();
+    if result.is_err() {
+        panic!("{}", result.unwrap_err());
+    }
+    assert_eq!(vm.get_registers()[..2], [30, 4]);
+    assert_eq!((vm.sp(), vm.bp()), (0, 0));
+}
@@ -1,28 +1,23 @@
-use uvm;
+// See the note in lib.rs.
+#![allow(clippy::unnecessary_unwrap)]
 
 #[test]
 fn serialize_and_deserialize_fibonacci() {
     let code = uvm::parser::parse_file("tests/recursive_fibonacci.uvm".to_string());
-    if !code.is_ok() {
-        println!("{}", code.unwrap_err());
-        assert!(false);
-        return;
+    if code.is_err() {
+        panic!("{}", code.unwrap_err());
     }
     let code = code.unwrap();
 
     let binary = uvm::serializer::serialize(&code);
-    if !binary.is_ok() {
-        println!("{}", binary.unwrap_err());
-        assert!(false);
-        return;
+    if binary.is_err() {
+        panic!("{}", binary.unwrap_err());
     }
     let binary = binary.unwrap();
 
     let deserialized = uvm::serializer::deserialize(binary);
-    if !deserialized.is_ok() {
-        println!("{}", deserialized.unwrap_err());
-        assert!(false);
-        return;
+    if deserialized.is_err() {
+        panic!("{}", deserialized.unwrap_err());
     }
     let deserialized = deserialized.unwrap();
 
@@ -32,26 +27,20 @@ fn serialize_and_deserialize_fibonacci() {
 #[test]
 fn serialize_and_deserialize_conditional_jump_tests() {
     let code = uvm::parser::parse_file("tests/conditional_jump_tests.uvm".to_string());
-    if !code.is_ok() {
-        println!("{}", code.unwrap_err());
-        assert!(false);
-        return;
+    if code.is_err() {
+        panic!("{}", code.unwrap_err());
     }
     let code = code.unwrap();
 
     let binary = uvm::serializer::serialize(&code);
-    if !binary.is_ok() {
-        println!("{}", binary.unwrap_err());
-        assert!(false);
-        return;
+    if binary.is_err() {
+        panic!("{}", binary.unwrap_err());
     }
     let binary = binary.unwrap();
 
     let deserialized = uvm::serializer::deserialize(binary);
-    if !deserialized.is_ok() {
-        println!("{}", deserialized.unwrap_err());
-        assert!(false);
-        return;
+    if deserialized.is_err() {
+        panic!("{}", deserialized.unwrap_err());
     }
     let deserialized = deserialized.unwrap();
 
@@ -61,28 +50,306 @@ fn serialize_and_deserialize_conditional_jump_tests() {
 #[test]
 fn serialize_and_deserialize_basic_float_arithmetic() {
     let code = uvm::parser::parse_file("tests/basic_float_arithmetic.uvm".to_string());
-    if !code.is_ok() {
-        println!("{}", code.unwrap_err());
-        assert!(false);
-        return;
+    if code.is_err() {
+        panic!("{}", code.unwrap_err());
     }
     let code = code.unwrap();
 
     let binary = uvm::serializer::serialize(&code);
-    if !binary.is_ok() {
-        println!("{}", binary.unwrap_err());
-        assert!(false);
-        return;
+    if binary.is_err() {
+        panic!("{}", binary.unwrap_err());
     }
     let binary = binary.unwrap();
 
     let deserialized = uvm::serializer::deserialize(binary);
-    if !deserialized.is_ok() {
-        println!("{}", deserialized.unwrap_err());
-        assert!(false);
-        return;
+    if deserialized.is_err() {
+        panic!("{}", deserialized.unwrap_err());
     }
     let deserialized = deserialized.unwrap();
 
     assert_eq!(code, deserialized);
 }
+
+#[test]
+fn diff_pinpoints_changed_instruction() {
+    let code = uvm::parser::parse_file("tests/recursive_fibonacci.uvm".to_string());
+    if code.is_err() {
+        panic!("{}", code.unwrap_err());
+    }
+    let code = code.unwrap();
+
+    // swap the first DEC for an INC, which keeps every address the same
+    let mut changed_code = code.clone();
+    let dec_addr = changed_code
+        .iter()
+        .position(|c| *c == uvm::asm::Code::Op(uvm::asm::OpCode::DEC))
+        .unwrap();
+    changed_code[dec_addr] = uvm::asm::Code::Op(uvm::asm::OpCode::INC);
+
+    let binary = uvm::serializer::serialize(&code).unwrap();
+    let changed_binary = uvm::serializer::serialize(&changed_code).unwrap();
+
+    let report = uvm::serializer::diff(&binary, &binary);
+    if report.is_err() {
+        panic!("{}", report.unwrap_err());
+    }
+    assert!(report.unwrap().is_empty());
+
+    let report = uvm::serializer::diff(&binary, &changed_binary);
+    if report.is_err() {
+        panic!("{}", report.unwrap_err());
+    }
+    let report = report.unwrap();
+    assert_eq!(report.len(), 3);
+    assert!(report[1].contains(&format!("{:04}", dec_addr)));
+    assert!(report[1].contains("DEC"));
+    assert!(report[2].contains(&format!("{:04}", dec_addr)));
+    assert_eq!(report[2], format!("  b: {:04} INC r0", dec_addr));
+}
+
+#[test]
+fn load_program_from_memory() {
+    let source = std::fs::read_to_string("tests/recursive_fibonacci.uvm").unwrap();
+    let code = uvm::load_source(&source);
+    if code.is_err() {
+        panic!("{}", code.unwrap_err());
+    }
+    let code = code.unwrap();
+
+    let binary: Vec<u8> = uvm::serializer::serialize(&code).unwrap();
+    let loaded = uvm::load_binary(&binary);
+    if loaded.is_err() {
+        panic!("{}", loaded.unwrap_err());
+    }
+    let loaded = loaded.unwrap();
+    assert_eq!(code, loaded);
+
+    let mut vm = uvm::vm::VM::new(loaded);
+    let result = vm.run();
+    if result.is_err() {
+        panic!("{}", result.unwrap_err());
+    }
+    assert_eq!(vm.get_registers()[0], 6765);
+}
+
+#[test]
+fn serialize_and_deserialize_two_immediates() {
+    let code = uvm::load_source("SET 5 r3\nENSURE -10 10 r3\nHALT");
+    if code.is_err() {
+        panic!("{}", code.unwrap_err());
+    }
+    let code = code.unwrap();
+
+    let binary = uvm::serializer::serialize(&code).unwrap();
+    let deserialized = uvm::serializer::deserialize(binary);
+    if deserialized.is_err() {
+        panic!("{}", deserialized.unwrap_err());
+    }
+    assert_eq!(code, deserialized.unwrap());
+}
+
+#[test]
+fn debug_symbols_survive_serialization() {
+    let program = uvm::parser::parse_program("tests/recursive_fibonacci.uvm".to_string());
+    if program.is_err() {
+        panic!("{}", program.unwrap_err());
+    }
+    let program = program.unwrap();
+    assert!(!program.symbols.labels.is_empty());
+
+    let binary = uvm::serializer::serialize_with_symbols(&program.code, Some(&program.symbols)).unwrap();
+    let deserialized = uvm::serializer::deserialize_with_symbols(binary);
+    if deserialized.is_err() {
+        panic!("{}", deserialized.unwrap_err());
+    }
+    let (code, symbols) = deserialized.unwrap();
+    assert_eq!(code, program.code);
+    assert_eq!(symbols, Some(program.symbols));
+
+    // without symbols there's simply no debug section
+    let binary = uvm::serializer::serialize(&program.code).unwrap();
+    let deserialized = uvm::serializer::deserialize_with_symbols(binary).unwrap();
+    assert_eq!(deserialized, (program.code, None));
+}
+
+#[test]
+fn deserialize_rejects_old_versions() {
+    let code = uvm::parser::parse_file("tests/conditional_jump_tests.uvm".to_string());
+    if code.is_err() {
+        panic!("{}", code.unwrap_err());
+    }
+    let code = code.unwrap();
+
+    let mut binary = uvm::serializer::serialize(&code).unwrap();
+    binary[15] = 0x01;
+
+    let deserialized = uvm::serializer::deserialize(binary);
+    assert!(deserialized.is_err());
+    assert!(deserialized
+        .unwrap_err()
+        .contains("version 1 which is no longer supported"));
+}
+
+#[test]
+fn deserialize_detects_corruption() {
+    let code = uvm::parser::parse_file("tests/recursive_fibonacci.uvm".to_string());
+    if code.is_err() {
+        panic!("{}", code.unwrap_err());
+    }
+    let code = code.unwrap();
+    let binary = uvm::serializer::serialize(&code).unwrap();
+
+    // flipping a single bit anywhere after the checksum gets caught
+    for idx in [20, 30, binary.len() - 1] {
+        let mut corrupted = binary.clone();
+        corrupted[idx] ^= 0x04;
+
+        let deserialized = uvm::serializer::deserialize(corrupted);
+        assert!(deserialized.is_err());
+        assert!(deserialized.unwrap_err().contains("Binary checksum mismatch"));
+    }
+
+    // and so does truncating the binary
+    let deserialized = uvm::serializer::deserialize(binary[..binary.len() - 3].to_vec());
+    assert!(deserialized.is_err());
+    assert!(deserialized.unwrap_err().contains("Binary checksum mismatch"));
+}
+
+#[test]
+fn disassembled_source_keeps_sublabels() {
+    let program = uvm::parser::parse_program("tests/recursive_fibonacci.uvm".to_string());
+    if program.is_err() {
+        panic!("{}", program.unwrap_err());
+    }
+    let program = program.unwrap();
+
+    let binary = uvm::serializer::serialize_with_symbols(&program.code, Some(&program.symbols)).unwrap();
+    let (code, symbols) = uvm::serializer::deserialize_with_symbols(binary).unwrap();
+    let disassembled = uvm::asm::Program {
+        code,
+        symbols: symbols.unwrap(),
+        ..Default::default()
+    };
+
+    let source = uvm::asm::render_source(&disassembled).unwrap();
+    assert!(source.contains("\nfibonacci:\n"));
+    assert!(source.contains("\n.ret_n:\n"));
+    assert!(source.contains("JLE .ret_n"));
+    assert!(!source.contains('>'));
+
+    // and reassembling it gives back the very same program
+    let reassembled = uvm::parser::parse_program_source(&source);
+    if reassembled.is_err() {
+        panic!("{}", reassembled.unwrap_err());
+    }
+    let reassembled = reassembled.unwrap();
+    assert_eq!(reassembled.code, program.code);
+    assert_eq!(reassembled.symbols.labels, program.symbols.labels);
+}
+
+#[test]
+fn deserialize_truncated_binaries() {
+    let program = uvm::parser::parse_program("tests/recursive_fibonacci.uvm".to_string());
+    if program.is_err() {
+        panic!("{}", program.unwrap_err());
+    }
+    let program = program.unwrap();
+    let binary = uvm::serializer::serialize_with_symbols(&program.code, Some(&program.symbols)).unwrap();
+
+    // every truncation errors out (rather than panicking), be it in the header, code or debug section
+    for len in [0, 10, 16, 18, 24, 30, binary.len() / 2, binary.len() - 1] {
+        let deserialized = uvm::serializer::deserialize(binary[..len].to_vec());
+        assert!(deserialized.is_err());
+    }
+}
+
+#[test]
+fn disassembled_source_reconstructs_labels() {
+    let code = uvm::parser::parse_file("tests/recursive_fibonacci.uvm".to_string());
+    if code.is_err() {
+        panic!("{}", code.unwrap_err());
+    }
+    let code = code.unwrap();
+
+    // without debug symbols, jump targets get made up labels
+    let binary = uvm::serializer::serialize(&code).unwrap();
+    let disassembled = uvm::asm::Program {
+        code: uvm::serializer::deserialize(binary).unwrap(),
+        ..Default::default()
+    };
+    let source = uvm::asm::render_source(&disassembled);
+    if source.is_err() {
+        panic!("{}", source.unwrap_err());
+    }
+    let source = source.unwrap();
+    assert!(source.contains("\nL_0009:\n"));
+    assert!(source.contains("CALL L_0009"));
+
+    let reparsed = uvm::parser::parse_source(&source);
+    if reparsed.is_err() {
+        panic!("{}", reparsed.unwrap_err());
+    }
+    assert_eq!(reparsed.unwrap(), code);
+
+    // a jump into the middle of an instruction can't be given a label
+    let mut broken = disassembled;
+    broken.code[3] = uvm::asm::Code::Addr(1);
+    let source = uvm::asm::render_source(&broken);
+    assert!(source.is_err());
+    assert!(source
+        .unwrap_err()
+        .contains("Jump target 1 lands in the middle of an instruction"));
+}
+
+#[test]
+fn register_aliases_survive_serialization() {
+    let program = uvm::parser::parse_program("tests/reg_aliases.uvm".to_string());
+    if program.is_err() {
+        panic!("{}", program.unwrap_err());
+    }
+    let program = program.unwrap();
+
+    let binary = uvm::serializer::serialize_with_symbols(&program.code, Some(&program.symbols)).unwrap();
+    let (code, symbols) = uvm::serializer::deserialize_with_symbols(binary).unwrap();
+    assert_eq!(code, program.code);
+    assert_eq!(symbols.as_ref(), Some(&program.symbols));
+
+    // the disassembled source keeps using the aliases
+    let source = uvm::asm::render_source(&program).unwrap();
+    assert!(source.starts_with(".reg counter r0\n.reg total r1\n"));
+    assert!(source.contains("\tADD counter total\n"));
+    assert!(source.contains("\tDBGREG r2\n"));
+    let reparsed = uvm::parser::parse_program_source(&source).unwrap();
+    assert_eq!(reparsed.code, program.code);
+    assert_eq!(reparsed.symbols.registers, program.symbols.registers);
+}
+
+#[test]
+fn initial_registers_survive_serialization() {
+    let program = uvm::parser::parse_program_source(".regs 7 -3 2.5\nADD r0 r1\nHALT").unwrap();
+    assert_eq!(program.registers, [7, -3, uvm::utils::f2i(2.5)]);
+
+    let binary = uvm::serializer::serialize_program(&program, false).unwrap();
+    let deserialized = uvm::serializer::deserialize_program(binary).unwrap();
+    assert_eq!(deserialized.registers, program.registers);
+    assert_eq!(deserialized.code, program.code);
+
+    // the registers hold their values before the first instruction runs
+    let mut vm = uvm::vm::VM::from_program(deserialized.clone()).unwrap();
+    assert_eq!(vm.get_registers()[..4], [7, -3, uvm::utils::f2i(2.5), 0]);
+    vm.run().unwrap();
+    assert_eq!(vm.get_registers()[1], 4);
+
+    // and disassembling keeps them
+    let source = uvm::asm::render_source(&deserialized).unwrap();
+    assert!(source.starts_with(".regs 7 -3 "));
+    assert_eq!(uvm::parser::parse_program_source(&source).unwrap().registers, program.registers);
+
+    // more values than there are registers can't be loaded
+    let config = uvm::vm::VmConfig {
+        num_registers: 2,
+        ..Default::default()
+    };
+    let vm = uvm::vm::VM::with_config(program.code, config).with_registers(&program.registers);
+    assert_eq!(vm.err(), Some(uvm::vm::VmError::RegisterOutOfBounds(2)));
+}